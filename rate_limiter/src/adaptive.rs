@@ -0,0 +1,275 @@
+use anyhow::anyhow;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{Request, Response, header::CONTENT_TYPE};
+use lazy_static::lazy_static;
+use opentelemetry::KeyValue;
+use redis::{AsyncCommands, Script, aio::ConnectionManager};
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+use crate::{errors::LimiterError, server_state::States};
+
+/// Admin endpoint receiving the outcome of the requests sent to the backend of a rule.
+pub const REPORT_PATH: &str = "/report";
+const MAX_REPORT_BODY_SIZE: usize = 64 * 1024;
+
+/// Limit of a rule scaled by the health of its backend. The multiplier, between `min_multiplier`
+/// and 1, is stored in the `adaptive:<rule id>` key. It is either written there by an external
+/// health checker, or adjusted by the reports sent to the limiter: added `increase` on success,
+/// multiplied by `decrease` on errors and slow responses. The multiplier expires `ttl` seconds
+/// after it was last written, so that the limit recovers when nobody reports anymore.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Adaptive {
+    #[serde(default = "default_min_multiplier")]
+    pub min_multiplier: f64,
+    #[serde(default = "default_increase")]
+    pub increase: f64,
+    #[serde(default = "default_decrease")]
+    pub decrease: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_latency: Option<u64>, // Milliseconds, slower responses are reported as errors
+    #[serde(default = "default_ttl")]
+    pub ttl: u64, // Seconds
+}
+
+fn default_min_multiplier() -> f64 {
+    0.1
+}
+
+fn default_increase() -> f64 {
+    0.05
+}
+
+fn default_decrease() -> f64 {
+    0.5
+}
+
+fn default_ttl() -> u64 {
+    300
+}
+
+impl Adaptive {
+    pub fn validate(&self, route: &str) -> anyhow::Result<()> {
+        if self.min_multiplier <= 0.0 || self.min_multiplier > 1.0 {
+            anyhow::bail!("Adaptive min_multiplier must be within ]0, 1]. Route: {route}");
+        }
+        if self.increase <= 0.0 {
+            anyhow::bail!("Adaptive increase must be positive. Route: {route}");
+        }
+        if self.decrease <= 0.0 || self.decrease >= 1.0 {
+            anyhow::bail!("Adaptive decrease must be within ]0, 1[. Route: {route}");
+        }
+        if self.ttl == 0 {
+            anyhow::bail!("Adaptive ttl must be positive. Route: {route}");
+        }
+        Ok(())
+    }
+
+    /// Limit applied with the multiplier, never below one request.
+    pub fn scale(&self, limit: u64, multiplier: f64) -> u64 {
+        let multiplier = multiplier.clamp(self.min_multiplier, 1.0);
+        ((limit as f64 * multiplier).floor() as u64).max(1)
+    }
+}
+
+// KEYS[1] = multiplier
+// ARGV = healthy ('1'/'0'), min multiplier, increase, decrease, ttl
+// Returns the new multiplier, as a string since redis truncates numbers returned by scripts.
+const REPORT_SOURCE: &str = r"
+        local multiplier = tonumber(redis.call('GET', KEYS[1]) or '1') or 1
+        if ARGV[1] == '1' then
+            multiplier = math.min(1, multiplier + tonumber(ARGV[3]))
+        else
+            multiplier = math.max(tonumber(ARGV[2]), multiplier * tonumber(ARGV[4]))
+        end
+        redis.call('SET', KEYS[1], multiplier, 'EX', ARGV[5])
+        return tostring(multiplier)
+";
+
+lazy_static! {
+    static ref REPORT_SCRIPT: Script = Script::new(REPORT_SOURCE);
+}
+
+pub fn multiplier_key(rule_id: &str) -> String {
+    format!("adaptive:{rule_id}")
+}
+
+fn report_args(adaptive: &Adaptive, healthy: bool) -> [String; 5] {
+    [
+        if healthy { "1" } else { "0" }.to_string(),
+        adaptive.min_multiplier.to_string(),
+        adaptive.increase.to_string(),
+        adaptive.decrease.to_string(),
+        adaptive.ttl.to_string(),
+    ]
+}
+
+/// Multiplier stored for a rule, 1 when there is none or when it is not a number: an external
+/// checker writing garbage must not fail the requests of the rule.
+pub fn parse_multiplier(rule_id: &str, multiplier: Option<&str>) -> f64 {
+    let Some(multiplier) = multiplier else {
+        return 1.0;
+    };
+    match multiplier.trim().parse::<f64>() {
+        Ok(parsed) if parsed.is_finite() => parsed,
+        _ => {
+            tracing::warn!("Invalid multiplier {multiplier:?} for rule {rule_id}, using 1.");
+            1.0
+        }
+    }
+}
+
+/// Current multiplier of the rule, 1 until anything was reported.
+pub async fn get_multiplier(
+    pool: &mut ConnectionManager,
+    rule_id: &str,
+) -> Result<f64, LimiterError> {
+    let multiplier: Option<String> = pool.get(multiplier_key(rule_id)).await?;
+    Ok(parse_multiplier(rule_id, multiplier.as_deref()))
+}
+
+#[derive(Deserialize)]
+struct Report {
+    #[serde(default)]
+    rule_id: Option<String>, // Rule of the backend, the most specific rule of `route` otherwise
+    #[serde(default)]
+    route: Option<String>, // Path the request was sent to
+    #[serde(default)]
+    error: bool, // The backend failed the request, ex: with a 5xx
+    #[serde(default)]
+    latency: Option<u64>, // Milliseconds taken by the backend to answer
+}
+
+#[derive(Serialize)]
+struct ReportResponse<'a> {
+    rule_id: &'a str,
+    multiplier: f64,
+}
+
+pub async fn report_handler(
+    states: Arc<States>,
+    request: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, LimiterError> {
+    match report(states, request).await {
+        Ok(response) => Ok(response),
+        Err(err) => Ok(err.into_hyper_response()),
+    }
+}
+
+/// Adjusts the multiplier of an adaptive rule with the outcome of one backend request.
+async fn report(
+    states: Arc<States>,
+    request: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, LimiterError> {
+    let body = Limited::new(request.into_body(), MAX_REPORT_BODY_SIZE)
+        .collect()
+        .await
+        .map_err(|err| LimiterError::InvalidRequest(err.to_string()))?
+        .to_bytes();
+    let report: Report = serde_json::from_slice(&body)
+        .map_err(|err| LimiterError::InvalidRequest(format!("Invalid report: {err}")))?;
+
+    let rule_id = states.resolve_rule_id(report.rule_id.as_deref(), report.route.as_deref())?;
+    let loaded_rule = states
+        .rules
+        .read()
+        .get(&rule_id)
+        .cloned()
+        .ok_or_else(|| LimiterError::InvalidRequest(format!("No rule with id {rule_id}")))?;
+    let rule = &loaded_rule.rule;
+    let Some(adaptive) = &rule.adaptive else {
+        return Err(LimiterError::InvalidRequest(format!(
+            "Rule {rule_id} is not adaptive"
+        )));
+    };
+
+    let slow = adaptive
+        .max_latency
+        .zip(report.latency)
+        .is_some_and(|(max_latency, latency)| latency > max_latency);
+    let healthy = !report.error && !slow;
+    let multiplier: String = REPORT_SCRIPT
+        .key(multiplier_key(&rule.id))
+        .arg(&report_args(adaptive, healthy))
+        .invoke_async(&mut states.pool.clone())
+        .await?;
+    let multiplier: f64 = multiplier
+        .parse()
+        .map_err(|err| LimiterError::Unknown(anyhow!("Invalid multiplier {multiplier}: {err}")))?;
+
+    let metrics_properties: Vec<KeyValue> = rule.clone().into();
+    states
+        .rl_adaptive_multiplier
+        .record(multiplier, &metrics_properties);
+    tracing::debug!("Multiplier of rule {} is now {multiplier}.", rule.id);
+
+    let body = serde_json::to_string(&ReportResponse {
+        rule_id: &rule.id,
+        multiplier,
+    })
+    .map_err(|err| LimiterError::Unknown(anyhow!("Unable to serialize report: {err}")))?;
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::algorithm_tests::FakeRedis;
+
+    fn adaptive(min_multiplier: f64) -> Adaptive {
+        Adaptive {
+            min_multiplier,
+            increase: default_increase(),
+            decrease: default_decrease(),
+            max_latency: None,
+            ttl: default_ttl(),
+        }
+    }
+
+    #[test]
+    fn limits_are_scaled_within_bounds() {
+        let adaptive = adaptive(0.2);
+        assert_eq!(adaptive.scale(100, 1.0), 100);
+        assert_eq!(adaptive.scale(100, 0.55), 55);
+        // Values written by an external checker are kept within the bounds of the rule.
+        assert_eq!(adaptive.scale(100, 0.01), 20);
+        assert_eq!(adaptive.scale(100, 3.0), 100);
+        assert_eq!(adaptive.scale(2, 0.2), 1);
+    }
+
+    #[test]
+    fn invalid_multipliers_fall_back_to_one() {
+        assert_eq!(parse_multiplier("rule", None), 1.0);
+        assert_eq!(parse_multiplier("rule", Some("0.4")), 0.4);
+        assert_eq!(parse_multiplier("rule", Some("healthy")), 1.0);
+        assert_eq!(parse_multiplier("rule", Some("")), 1.0);
+        assert_eq!(parse_multiplier("rule", Some("NaN")), 1.0);
+    }
+
+    #[test]
+    fn multipliers_expire_when_reports_stop() {
+        let redis = FakeRedis::new();
+        let adaptive = adaptive(0.1);
+        let key = multiplier_key("rule");
+        // Scripts returning decimals are truncated, the multiplier is read as a percentage.
+        let report = format!(
+            "local report = function() {REPORT_SOURCE} end
+            return {{tonumber(report()) * 100, redis.call('TTL', KEYS[1])}}"
+        );
+        redis.set_time(1_000);
+
+        let args = report_args(&adaptive, false);
+        assert_eq!(redis.eval(&report, &[&key], &args), vec![50, 300]);
+        redis.set_time(1_100);
+        assert_eq!(redis.eval(&report, &[&key], &args), vec![25, 300]);
+
+        redis.set_time(1_400);
+        assert_eq!(redis.eval(&report, &[&key], &args), vec![50, 300]);
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::Context;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    HeaderMap, Method, Request, Response, header::AUTHORIZATION, server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpListener;
+
+use crate::{
+    adaptive::{REPORT_PATH, report_handler},
+    batch::{BATCH_PATH, batch_handler},
+    errors::LimiterError,
+    handler::{peek_handler, peeked_path},
+    refund::{REFUND_PATH, refund_handler},
+    server_state::States,
+};
+
+/// Listener of the endpoints reading or changing the limits of any key, kept apart from the
+/// traffic proxied to the limiter so that clients can't reach them.
+/// Configured with `RL_ADMIN_PORT` and `RL_ADMIN_TOKEN`.
+pub struct AdminListener {
+    port: u16,
+    token: Option<String>, // Bearer token required by every admin request, when set
+}
+
+impl AdminListener {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let port = match std::env::var("RL_ADMIN_PORT") {
+            Ok(port) => port
+                .parse()
+                .with_context(|| format!("Invalid RL_ADMIN_PORT {port}"))?,
+            Err(_) => 3001,
+        };
+        let token = std::env::var("RL_ADMIN_TOKEN").ok();
+        if token.is_none() {
+            tracing::warn!(
+                "RL_ADMIN_TOKEN is not set, the admin port must not be reachable by clients."
+            );
+        }
+        Ok(AdminListener { port, token })
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok())
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .is_some_and(|bearer| bearer == token)
+    }
+
+    async fn handle(
+        &self,
+        states: Arc<States>,
+        request: Request<hyper::body::Incoming>,
+    ) -> Result<Response<Full<Bytes>>, LimiterError> {
+        if !self.authorized(request.headers()) {
+            return Ok(LimiterError::Unauthorized.into_hyper_response());
+        }
+        match (request.method(), request.uri().path()) {
+            (&Method::POST, BATCH_PATH) => batch_handler(states, request).await,
+            (&Method::POST, REFUND_PATH) => refund_handler(states, request).await,
+            (&Method::POST, REPORT_PATH) => report_handler(states, request).await,
+            (_, path) if peeked_path(path).is_some() => peek_handler(states, request).await,
+            (_, path) => Ok(LimiterError::NoRouteMatch(path.to_string()).into_hyper_response()),
+        }
+    }
+
+    pub async fn serve(self, states: Arc<States>) -> Result<(), Box<dyn std::error::Error>> {
+        tracing::info!("Starting admin server on port {}", self.port);
+
+        let addr: SocketAddr = ([0, 0, 0, 0], self.port).into();
+        let listener = TcpListener::bind(addr).await?;
+        let admin = Arc::new(self);
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let io = TokioIo::new(stream);
+            let states = states.clone();
+            let admin = admin.clone();
+
+            tokio::spawn(async move {
+                let _ = http1::Builder::new()
+                    .serve_connection(
+                        io,
+                        service_fn(move |req| {
+                            let states = states.clone();
+                            let admin = admin.clone();
+                            async move { admin.handle(states, req).await }
+                        }),
+                    )
+                    .await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(authorization: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn admin_requests_need_the_token_when_set() {
+        let admin = AdminListener {
+            port: 3001,
+            token: Some("secret".to_string()),
+        };
+        assert!(admin.authorized(&headers(Some("Bearer secret"))));
+        assert!(!admin.authorized(&headers(Some("Bearer other"))));
+        assert!(!admin.authorized(&headers(Some("secret"))));
+        assert!(!admin.authorized(&headers(None)));
+
+        let open = AdminListener {
+            port: 3001,
+            token: None,
+        };
+        assert!(open.authorized(&headers(None)));
+    }
+}
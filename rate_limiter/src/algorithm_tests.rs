@@ -0,0 +1,1095 @@
+//! Runs the algorithm scripts against an in-memory redis under a simulated clock, and compares
+//! their decisions with reference models of the algorithms. The in-memory redis is shared with the
+//! tests of the other scripts.
+
+use mlua::{Lua, Value, Variadic};
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use crate::rate_limiter::RateLimiterAlgorithms;
+
+enum Data {
+    String(String),
+    Hash(BTreeMap<String, String>),
+    SortedSet(Vec<(f64, String)>), // Ordered by score, then member
+    List(Vec<String>),
+    Json(serde_json::Value),
+}
+
+struct Entry {
+    data: Data,
+    expire_at: Option<u64>,
+}
+
+enum Reply {
+    Integer(i64),
+    Bulk(String),
+    Nil,
+    Array(Vec<Reply>),
+    Status,
+    Error(String),
+}
+
+/// Subset of the redis commands used by the scripts, expiring keys with the simulated clock.
+#[derive(Default)]
+struct Store {
+    now: u64,
+    entries: HashMap<String, Entry>,
+    published: Vec<(String, String)>,
+}
+
+/// Numbers are given to redis the way it formats them, ex: `1` or `0.5`.
+fn format_number(number: f64) -> String {
+    if number.fract() == 0.0 && number.abs() < 1e15 {
+        format!("{}", number as i64)
+    } else {
+        format!("{number}")
+    }
+}
+
+fn number(arg: &str) -> f64 {
+    arg.parse()
+        .unwrap_or_else(|_| panic!("{arg} is not a number"))
+}
+
+/// Whether a score is within the `min` and `max` bounds of a range, `(` making them exclusive.
+fn in_score_range(score: f64, min: &str, max: &str) -> bool {
+    let above = match min.strip_prefix('(') {
+        Some(min) => score > number(min),
+        None => score >= number(min),
+    };
+    let below = match max.strip_prefix('(') {
+        Some(max) => score < number(max),
+        None => score <= number(max),
+    };
+    above && below
+}
+
+/// Member targeted by a JSON path, only `$` (None) and `$.member` or `.member` are supported.
+fn json_member(path: &str) -> Option<&str> {
+    match path {
+        "$" | "." => None,
+        _ => Some(
+            path.strip_prefix("$.")
+                .or_else(|| path.strip_prefix('.'))
+                .unwrap_or_else(|| panic!("Unsupported JSON path {path}")),
+        ),
+    }
+}
+
+/// Index of a redis range bound, negative ones counting from the end.
+fn index(arg: &str, len: usize) -> i64 {
+    let index: i64 = arg.parse().unwrap();
+    if index < 0 { len as i64 + index } else { index }
+}
+
+impl Store {
+    fn entry(&mut self, key: &str) -> Option<&mut Entry> {
+        let now = self.now;
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.expire_at.is_some_and(|expire_at| expire_at <= now))
+        {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn string(&mut self, key: &str) -> Option<String> {
+        match self.entry(key).map(|entry| &entry.data) {
+            Some(Data::String(value)) => Some(value.clone()),
+            None => None,
+            _ => panic!("{key} is not a string"),
+        }
+    }
+
+    fn set_string(&mut self, key: &str, value: String) {
+        match self.entry(key) {
+            Some(entry) => entry.data = Data::String(value),
+            None => {
+                self.entries.insert(
+                    key.to_string(),
+                    Entry {
+                        data: Data::String(value),
+                        expire_at: None,
+                    },
+                );
+            }
+        }
+    }
+
+    fn hash(&mut self, key: &str) -> &mut BTreeMap<String, String> {
+        if self.entry(key).is_none() {
+            self.entries.insert(
+                key.to_string(),
+                Entry {
+                    data: Data::Hash(BTreeMap::new()),
+                    expire_at: None,
+                },
+            );
+        }
+        match &mut self.entries.get_mut(key).unwrap().data {
+            Data::Hash(hash) => hash,
+            _ => panic!("{key} is not a hash"),
+        }
+    }
+
+    fn sorted_set(&mut self, key: &str) -> &mut Vec<(f64, String)> {
+        if self.entry(key).is_none() {
+            self.entries.insert(
+                key.to_string(),
+                Entry {
+                    data: Data::SortedSet(vec![]),
+                    expire_at: None,
+                },
+            );
+        }
+        match &mut self.entries.get_mut(key).unwrap().data {
+            Data::SortedSet(set) => set,
+            _ => panic!("{key} is not a sorted set"),
+        }
+    }
+
+    fn list(&mut self, key: &str) -> &mut Vec<String> {
+        if self.entry(key).is_none() {
+            self.entries.insert(
+                key.to_string(),
+                Entry {
+                    data: Data::List(vec![]),
+                    expire_at: None,
+                },
+            );
+        }
+        match &mut self.entries.get_mut(key).unwrap().data {
+            Data::List(list) => list,
+            _ => panic!("{key} is not a list"),
+        }
+    }
+
+    fn json(&mut self, key: &str) -> Option<&mut serde_json::Value> {
+        match self.entry(key).map(|entry| &mut entry.data) {
+            Some(Data::Json(document)) => Some(document),
+            None => None,
+            _ => panic!("{key} is not a JSON document"),
+        }
+    }
+
+    /// Drops the hashes and sorted sets left empty, as redis does.
+    fn drop_if_empty(&mut self, key: &str) {
+        let empty = match self.entries.get(key).map(|entry| &entry.data) {
+            Some(Data::Hash(hash)) => hash.is_empty(),
+            Some(Data::SortedSet(set)) => set.is_empty(),
+            Some(Data::List(list)) => list.is_empty(),
+            _ => false,
+        };
+        if empty {
+            self.entries.remove(key);
+        }
+    }
+
+    fn call(&mut self, args: &[String]) -> Reply {
+        let command = args[0].to_uppercase();
+        let key = args.get(1).map(String::as_str).unwrap_or_default();
+        match command.as_str() {
+            "TIME" => Reply::Array(vec![
+                Reply::Bulk(self.now.to_string()),
+                Reply::Bulk("0".to_string()),
+            ]),
+            "EXISTS" => Reply::Integer(self.entry(key).is_some() as i64),
+            "DEL" => Reply::Integer(
+                args[1..]
+                    .iter()
+                    .filter(|key| self.entry(key).is_some() && self.entries.remove(*key).is_some())
+                    .count() as i64,
+            ),
+            "EXPIRE" => {
+                let expire_at = self.now + number(&args[2]) as u64;
+                let nx = args.get(3).is_some_and(|option| option == "NX");
+                match self.entry(key) {
+                    Some(entry) if !(nx && entry.expire_at.is_some()) => {
+                        entry.expire_at = Some(expire_at);
+                        Reply::Integer(1)
+                    }
+                    _ => Reply::Integer(0),
+                }
+            }
+            "TTL" => {
+                let now = self.now;
+                match self.entry(key) {
+                    None => Reply::Integer(-2),
+                    Some(Entry {
+                        expire_at: None, ..
+                    }) => Reply::Integer(-1),
+                    Some(Entry {
+                        expire_at: Some(expire_at),
+                        ..
+                    }) => Reply::Integer((*expire_at - now) as i64),
+                }
+            }
+            "GET" => self.string(key).map_or(Reply::Nil, Reply::Bulk),
+            "SET" => {
+                self.set_string(key, args[2].clone());
+                if args.get(3).is_some_and(|option| option == "EX") {
+                    self.entries.get_mut(key).unwrap().expire_at =
+                        Some(self.now + number(&args[4]) as u64);
+                } else {
+                    self.entries.get_mut(key).unwrap().expire_at = None;
+                }
+                Reply::Status
+            }
+            "INCR" | "DECRBY" => {
+                let value = self.string(key).map_or(0, |value| value.parse().unwrap());
+                let value = match command.as_str() {
+                    "INCR" => value + 1,
+                    _ => value - args[2].parse::<i64>().unwrap(),
+                };
+                self.set_string(key, value.to_string());
+                Reply::Integer(value)
+            }
+            "HGET" => self
+                .entry(key)
+                .is_some()
+                .then(|| self.hash(key).get(&args[2]).cloned())
+                .flatten()
+                .map_or(Reply::Nil, Reply::Bulk),
+            "HSET" | "HMSET" => {
+                let hash = self.hash(key);
+                let mut added = 0;
+                for pair in args[2..].chunks(2) {
+                    added += hash.insert(pair[0].clone(), pair[1].clone()).is_none() as i64;
+                }
+                match command.as_str() {
+                    "HSET" => Reply::Integer(added),
+                    _ => Reply::Status,
+                }
+            }
+            "HSETNX" => {
+                let hash = self.hash(key);
+                match hash.contains_key(&args[2]) {
+                    true => Reply::Integer(0),
+                    false => {
+                        hash.insert(args[2].clone(), args[3].clone());
+                        Reply::Integer(1)
+                    }
+                }
+            }
+            "HINCRBY" => {
+                let hash = self.hash(key);
+                let value = hash.get(&args[2]).map_or(0, |value| value.parse().unwrap())
+                    + args[3].parse::<i64>().unwrap();
+                hash.insert(args[2].clone(), value.to_string());
+                Reply::Integer(value)
+            }
+            "ZADD" => {
+                let set = self.sorted_set(key);
+                set.retain(|(_, member)| *member != args[3]);
+                set.push((number(&args[2]), args[3].clone()));
+                set.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                Reply::Integer(1)
+            }
+            "ZCARD" => match self.entry(key) {
+                Some(_) => Reply::Integer(self.sorted_set(key).len() as i64),
+                None => Reply::Integer(0),
+            },
+            "ZRANGE" => {
+                if self.entry(key).is_none() {
+                    return Reply::Array(vec![]);
+                }
+                let set = self.sorted_set(key);
+                let start = index(&args[2], set.len()).max(0) as usize;
+                let stop = index(&args[3], set.len()).min(set.len() as i64 - 1);
+                let with_scores = args.get(4).is_some_and(|option| option == "WITHSCORES");
+                let mut reply = vec![];
+                for (score, member) in set.iter().take((stop + 1).max(0) as usize).skip(start) {
+                    reply.push(Reply::Bulk(member.clone()));
+                    if with_scores {
+                        reply.push(Reply::Bulk(format_number(*score)));
+                    }
+                }
+                Reply::Array(reply)
+            }
+            "ZCOUNT" => match self.entry(key) {
+                Some(_) => Reply::Integer(
+                    self.sorted_set(key)
+                        .iter()
+                        .filter(|(score, _)| in_score_range(*score, &args[2], &args[3]))
+                        .count() as i64,
+                ),
+                None => Reply::Integer(0),
+            },
+            "ZRANGEBYSCORE" => {
+                if self.entry(key).is_none() {
+                    return Reply::Array(vec![]);
+                }
+                let options: Vec<String> = args[4..].iter().map(|arg| arg.to_uppercase()).collect();
+                let with_scores = options.iter().any(|option| option == "WITHSCORES");
+                let (offset, count) = match options.iter().position(|option| option == "LIMIT") {
+                    Some(limit) => (
+                        number(&options[limit + 1]) as usize,
+                        number(&options[limit + 2]) as usize,
+                    ),
+                    None => (0, usize::MAX),
+                };
+                let mut reply = vec![];
+                for (score, member) in self
+                    .sorted_set(key)
+                    .iter()
+                    .filter(|(score, _)| in_score_range(*score, &args[2], &args[3]))
+                    .skip(offset)
+                    .take(count)
+                {
+                    reply.push(Reply::Bulk(member.clone()));
+                    if with_scores {
+                        reply.push(Reply::Bulk(format_number(*score)));
+                    }
+                }
+                Reply::Array(reply)
+            }
+            "ZREMRANGEBYSCORE" | "ZREMRANGEBYRANK" => {
+                if self.entry(key).is_none() {
+                    return Reply::Integer(0);
+                }
+                let set = self.sorted_set(key);
+                let len = set.len();
+                let mut position = 0;
+                set.retain(|(score, _)| {
+                    let removed = match command.as_str() {
+                        "ZREMRANGEBYSCORE" => {
+                            *score >= number(&args[2]) && *score <= number(&args[3])
+                        }
+                        _ => position >= index(&args[2], len) && position <= index(&args[3], len),
+                    };
+                    position += 1;
+                    !removed
+                });
+                let removed = len - set.len();
+                self.drop_if_empty(key);
+                Reply::Integer(removed as i64)
+            }
+            "LPUSH" => {
+                let list = self.list(key);
+                for value in &args[2..] {
+                    list.insert(0, value.clone());
+                }
+                Reply::Integer(list.len() as i64)
+            }
+            "LRANGE" => {
+                if self.entry(key).is_none() {
+                    return Reply::Array(vec![]);
+                }
+                let list = self.list(key);
+                let start = index(&args[2], list.len()).max(0) as usize;
+                let stop = index(&args[3], list.len()).min(list.len() as i64 - 1);
+                Reply::Array(
+                    list.iter()
+                        .take((stop + 1).max(0) as usize)
+                        .skip(start)
+                        .cloned()
+                        .map(Reply::Bulk)
+                        .collect(),
+                )
+            }
+            "LTRIM" => {
+                if self.entry(key).is_some() {
+                    let list = self.list(key);
+                    let start = index(&args[2], list.len()).max(0) as usize;
+                    let stop = index(&args[3], list.len()).min(list.len() as i64 - 1);
+                    *list = list
+                        .iter()
+                        .take((stop + 1).max(0) as usize)
+                        .skip(start)
+                        .cloned()
+                        .collect();
+                    self.drop_if_empty(key);
+                }
+                Reply::Status
+            }
+            "PUBLISH" => {
+                self.published.push((args[1].clone(), args[2].clone()));
+                Reply::Integer(0)
+            }
+            "JSON.SET" => {
+                let value: serde_json::Value = serde_json::from_str(&args[3]).unwrap();
+                let nx = args.get(4).is_some_and(|option| option == "NX");
+                match (json_member(&args[2]), self.json(key)) {
+                    (None, Some(_)) if nx => return Reply::Nil,
+                    (None, _) => self.entries.insert(
+                        key.to_string(),
+                        Entry {
+                            data: Data::Json(value),
+                            expire_at: None,
+                        },
+                    ),
+                    (Some(member), Some(document)) => {
+                        document[member] = value;
+                        None
+                    }
+                    (Some(_), None) => {
+                        return Reply::Error("ERR new objects must be created at the root".into());
+                    }
+                };
+                Reply::Status
+            }
+            "JSON.GET" => {
+                let member = args.get(2).and_then(|path| json_member(path));
+                match (self.json(key), member) {
+                    (None, _) => Reply::Nil,
+                    (Some(document), None) => Reply::Bulk(document.to_string()),
+                    (Some(document), Some(member)) => document
+                        .get(member)
+                        .map_or(Reply::Nil, |value| Reply::Bulk(value.to_string())),
+                }
+            }
+            "JSON.DEL" => {
+                let member = json_member(&args[2]).expect("JSON.DEL of the root");
+                let removed = self
+                    .json(key)
+                    .and_then(|document| document.as_object_mut())
+                    .and_then(|document| document.remove(member));
+                Reply::Integer(removed.is_some() as i64)
+            }
+            _ => panic!("Unsupported command {command}"),
+        }
+    }
+}
+
+fn into_lua(lua: &Lua, reply: Reply) -> mlua::Result<Value> {
+    Ok(match reply {
+        Reply::Integer(value) => Value::Number(value as f64),
+        Reply::Bulk(value) => Value::String(lua.create_string(&value)?),
+        // Nil replies are converted to false, as redis does.
+        Reply::Nil => Value::Boolean(false),
+        Reply::Array(replies) => {
+            let table = lua.create_table()?;
+            for reply in replies {
+                table.push(into_lua(lua, reply)?)?;
+            }
+            Value::Table(table)
+        }
+        Reply::Status => {
+            let table = lua.create_table()?;
+            table.set("ok", "OK")?;
+            Value::Table(table)
+        }
+        Reply::Error(message) => return Err(mlua::Error::RuntimeError(message)),
+    })
+}
+
+/// Encoding of the tables given to `cjson.encode`, arrays are not supported.
+fn into_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(value) => value.into(),
+        Value::Integer(value) => value.into(),
+        Value::Number(value) if value.fract() == 0.0 => (value as i64).into(),
+        Value::Number(value) => value.into(),
+        Value::String(value) => value.to_str().unwrap().to_string().into(),
+        Value::Table(table) => table
+            .pairs::<String, Value>()
+            .map(|pair| {
+                let (key, value) = pair.unwrap();
+                (key, into_json(value))
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        other => panic!("Unsupported JSON value {other:?}"),
+    }
+}
+
+/// Redis with the scripting API of a redis server, to which time is given by the test.
+pub(crate) struct FakeRedis {
+    lua: Lua,
+    store: Rc<RefCell<Store>>,
+}
+
+impl FakeRedis {
+    pub(crate) fn new() -> Self {
+        let lua = Lua::new();
+        let store = Rc::new(RefCell::new(Store::default()));
+
+        let call_store = store.clone();
+        let call = lua
+            .create_function(move |lua, args: Variadic<Value>| {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|arg| match arg {
+                        Value::String(value) => value.to_str().unwrap().to_string(),
+                        Value::Integer(value) => value.to_string(),
+                        Value::Number(value) => format_number(*value),
+                        other => panic!("Unsupported argument {other:?}"),
+                    })
+                    .collect();
+                let reply = call_store.borrow_mut().call(&args);
+                into_lua(lua, reply)
+            })
+            .unwrap();
+        let error_reply = lua
+            .create_function(|lua, message: String| {
+                let table = lua.create_table()?;
+                table.set("err", message)?;
+                Ok(table)
+            })
+            .unwrap();
+        let redis = lua.create_table().unwrap();
+        redis.set("call", call).unwrap();
+        redis.set("error_reply", error_reply).unwrap();
+        lua.globals().set("redis", redis).unwrap();
+
+        let encode = lua
+            .create_function(|_, value: Value| Ok(into_json(value).to_string()))
+            .unwrap();
+        let cjson = lua.create_table().unwrap();
+        cjson.set("encode", encode).unwrap();
+        lua.globals().set("cjson", cjson).unwrap();
+
+        FakeRedis { lua, store }
+    }
+
+    pub(crate) fn set_time(&self, now: u64) {
+        self.store.borrow_mut().now = now;
+    }
+
+    /// Runs the script and returns its result as redis would, numbers being truncated to integers.
+    pub(crate) fn eval(&self, script: &str, keys: &[&str], args: &[String]) -> Vec<i64> {
+        self.lua.globals().set("KEYS", keys.to_vec()).unwrap();
+        self.lua.globals().set("ARGV", args.to_vec()).unwrap();
+        let result: Value = self.lua.load(script).eval().unwrap();
+        let as_integer = |value: Value| match value {
+            Value::Integer(value) => value,
+            Value::Number(value) => value as i64,
+            Value::String(value) => value.to_str().unwrap().parse().unwrap(),
+            other => panic!("Unexpected result {other:?}"),
+        };
+        match result {
+            Value::Table(table) if table.contains_key("err").unwrap() => {
+                panic!("{}", table.get::<String>("err").unwrap())
+            }
+            Value::Table(table) => table
+                .sequence_values::<Value>()
+                .map(|value| as_integer(value.unwrap()))
+                .collect(),
+            other => vec![as_integer(other)],
+        }
+    }
+
+    /// The JSON document stored at `key`.
+    pub(crate) fn json(&self, key: &str) -> Option<serde_json::Value> {
+        self.store.borrow_mut().json(key).cloned()
+    }
+
+    /// The list stored at `key`, empty when it doesn't exist.
+    pub(crate) fn list(&self, key: &str) -> Vec<String> {
+        let mut store = self.store.borrow_mut();
+        match store.entry(key) {
+            Some(_) => store.list(key).clone(),
+            None => vec![],
+        }
+    }
+
+    /// The (channel, message) pairs published so far.
+    pub(crate) fn published(&self) -> Vec<(String, String)> {
+        self.store.borrow().published.clone()
+    }
+}
+
+/// Decision of an algorithm for one request.
+#[derive(Debug, PartialEq)]
+struct Decision {
+    allowed: bool,
+    remaining: i64,
+}
+
+/// Reference implementation of an algorithm, `consume` being false when only peeking.
+trait Model {
+    fn check(&mut self, now: u64, consume: bool) -> Decision;
+}
+
+struct FixedWindow {
+    limit: u64,
+    expiration: u64,
+    window: Option<(u64, u64)>, // Start and count of the current window
+}
+
+impl Model for FixedWindow {
+    fn check(&mut self, now: u64, consume: bool) -> Decision {
+        if self
+            .window
+            .is_some_and(|(start, _)| now >= start + self.expiration)
+        {
+            self.window = None;
+        }
+        let count = self.window.map_or(0, |(_, count)| count);
+        let allowed = count < self.limit;
+        if allowed && consume {
+            let start = self.window.map_or(now, |(start, _)| start);
+            self.window = Some((start, count + 1));
+        }
+        let count = self.window.map_or(0, |(_, count)| count);
+        Decision {
+            allowed,
+            remaining: self.limit as i64 - count as i64,
+        }
+    }
+}
+
+struct SlidingWindowLog {
+    limit: u64,
+    expiration: u64,
+    log: Vec<u64>,
+}
+
+impl Model for SlidingWindowLog {
+    fn check(&mut self, now: u64, consume: bool) -> Decision {
+        self.log
+            .retain(|timestamp| *timestamp + self.expiration > now);
+        let allowed = (self.log.len() as u64) < self.limit;
+        if allowed && consume {
+            self.log.push(now);
+        }
+        Decision {
+            allowed,
+            remaining: self.limit as i64 - self.log.len() as i64,
+        }
+    }
+}
+
+struct SlidingWindowCounter {
+    limit: u64,
+    expiration: u64,
+    windows: HashMap<u64, u64>, // Requests counted in each window of `expiration` seconds
+}
+
+impl Model for SlidingWindowCounter {
+    fn check(&mut self, now: u64, consume: bool) -> Decision {
+        let window = now / self.expiration;
+        let elapsed = (now % self.expiration) as f64 / self.expiration as f64;
+        let previous = window
+            .checked_sub(1)
+            .and_then(|previous| self.windows.get(&previous))
+            .copied()
+            .unwrap_or_default();
+        let current = self.windows.get(&window).copied().unwrap_or_default();
+        let weight = (1.0 - elapsed) * previous as f64 + current as f64;
+
+        let allowed = weight + 1.0 <= self.limit as f64;
+        if !allowed {
+            return Decision {
+                allowed,
+                remaining: 0,
+            };
+        }
+        if consume {
+            *self.windows.entry(window).or_default() += 1;
+        }
+        Decision {
+            allowed,
+            remaining: (self.limit as f64 - weight - consume as u64 as f64) as i64,
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: u64,
+    expiration: u64,
+    bucket: Option<(f64, u64)>, // Tokens and time of the last refill
+}
+
+impl Model for TokenBucket {
+    fn check(&mut self, now: u64, consume: bool) -> Decision {
+        let rate = self.limit as f64 / self.expiration as f64;
+        let (tokens, last) = self.bucket.unwrap_or((self.limit as f64, now));
+        let tokens = (tokens + (now - last) as f64 * rate).min(self.limit as f64);
+
+        let allowed = tokens >= 1.0;
+        if !allowed {
+            return Decision {
+                allowed,
+                remaining: 0,
+            };
+        }
+        let tokens = if consume { tokens - 1.0 } else { tokens };
+        if consume {
+            self.bucket = Some((tokens, now));
+        }
+        Decision {
+            allowed,
+            remaining: tokens as i64,
+        }
+    }
+}
+
+struct LeakyBucket {
+    limit: u64,
+    expiration: u64,
+    bucket: Option<(f64, u64)>, // Requests in the bucket and time of the last leak
+}
+
+impl Model for LeakyBucket {
+    fn check(&mut self, now: u64, consume: bool) -> Decision {
+        let rate = self.limit as f64 / self.expiration as f64;
+        let (level, last) = self.bucket.unwrap_or((0.0, now));
+        let level = (level - (now - last) as f64 * rate).max(0.0);
+
+        let allowed = level + 1.0 <= self.limit as f64;
+        if !allowed {
+            return Decision {
+                allowed,
+                remaining: 0,
+            };
+        }
+        if consume {
+            self.bucket = Some((level + 1.0, now));
+        }
+        Decision {
+            allowed,
+            remaining: self.limit as i64 - level.ceil() as i64 - consume as i64,
+        }
+    }
+}
+
+fn model(algorithm: &RateLimiterAlgorithms, limit: u64, expiration: u64) -> Box<dyn Model> {
+    match algorithm {
+        RateLimiterAlgorithms::FixedWindow => Box::new(FixedWindow {
+            limit,
+            expiration,
+            window: None,
+        }),
+        RateLimiterAlgorithms::SlidingWindowLog => Box::new(SlidingWindowLog {
+            limit,
+            expiration,
+            log: vec![],
+        }),
+        RateLimiterAlgorithms::SlidingWindowCounter => Box::new(SlidingWindowCounter {
+            limit,
+            expiration,
+            windows: HashMap::new(),
+        }),
+        RateLimiterAlgorithms::TokenBucket => Box::new(TokenBucket {
+            limit,
+            expiration,
+            bucket: None,
+        }),
+        RateLimiterAlgorithms::LeakyBucket => Box::new(LeakyBucket {
+            limit,
+            expiration,
+            bucket: None,
+        }),
+    }
+}
+
+/// Deterministic pseudo random numbers, so that failures can be replayed.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % bound
+    }
+}
+
+const ALGORITHMS: [RateLimiterAlgorithms; 5] = [
+    RateLimiterAlgorithms::FixedWindow,
+    RateLimiterAlgorithms::SlidingWindowCounter,
+    RateLimiterAlgorithms::SlidingWindowLog,
+    RateLimiterAlgorithms::TokenBucket,
+    RateLimiterAlgorithms::LeakyBucket,
+];
+
+/// Arguments of the levels script for a single key, at the simulated time.
+fn check_args(limit: u64, expiration: u64, consume: bool, now: u64) -> Vec<String> {
+    vec![
+        limit.to_string(),
+        expiration.to_string(),
+        (consume as u8).to_string(),
+        String::new(),
+        "0".to_string(),
+        now.to_string(),
+    ]
+}
+
+#[test]
+fn scripts_match_the_reference_models() {
+    for algorithm in &ALGORITHMS {
+        for (limit, expiration) in [(5, 10), (3, 1), (10, 4)] {
+            let redis = FakeRedis::new();
+            let script = algorithm.get_levels_script();
+            let mut model = model(algorithm, limit, expiration);
+            let mut random = Lcg(limit * 31 + expiration);
+            let mut now = 1_700_000_000;
+
+            for step in 0..3000 {
+                // Mostly bursts within a second, sometimes idle for longer than a window.
+                now += match random.next(10) {
+                    0..=5 => 0,
+                    6..=8 => random.next(expiration + 1),
+                    _ => random.next(expiration * 3),
+                };
+                let consume = random.next(5) != 0;
+                redis.set_time(now);
+
+                let result = redis.eval(
+                    &script,
+                    &["key"],
+                    &check_args(limit, expiration, consume, now),
+                );
+                let decision = Decision {
+                    allowed: result[3] == 1,
+                    remaining: result[1],
+                };
+                assert_eq!(
+                    decision,
+                    model.check(now, consume),
+                    "{algorithm} diverged at step {step}, time {now} (limit {limit}, expiration {expiration}, consume {consume})"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn rejected_levels_do_not_consume_the_others() {
+    for algorithm in &ALGORITHMS {
+        let redis = FakeRedis::new();
+        let script = algorithm.get_levels_script();
+        let now = 1_700_000_000;
+        redis.set_time(now);
+        // The key allows 3 requests, the route 2.
+        let args = |consume: bool| {
+            let mut args = check_args(3, 60, consume, now);
+            args.splice(2..2, ["2".to_string(), "60".to_string()]);
+            args
+        };
+
+        for _ in 0..2 {
+            assert_eq!(redis.eval(&script, &["key", "route"], &args(true))[3], 1);
+        }
+        let rejected = redis.eval(&script, &["key", "route"], &args(true));
+        assert_eq!((rejected[3], rejected[4]), (0, 2), "{algorithm}");
+
+        let key_only = redis.eval(&script, &["key"], &check_args(3, 60, false, now));
+        assert_eq!(
+            key_only[1], 1,
+            "{algorithm} consumed the key of a rejected request"
+        );
+    }
+}
+
+#[test]
+fn refunded_requests_can_be_sent_again() {
+    for algorithm in &ALGORITHMS {
+        let redis = FakeRedis::new();
+        let script = algorithm.get_levels_script();
+        let refund = algorithm.get_refund_levels_script();
+        let now = 1_700_000_000;
+        redis.set_time(now);
+
+        for _ in 0..3 {
+            redis.eval(&script, &["key"], &check_args(3, 60, true, now));
+        }
+        assert_eq!(
+            redis.eval(&script, &["key"], &check_args(3, 60, true, now))[3],
+            0
+        );
+
+        let refund_args = ["3", "60", "5", "0", &now.to_string()].map(String::from);
+        // Nothing is refunded beyond the counted requests.
+        assert_eq!(
+            redis.eval(&refund, &["key"], &refund_args),
+            vec![3],
+            "{algorithm}"
+        );
+        let peek = redis.eval(&script, &["key"], &check_args(3, 60, false, now));
+        assert_eq!(peek[1], 3, "{algorithm}");
+    }
+}
+
+#[test]
+fn lowered_limits_leave_nothing_remaining() {
+    for algorithm in &ALGORITHMS {
+        let redis = FakeRedis::new();
+        let script = algorithm.get_levels_script();
+        let now = 1_700_000_000;
+        redis.set_time(now);
+
+        for _ in 0..5 {
+            redis.eval(&script, &["key"], &check_args(10, 60, true, now));
+        }
+        // Ex: an override, a schedule or the adaptive multiplier lowered the limit mid-window.
+        // The windows are over it, the buckets only hold up to the new limit.
+        for consume in [false, true] {
+            let result = redis.eval(&script, &["key"], &check_args(2, 60, consume, now));
+            assert!((0..=2).contains(&result[1]), "{algorithm}: {result:?}");
+        }
+        if !matches!(
+            algorithm,
+            RateLimiterAlgorithms::TokenBucket | RateLimiterAlgorithms::LeakyBucket
+        ) {
+            let result = redis.eval(&script, &["key"], &check_args(2, 60, true, now));
+            assert_eq!((result[1], result[3]), (0, 0), "{algorithm}");
+        }
+    }
+}
+
+#[test]
+fn rejections_do_not_refill_the_buckets() {
+    for algorithm in [
+        RateLimiterAlgorithms::TokenBucket,
+        RateLimiterAlgorithms::LeakyBucket,
+    ] {
+        let redis = FakeRedis::new();
+        let script = algorithm.get_levels_script();
+        let start = 1_700_000_000;
+        // Two requests per 4 seconds, half a request is refilled, or leaks out, every second.
+        let decisions: Vec<_> = [0, 0, 1, 2, 2, 3]
+            .into_iter()
+            .map(|elapsed| {
+                redis.set_time(start + elapsed);
+                redis.eval(&script, &["key"], &check_args(2, 4, true, start + elapsed))[3]
+            })
+            .collect();
+        assert_eq!(decisions, vec![1, 1, 0, 1, 0, 0], "{algorithm}");
+    }
+}
+
+#[test]
+fn shaped_requests_wait_for_the_bucket_to_leak() {
+    let redis = FakeRedis::new();
+    let script = RateLimiterAlgorithms::LeakyBucket.get_levels_script();
+    let now = 1_700_000_000;
+    redis.set_time(now);
+    // One request leaks out every second, none may wait more than 2.5 seconds.
+    let mut args = check_args(10, 10, true, now);
+    args[3] = "2500".to_string();
+
+    let delays: Vec<_> = (0..4)
+        .map(|_| redis.eval(&script, &["key"], &args))
+        // Rejections carry the position of the rejecting level instead of a delay.
+        .map(|result| (result[3], (result[3] == 1).then(|| result[4])))
+        .collect();
+    assert_eq!(
+        delays,
+        vec![(1, Some(0)), (1, Some(1000)), (1, Some(2000)), (0, None)]
+    );
+
+    redis.set_time(now + 2);
+    args[5] = (now + 2).to_string();
+    assert_eq!(redis.eval(&script, &["key"], &args)[4], 1000);
+}
+
+#[test]
+fn the_global_limit_is_one_fixed_window_across_algorithms() {
+    let redis = FakeRedis::new();
+    let now = 1_700_000_000;
+    redis.set_time(now);
+    // Each key allows 10 requests, the global limit 3 over every algorithm.
+    let args = |now: u64| {
+        let mut args = check_args(10, 60, true, now);
+        args.splice(2..2, ["3".to_string(), "60".to_string()]);
+        args[6] = "1".to_string();
+        args
+    };
+    let check = |algorithm: &RateLimiterAlgorithms, now: u64| {
+        let keys = [format!("key:{algorithm}"), "global".to_string()];
+        let keys = keys.each_ref().map(String::as_str);
+        redis.eval(&algorithm.get_levels_script(), &keys, &args(now))
+    };
+
+    for algorithm in &ALGORITHMS[..3] {
+        assert_eq!(check(algorithm, now)[3], 1, "{algorithm}");
+    }
+    for algorithm in &ALGORITHMS {
+        let rejected = check(algorithm, now);
+        assert_eq!((rejected[3], rejected[4]), (0, 2), "{algorithm}");
+    }
+
+    // Refunds give back the global window whatever the algorithm of the rule.
+    let algorithm = &RateLimiterAlgorithms::SlidingWindowLog;
+    let refund_args = ["10", "60", "3", "60", "1", "1", &now.to_string()].map(String::from);
+    redis.eval(
+        &algorithm.get_refund_levels_script(),
+        &[&format!("key:{algorithm}"), "global"],
+        &refund_args,
+    );
+    assert_eq!(check(algorithm, now)[3], 1);
+
+    // The whole window expires at once.
+    redis.set_time(now + 60);
+    assert_eq!(check(&RateLimiterAlgorithms::LeakyBucket, now + 60)[3], 1);
+}
+
+#[test]
+fn checking_a_sliding_window_log_writes_nothing() {
+    let redis = FakeRedis::new();
+    let script = RateLimiterAlgorithms::SlidingWindowLog.get_levels_script();
+    let state =
+        r#"return {redis.call('ZCARD', KEYS[1] .. ':ss'), redis.call('TTL', KEYS[1] .. ':ss')}"#;
+    let now = 1_700_000_000;
+    redis.set_time(now);
+    for _ in 0..3 {
+        redis.eval(&script, &["key"], &check_args(3, 10, true, now));
+    }
+
+    // The logged requests are out of the window, but only counting removes them.
+    redis.set_time(now + 5);
+    let before = redis.eval(state, &["key"], &[]);
+    let checked = redis.eval(&script, &["key"], &check_args(3, 10, false, now + 11));
+    assert_eq!((checked[1], checked[3]), (3, 1));
+    assert_eq!(redis.eval(state, &["key"], &[]), before);
+
+    let counted = redis.eval(&script, &["key"], &check_args(3, 10, true, now + 11));
+    assert_eq!((counted[1], counted[3]), (2, 1));
+    assert_eq!(redis.eval(state, &["key"], &[])[0], 1);
+}
+
+#[test]
+fn aggregates_only_get_back_what_the_key_got_back() {
+    for algorithm in &ALGORITHMS {
+        let redis = FakeRedis::new();
+        let script = algorithm.get_levels_script();
+        let refund = algorithm.get_refund_levels_script();
+        let now = 1_700_000_000;
+        redis.set_time(now);
+        // Each key allows 5 requests, the route 4 over every key.
+        let args = |consume: bool| {
+            let mut args = check_args(5, 60, consume, now);
+            args.splice(2..2, ["4".to_string(), "60".to_string()]);
+            args
+        };
+        let refund_args =
+            |units: &str| ["5", "60", "4", "60", units, "0", &now.to_string()].map(String::from);
+
+        for key in ["a", "a", "b", "b"] {
+            redis.eval(&script, &[key, "route"], &args(true));
+        }
+        // Nothing was counted for c, the route count belongs to a and b.
+        assert_eq!(
+            redis.eval(&refund, &["c", "route"], &refund_args("3")),
+            vec![0]
+        );
+        assert_eq!(
+            redis.eval(&script, &["c", "route"], &args(true))[3],
+            0,
+            "{algorithm}"
+        );
+
+        // a gets back its 2 requests, and so does the route, not the 3 asked for.
+        assert_eq!(
+            redis.eval(&refund, &["a", "route"], &refund_args("3")),
+            vec![2]
+        );
+        let route_only = ["4", "60", "0", "", "0", &now.to_string()].map(String::from);
+        assert_eq!(
+            redis.eval(&script, &["route"], &route_only)[1],
+            2,
+            "{algorithm}"
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use anyhow::anyhow;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{Request, Response, header::CONTENT_TYPE};
+use opentelemetry::KeyValue;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+use crate::{
+    adaptive::{multiplier_key, parse_multiplier},
+    errors::LimiterError,
+    handler::ban_headers,
+    overrides::KeyOverride,
+    penalty::penalty_keys,
+    rate_limiter::{RateLimitCheck, RateLimiterHeaders, execute_rate_limiting_batch},
+    rules::{LoadedRule, Rule},
+    schedules::active_schedule,
+    server_state::States,
+    tiers::{TIERS_KEY, TierSource},
+};
+
+/// Admin endpoint checking many (route, key) pairs at once, ex: for a job scheduler.
+pub const BATCH_PATH: &str = "/check/batch";
+const MAX_BATCH_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+struct BatchCheck {
+    route: String, // Path a request would be sent to, matched against the rules
+    key: String,   // Tracked key, components of composite keys joined as they are by the limiter
+}
+
+#[derive(Serialize)]
+struct BatchDecision<'a> {
+    route: &'a str,
+    key: &'a str,
+    decision: &'static str, // allowed, rejected, banned, undecidable or unmatched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<RateLimiterHeaders>, // Not set when the check was not counted
+}
+
+pub async fn batch_handler(
+    states: Arc<States>,
+    request: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, LimiterError> {
+    match check_batch(states, request).await {
+        Ok(response) => Ok(response),
+        Err(err) => Ok(err.into_hyper_response()),
+    }
+}
+
+/// What the limiter reads from redis about a key before running the algorithm of its rule.
+#[derive(Default)]
+struct KeyState {
+    ban: Option<u64>,           // Remaining seconds of the ban of the key
+    multiplier: Option<String>, // Adaptive multiplier of the rule
+    tier: Option<String>,       // Tier of the key in the `tiers` hash
+}
+
+/// Bans, adaptive multipliers and tiers of the (key, rule) pairs, read in a single round trip.
+async fn get_key_states(
+    pool: &mut ConnectionManager,
+    keys: &[(&str, &Rule)],
+) -> Result<Vec<KeyState>, LimiterError> {
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut pipeline = redis::pipe();
+    for (key, rule) in keys {
+        let [_, ban_key, _] = penalty_keys(&rule.id, key);
+        pipeline
+            .ttl(ban_key)
+            .get(multiplier_key(&rule.id))
+            .hget(TIERS_KEY, *key);
+    }
+    let states: Vec<(i64, Option<String>, Option<String>)> = pipeline.query_async(pool).await?;
+    Ok(states
+        .into_iter()
+        .map(|(ttl, multiplier, tier)| KeyState {
+            ban: (ttl > 0).then_some(ttl as u64),
+            multiplier,
+            tier,
+        })
+        .collect())
+}
+
+/// Outcome of a check before its script runs.
+enum Precheck {
+    Decided(&'static str, Option<RateLimiterHeaders>),
+    Scripted(u64, u64), // Limit and expiration the script runs with
+}
+
+/// Applies what the limiter applies to a request before running the algorithm, in the same order:
+/// bans, tiers, schedules, adaptive limits and the override of the key. Tiers read from the
+/// request, a header or a JWT claim, make the check undecidable.
+fn precheck(
+    rule: &Rule,
+    state: &KeyState,
+    key_override: Option<&KeyOverride>,
+    now: DateTime<Utc>,
+) -> Precheck {
+    if rule.penalty.is_some()
+        && let Some(retry_after) = state.ban
+    {
+        return Precheck::Decided("banned", Some(ban_headers(rule, retry_after)));
+    }
+
+    let mut limit = rule.limit as u64;
+    let mut expiration = rule.expiration as u64;
+    if let Some(tiers) = &rule.tiers {
+        if !matches!(tiers.source, TierSource::Redis) {
+            return Precheck::Decided("undecidable", None);
+        }
+        if let Some((_, tier_limit)) = tiers.limit_of(state.tier.clone()) {
+            limit = tier_limit.limit as u64;
+            expiration = tier_limit.expiration.unwrap_or(rule.expiration) as u64;
+        }
+    }
+
+    let schedules = rule.schedules.as_deref().unwrap_or_default();
+    if let Some((schedule, until_end)) = active_schedule(schedules, now) {
+        if schedule.closed {
+            return Precheck::Decided(
+                "rejected",
+                Some(RateLimiterHeaders::new(
+                    0,
+                    0,
+                    until_end,
+                    rule.algorithm.to_string(),
+                )),
+            );
+        }
+        (limit, expiration) = schedule.apply(limit, expiration, rule.limit);
+    }
+
+    if let Some(adaptive) = &rule.adaptive {
+        limit = adaptive.scale(
+            limit,
+            parse_multiplier(&rule.id, state.multiplier.as_deref()),
+        );
+    }
+
+    match key_override.map(|key_override| key_override.apply(limit, expiration)) {
+        // Unlimited keys are allowed without being counted.
+        Some(None) => Precheck::Decided("allowed", None),
+        Some(Some((limit, expiration))) => Precheck::Scripted(limit, expiration),
+        None => Precheck::Scripted(limit, expiration),
+    }
+}
+
+/// Rate limits a list of checks with the most specific rule of each route, as requests with the
+/// check key as tracked key would be. The bans, adaptive multipliers and tiers of the keys are read
+/// in a first round trip, and the algorithm scripts of all the checks executed in a second one.
+/// IP lists, which depend on the request, are ignored.
+async fn check_batch(
+    states: Arc<States>,
+    request: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, LimiterError> {
+    let body = Limited::new(request.into_body(), MAX_BATCH_BODY_SIZE)
+        .collect()
+        .await
+        .map_err(|err| LimiterError::InvalidRequest(err.to_string()))?
+        .to_bytes();
+    let checks: Vec<BatchCheck> = serde_json::from_slice(&body)
+        .map_err(|err| LimiterError::InvalidRequest(format!("Invalid batch: {err}")))?;
+
+    let matched_rules: Vec<Option<Arc<LoadedRule>>> = checks
+        .iter()
+        .map(|check| {
+            states
+                .most_specific_rule(&check.route)
+                .and_then(|rule_id| states.rule(&rule_id).ok())
+        })
+        .collect();
+
+    // Disabled rules let every check through, nothing is read for them.
+    let active_rules: Vec<Option<&Rule>> = matched_rules
+        .iter()
+        .map(|loaded_rule| {
+            loaded_rule
+                .as_ref()
+                .map(|loaded_rule| &loaded_rule.rule)
+                .filter(|rule| rule.active != Some(false))
+        })
+        .collect();
+    let looked_up: Vec<(&str, &Rule)> = checks
+        .iter()
+        .zip(&active_rules)
+        .filter_map(|(check, rule)| Some((check.key.as_str(), (*rule)?)))
+        .collect();
+    let mut key_states = get_key_states(&mut states.pool.clone(), &looked_up)
+        .await?
+        .into_iter();
+
+    // Checks which don't need their script are decided right away.
+    let now = Utc::now();
+    let mut decisions = vec![];
+    let mut scripted = vec![];
+    for (index, (check, loaded_rule)) in checks.iter().zip(&matched_rules).enumerate() {
+        let rule = loaded_rule.as_ref().map(|loaded_rule| &loaded_rule.rule);
+        let mut decision = BatchDecision {
+            route: &check.route,
+            key: &check.key,
+            decision: "allowed",
+            rule_id: rule.map(|rule| rule.id.as_str()),
+            headers: None,
+        };
+        match (rule, active_rules[index]) {
+            (None, _) => decision.decision = "unmatched",
+            (Some(_), None) => {}
+            (Some(_), Some(rule)) => {
+                let state = key_states.next().unwrap_or_default();
+                let key_override = states.key_override(&rule.id, &check.key);
+                match precheck(rule, &state, key_override.as_ref(), now) {
+                    Precheck::Decided(outcome, headers) => {
+                        decision.decision = outcome;
+                        decision.headers = headers;
+                    }
+                    Precheck::Scripted(limit, expiration) => scripted.push((
+                        index,
+                        RateLimitCheck {
+                            tracked_keys: vec![check.key.clone()],
+                            rule,
+                            limit,
+                            expiration,
+                            route: check.route.clone(),
+                        },
+                    )),
+                }
+            }
+        }
+        decisions.push(decision);
+    }
+
+    let (indexes, scripted_checks): (Vec<usize>, Vec<RateLimitCheck>) =
+        scripted.into_iter().unzip();
+    let results = match scripted_checks.is_empty() {
+        true => vec![],
+        false => execute_rate_limiting_batch(states.pool.clone(), &scripted_checks).await?,
+    };
+
+    for ((index, check), result) in indexes.into_iter().zip(&scripted_checks).zip(results) {
+        let mut metrics_properties: Vec<KeyValue> = check.rule.clone().into();
+        metrics_properties.push(KeyValue::new("batch", true));
+        states.rl_total_requests.add(1, &metrics_properties);
+        match result {
+            Ok(headers) => {
+                decisions[index].headers = Some(headers);
+                metrics_properties.push(KeyValue::new("http", "200"));
+                states.rl_allowed_requests.add(1, &metrics_properties);
+            }
+            Err(err) => {
+                decisions[index].decision = "rejected";
+                err.emit_metric(states.rl_rejected_requests.clone(), &mut metrics_properties);
+                if let LimiterError::RateLimitExceeded { headers, .. } = err {
+                    decisions[index].headers = Some(*headers);
+                }
+            }
+        }
+    }
+
+    let body = serde_json::to_string(&decisions)
+        .map_err(|err| LimiterError::Unknown(anyhow!("Unable to serialize decisions: {err}")))?;
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        penalty::Penalty,
+        rate_limiter::{LimiterTrackingType, RateLimiterAlgorithms},
+    };
+
+    fn rule() -> Rule {
+        Rule::new(
+            "/api/v1/jobs".to_string(),
+            RateLimiterAlgorithms::FixedWindow,
+            100,
+            60,
+            LimiterTrackingType::Header,
+            Some("x-api-key".to_string()),
+            None,
+        )
+    }
+
+    fn scripted(rule: &Rule, state: &KeyState, key_override: Option<&KeyOverride>) -> (u64, u64) {
+        match precheck(rule, state, key_override, Utc::now()) {
+            Precheck::Scripted(limit, expiration) => (limit, expiration),
+            Precheck::Decided(outcome, _) => panic!("Decided as {outcome}"),
+        }
+    }
+
+    fn decided(rule: &Rule, state: &KeyState, key_override: Option<&KeyOverride>) -> &'static str {
+        match precheck(rule, state, key_override, Utc::now()) {
+            Precheck::Decided(outcome, _) => outcome,
+            Precheck::Scripted(..) => panic!("Scripted"),
+        }
+    }
+
+    #[test]
+    fn banned_keys_are_reported_as_banned() {
+        let mut rule = rule();
+        let state = KeyState {
+            ban: Some(30),
+            ..Default::default()
+        };
+        // Bans are only looked at for rules with a penalty.
+        assert_eq!(scripted(&rule, &state, None), (100, 60));
+
+        rule.penalty = Some(Penalty {
+            rejections: 3,
+            window: 60,
+            ban: 300,
+            exponential: false,
+            max_ban: None,
+        });
+        let Precheck::Decided("banned", Some(headers)) = precheck(&rule, &state, None, Utc::now())
+        else {
+            panic!("Not banned");
+        };
+        assert_eq!((headers.remaining, headers.reset), (0, 30));
+    }
+
+    #[test]
+    fn overrides_and_adaptive_limits_apply_to_checks() {
+        let mut rule = rule();
+        let unlimited = KeyOverride {
+            limit: None,
+            expiration: None,
+            unlimited: true,
+        };
+        let higher = KeyOverride {
+            limit: Some(1000),
+            expiration: None,
+            unlimited: false,
+        };
+        let state = KeyState {
+            multiplier: Some("0.5".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(decided(&rule, &state, Some(&unlimited)), "allowed");
+        assert_eq!(scripted(&rule, &state, Some(&higher)), (1000, 60));
+
+        rule.adaptive = Some(serde_json::from_str("{}").unwrap());
+        assert_eq!(scripted(&rule, &state, None), (50, 60));
+        // The override of the key has the last word.
+        assert_eq!(scripted(&rule, &state, Some(&higher)), (1000, 60));
+    }
+
+    #[test]
+    fn only_tiers_stored_in_redis_can_be_resolved() {
+        let mut rule = rule();
+        rule.tiers = Some(
+            serde_json::from_str(
+                r#"{"source": "redis", "default": "free", "limits": {
+                    "free": {"limit": 10}, "enterprise": {"limit": 1000, "expiration": 3600}
+                }}"#,
+            )
+            .unwrap(),
+        );
+        let state = KeyState {
+            tier: Some("enterprise".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(scripted(&rule, &state, None), (1000, 3600));
+        assert_eq!(scripted(&rule, &KeyState::default(), None), (10, 60));
+
+        rule.tiers.as_mut().unwrap().source = TierSource::Header;
+        rule.tiers.as_mut().unwrap().key = Some("x-plan".to_string());
+        assert_eq!(decided(&rule, &state, None), "undecidable");
+    }
+}
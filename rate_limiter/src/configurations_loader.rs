@@ -1,35 +1,236 @@
 use anyhow::Context;
-use serde::Deserialize;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
-use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+use uuid::Uuid;
 
 use crate::{
-    rate_limiter::{LimiterTrackingType, RateLimiterAlgorithms},
-    rules::{Rule, get_rules_route_and_id},
-    utils::make_rules_configuration_script,
+    adaptive::Adaptive,
+    diff::diff_rules,
+    history::{checksum, default_author, get_rules_version, history_size},
+    penalty::Penalty,
+    rate_limiter::{
+        AggregateLimit, JwtTracking, LimiterTrackingType, MissingComponentBehaviour,
+        RateLimiterAlgorithms, Shaping, TrackingComponent,
+    },
+    rejections::RejectionResponse,
+    rules::{Rule, get_rules_documents, get_rules_route_and_id},
+    schedules::Schedule,
+    tiers::Tiers,
+    utils::{RULES_CONFIGURATION_SCRIPT, make_rules_configuration_args},
 };
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 struct Configuration {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>, // Stable identity of the rule, its route is used to find it otherwise
     pub route: String,
     pub algorithm: RateLimiterAlgorithms,
     pub limit: i32,
     pub expiration: i32,
     pub tracking_type: LimiterTrackingType,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub custom_tracking_key: Option<String>,
     pub active: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracking_components: Option<Vec<TrackingComponent>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_missing_component: Option<MissingComponentBehaviour>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<JwtTracking>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tiers: Option<Tiers>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_cidrs: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub denied_cidrs: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub penalty: Option<Penalty>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route_limit: Option<AggregateLimit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub global_limit: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedules: Option<Vec<Schedule>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<RejectionResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shaping: Option<Shaping>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<Adaptive>,
 }
 
-pub async fn load_configuration(config_file: &Path) -> anyhow::Result<()> {
-    let start_time = std::time::Instant::now();
+impl Configuration {
+    /// Builds the rule described by this configuration.
+    /// The id is either the explicit one, the one of the existing rule with the same route or a new one.
+    /// Ids claimed explicitly by the configuration file are never reused by route.
+    fn into_rule(
+        self,
+        rules_to_ids: &HashMap<String, String>,
+        explicit_ids: &HashSet<String>,
+    ) -> anyhow::Result<Rule> {
+        let previous_id = rules_to_ids
+            .get(&self.route)
+            .filter(|id| !explicit_ids.contains(*id));
+        let id = match (self.id, previous_id) {
+            (Some(id), _) => {
+                validate_id(&id, &self.route)?;
+                tracing::debug!(
+                    "= Route {} is loaded with its explicit id {}",
+                    self.route,
+                    id
+                );
+                id
+            }
+            (None, Some(id)) => {
+                tracing::debug!(
+                    "- Route {} already exists with id {}. Id will be reused",
+                    self.route,
+                    id
+                );
+                id.clone()
+            }
+            (None, None) => {
+                let id = Uuid::new_v4().to_string();
+                tracing::debug!("+ Route {} will be added with id {}", self.route, id);
+                id
+            }
+        };
+
+        let rule = Rule {
+            tracking_components: self.tracking_components,
+            on_missing_component: self.on_missing_component,
+            jwt: self.jwt,
+            tiers: self.tiers,
+            allowed_cidrs: self.allowed_cidrs,
+            denied_cidrs: self.denied_cidrs,
+            penalty: self.penalty,
+            route_limit: self.route_limit,
+            global_limit: self.global_limit,
+            priority: self.priority,
+            schedules: self.schedules,
+            rejection: self.rejection,
+            shaping: self.shaping,
+            adaptive: self.adaptive,
+            ..Rule::with_id(
+                id,
+                self.route,
+                self.algorithm,
+                self.limit,
+                self.expiration,
+                self.tracking_type,
+                self.custom_tracking_key,
+                self.active,
+            )
+        };
+        rule.validate()?;
+
+        Ok(rule)
+    }
+}
+
+/// Ids end up in JSON paths and redis keys, only accept the ones made of letters, digits, `-` and `_`.
+fn validate_id(id: &str, route: &str) -> anyhow::Result<()> {
+    if id.is_empty()
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        anyhow::bail!(
+            "Invalid id {id} for route {route}, ids are made of letters, digits, '-' and '_'."
+        );
+    }
+    Ok(())
+}
 
+/// Builds the rules of a configuration file, two rules can't share an id.
+fn into_rules(
+    configurations: Vec<Configuration>,
+    rules_to_ids: &HashMap<String, String>,
+) -> anyhow::Result<Vec<Rule>> {
+    let explicit_ids: HashSet<String> =
+        configurations.iter().filter_map(|c| c.id.clone()).collect();
+    let rules: Vec<Rule> = configurations
+        .into_iter()
+        .map(|c| c.into_rule(rules_to_ids, &explicit_ids))
+        .collect::<anyhow::Result<_>>()?;
+
+    let mut routes_by_id: HashMap<&str, &str> = HashMap::new();
+    for rule in &rules {
+        if let Some(route) = routes_by_id.insert(&rule.id, &rule.route) {
+            anyhow::bail!(
+                "Duplicate id {} for routes {} and {}",
+                rule.id,
+                route,
+                rule.route
+            );
+        }
+    }
+
+    Ok(rules)
+}
+
+impl From<Rule> for Configuration {
+    fn from(rule: Rule) -> Self {
+        Configuration {
+            id: Some(rule.id),
+            route: rule.route,
+            algorithm: rule.algorithm,
+            limit: rule.limit,
+            expiration: rule.expiration,
+            tracking_type: rule.tracking_type,
+            custom_tracking_key: rule.custom_tracking_key.filter(|key| !key.is_empty()),
+            active: rule.active,
+            tracking_components: rule.tracking_components,
+            on_missing_component: rule.on_missing_component,
+            jwt: rule.jwt,
+            tiers: rule.tiers,
+            allowed_cidrs: rule.allowed_cidrs,
+            denied_cidrs: rule.denied_cidrs,
+            penalty: rule.penalty,
+            route_limit: rule.route_limit,
+            global_limit: rule.global_limit,
+            priority: rule.priority,
+            schedules: rule.schedules,
+            rejection: rule.rejection,
+            shaping: rule.shaping,
+            adaptive: rule.adaptive,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Yaml,
+    Json,
+}
+
+pub fn connect_to_redis() -> anyhow::Result<redis::Connection> {
     tracing::debug!("Reading environment variables...");
     let redis_host = std::env::var("RL_REDIS_HOST").unwrap_or("localhost".to_string());
     let redis_port = std::env::var("RL_REDIS_PORT").unwrap_or("6379".to_string());
 
+    tracing::info!("connecting to redis...");
+    let client = redis::Client::open(format!("redis://{}:{}", redis_host, redis_port))?;
+    Ok(client.get_connection()?)
+}
+
+pub async fn load_configuration(
+    config_file: &Path,
+    dry_run: bool,
+    author: Option<String>,
+) -> anyhow::Result<()> {
+    let start_time = std::time::Instant::now();
+
     let file_extension = config_file.extension().unwrap_or_default();
-    if file_extension != "yaml" && file_extension != "yml" {
-        panic!("Configuration file must be a yaml file.");
+    if file_extension != "yaml" && file_extension != "yml" && file_extension != "json" {
+        panic!("Configuration file must be a yaml or json file.");
     }
 
     tracing::info!("Reading configuration file...");
@@ -41,58 +242,61 @@ pub async fn load_configuration(config_file: &Path) -> anyhow::Result<()> {
             )
         })?;
 
-    tracing::info!("connecting to redis...");
-    let client = redis::Client::open(format!("redis://{}:{}", redis_host, redis_port))?;
-    let mut con = client.get_connection()?;
+    let mut con = connect_to_redis()?;
+
+    // Read before the rules themselves: a load happening in between makes the apply fail
+    // instead of overwriting it.
+    let base_version = get_rules_version(&mut con)?;
+    tracing::info!("Current rules version is {base_version}.");
 
     tracing::info!("Getting previous rules (route, id) pairs from redis...");
     let rules_to_ids = get_rules_route_and_id(&mut con).map_err(anyhow::Error::from_boxed)?;
     tracing::debug!("Previous rules :: {:#?}", rules_to_ids);
 
     tracing::info!("Parsing rules...");
-    let rules: Vec<Rule> = serde_yaml::from_str::<Vec<Configuration>>(&content)
-        .with_context(|| "Invalid configuration file.".to_string())?
-        .into_iter()
-        .map(|c| {
-            if let Some(id) = rules_to_ids.get(&c.route) {
-                tracing::debug!(
-                    "- Route {} already exists with id {}. Id will be reused",
-                    c.route,
-                    id
-                );
-                return Rule {
-                    id: id.clone(),
-                    route: c.route,
-                    algorithm: c.algorithm,
-                    tracking_type: c.tracking_type,
-                    limit: c.limit,
-                    expiration: c.expiration,
-                    custom_tracking_key: c.custom_tracking_key,
-                    active: c.active,
-                };
-            }
-
-            let rule = Rule::new(
-                c.route,
-                c.algorithm,
-                c.limit,
-                c.expiration,
-                c.tracking_type,
-                c.custom_tracking_key,
-                c.active,
-            );
-            tracing::debug!("+ Route {} will be added with id {}", &rule.route, &rule.id);
-            rule
-        })
-        .collect();
+    let configurations: Vec<Configuration> = if file_extension == "json" {
+        serde_json::from_str(&content).with_context(|| "Invalid configuration file.".to_string())?
+    } else {
+        serde_yaml::from_str(&content).with_context(|| "Invalid configuration file.".to_string())?
+    };
+    let rules = into_rules(configurations, &rules_to_ids)?;
 
     tracing::info!("Processed {} rules.", rules.len());
-    tracing::info!("Creating redis script...");
-    let generated_script = make_rules_configuration_script(rules);
-    tracing::debug!("Script :: {:#?}", generated_script);
 
-    tracing::info!("Publishing script to redis store...");
-    let _: () = generated_script.invoke(&mut con)?;
+    tracing::info!("Comparing rules against the ones stored in redis...");
+    let live_rules = get_rules_documents(&mut con).map_err(anyhow::Error::from_boxed)?;
+    let desired_rules: Vec<_> = rules.iter().map(Rule::to_redis_json).collect();
+    let diff = diff_rules(&live_rules, &desired_rules);
+
+    if dry_run {
+        println!("{diff}");
+
+        if !diff.is_empty() {
+            anyhow::bail!("Dry run: {} rule(s) would be changed.", diff.len());
+        }
+        tracing::info!("Dry run: rules are up to date.");
+        return Ok(());
+    }
+
+    if diff.is_empty() {
+        tracing::info!("Rules are already up to date, nothing to apply.");
+        return Ok(());
+    }
+    tracing::info!("{diff}");
+
+    tracing::info!("Creating redis script arguments...");
+    let script_args = make_rules_configuration_args(&diff);
+    tracing::debug!("Script arguments :: {:#?}", script_args);
+
+    tracing::info!("Publishing changes to redis store...");
+    let version: u64 = RULES_CONFIGURATION_SCRIPT
+        .arg(author.unwrap_or_else(default_author))
+        .arg(checksum(&content))
+        .arg(history_size())
+        .arg(base_version)
+        .arg(script_args)
+        .invoke(&mut con)?;
+    tracing::info!("Rules recorded as version {version}.");
 
     let duration = start_time.elapsed();
     tracing::info!(
@@ -101,3 +305,173 @@ pub async fn load_configuration(config_file: &Path) -> anyhow::Result<()> {
     );
     Ok(())
 }
+
+pub async fn export_configuration(
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> anyhow::Result<()> {
+    let mut con = connect_to_redis()?;
+
+    tracing::info!("Getting rules from redis...");
+    let mut configurations: Vec<Configuration> = get_rules_documents(&mut con)
+        .map_err(anyhow::Error::from_boxed)?
+        .into_values()
+        .map(|document| serde_json::from_value::<Rule>(document).map(Configuration::from))
+        .collect::<Result<_, _>>()
+        .with_context(|| "Invalid rule stored in redis.".to_string())?;
+    configurations.sort_by(|a, b| a.route.cmp(&b.route));
+
+    let content = match format {
+        ExportFormat::Yaml => serde_yaml::to_string(&configurations)?,
+        ExportFormat::Json => serde_json::to_string_pretty(&configurations)?,
+    };
+
+    match output {
+        Some(path) => {
+            std::fs::write(path, content)
+                .with_context(|| format!("Failed to write file: {}", path.display()))?;
+            tracing::info!(
+                "Exported {} rules to {}.",
+                configurations.len(),
+                path.display()
+            );
+        }
+        None => println!("{content}"),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST_ID: &str = "2b1f6f5e-2c1a-4a8e-9a43-0f1e0c6c1a01";
+
+    fn configurations(content: &str) -> Vec<Configuration> {
+        serde_yaml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn ids_are_explicit_reused_or_generated() {
+        let rules_to_ids = HashMap::from([(
+            "/api/existing".to_string(),
+            "0c3d2f4e-7b6a-4c1d-8e9f-a0b1c2d3e4f5".to_string(),
+        )]);
+        let rules = into_rules(
+            configurations(&format!(
+                r#"
+- id: {FIRST_ID}
+  route: /api/explicit
+  algorithm: fw
+  limit: 10
+  expiration: 60
+  tracking_type: ip
+- route: /api/existing
+  algorithm: fw
+  limit: 10
+  expiration: 60
+  tracking_type: ip
+- route: /api/new
+  algorithm: fw
+  limit: 10
+  expiration: 60
+  tracking_type: ip
+"#
+            )),
+            &rules_to_ids,
+        )
+        .unwrap();
+
+        assert_eq!(rules[0].id, FIRST_ID);
+        assert_eq!(rules[1].id, rules_to_ids["/api/existing"]);
+        assert!(Uuid::parse_str(&rules[2].id).is_ok());
+    }
+
+    #[test]
+    fn explicit_ids_survive_a_route_change() {
+        let rules_to_ids = HashMap::from([
+            ("/api/orders".to_string(), "orders".to_string()),
+            ("/api/search".to_string(), FIRST_ID.to_string()),
+        ]);
+        let rules = into_rules(
+            configurations(
+                r#"
+- id: orders
+  route: /api/v2/orders
+  algorithm: fw
+  limit: 10
+  expiration: 60
+  tracking_type: ip
+- route: /api/orders
+  algorithm: fw
+  limit: 10
+  expiration: 60
+  tracking_type: ip
+- route: /api/search
+  algorithm: fw
+  limit: 10
+  expiration: 60
+  tracking_type: ip
+"#,
+            ),
+            &rules_to_ids,
+        )
+        .unwrap();
+
+        assert_eq!(rules[0].id, "orders");
+        // The previous id of the route is claimed by the renamed rule, a new one is generated.
+        assert!(Uuid::parse_str(&rules[1].id).is_ok());
+        assert_eq!(rules[2].id, FIRST_ID);
+    }
+
+    #[test]
+    fn invalid_ids_are_rejected() {
+        for id in ["\"a.b\"", "\"a b\"", "\"a]\"", "\"\""] {
+            let error = into_rules(
+                configurations(&format!(
+                    r#"
+- id: {id}
+  route: /api/a
+  algorithm: fw
+  limit: 10
+  expiration: 60
+  tracking_type: ip
+"#
+                )),
+                &HashMap::new(),
+            )
+            .unwrap_err();
+            assert!(error.to_string().starts_with("Invalid id"), "{id}: {error}");
+        }
+    }
+
+    #[test]
+    fn duplicate_ids_are_rejected() {
+        let error = into_rules(
+            configurations(&format!(
+                r#"
+- id: {FIRST_ID}
+  route: /api/a
+  algorithm: fw
+  limit: 10
+  expiration: 60
+  tracking_type: ip
+- id: {FIRST_ID}
+  route: /api/b
+  algorithm: tb
+  limit: 5
+  expiration: 60
+  tracking_type: ip
+"#
+            )),
+            &HashMap::new(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            format!("Duplicate id {FIRST_ID} for routes /api/a and /api/b")
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use serde_json::Value;
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+#[derive(Debug, Clone)]
+pub struct FieldChange {
+    pub field: String,
+    pub previous: Value,
+    pub next: Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleChange {
+    pub next: Value, // The rule as it would be stored after the load
+    pub fields: Vec<FieldChange>,
+}
+
+/// Differences between the rules stored in redis and the rules of a configuration file.
+/// Rules are paired by id, every field but the id is compared.
+#[derive(Debug, Default)]
+pub struct RulesDiff {
+    pub added: Vec<Value>,
+    pub removed: Vec<Value>,
+    pub changed: Vec<RuleChange>,
+}
+
+impl RulesDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.added.len() + self.removed.len() + self.changed.len()
+    }
+}
+
+fn field_as_str<'a>(document: &'a Value, field: &str) -> &'a str {
+    document
+        .get(field)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+}
+
+fn compare_documents(previous: &Value, next: &Value) -> Vec<FieldChange> {
+    let fields: BTreeSet<&String> = previous
+        .as_object()
+        .into_iter()
+        .chain(next.as_object())
+        .flat_map(|document| document.keys())
+        .filter(|field| field.as_str() != "id")
+        .collect();
+
+    fields
+        .into_iter()
+        .filter_map(|field| {
+            let before = previous.get(field).cloned().unwrap_or(Value::Null);
+            let after = next.get(field).cloned().unwrap_or(Value::Null);
+            (before != after).then(|| FieldChange {
+                field: field.clone(),
+                previous: before,
+                next: after,
+            })
+        })
+        .collect()
+}
+
+/// Computes the changes required to go from the `live` rules (indexed by id) to the `desired` ones.
+pub fn diff_rules(live: &HashMap<String, Value>, desired: &[Value]) -> RulesDiff {
+    let mut diff = RulesDiff::default();
+
+    for next in desired {
+        match live.get(field_as_str(next, "id")) {
+            None => diff.added.push(next.clone()),
+            Some(previous) => {
+                let fields = compare_documents(previous, next);
+                if !fields.is_empty() {
+                    diff.changed.push(RuleChange {
+                        next: next.clone(),
+                        fields,
+                    });
+                }
+            }
+        }
+    }
+
+    diff.removed = live
+        .iter()
+        .filter(|(id, _)| {
+            !desired
+                .iter()
+                .any(|document| field_as_str(document, "id") == id.as_str())
+        })
+        .map(|(_, document)| document.clone())
+        .collect();
+
+    diff.added
+        .sort_by(|a, b| field_as_str(a, "route").cmp(field_as_str(b, "route")));
+    diff.removed
+        .sort_by(|a, b| field_as_str(a, "route").cmp(field_as_str(b, "route")));
+    diff.changed
+        .sort_by(|a, b| field_as_str(&a.next, "route").cmp(field_as_str(&b.next, "route")));
+
+    diff
+}
+
+impl fmt::Display for RulesDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for document in &self.added {
+            writeln!(
+                f,
+                "+ {} (id {})",
+                field_as_str(document, "route"),
+                field_as_str(document, "id")
+            )?;
+            for (field, value) in document.as_object().into_iter().flatten() {
+                if field != "id" {
+                    writeln!(f, "      {field}: {value}")?;
+                }
+            }
+        }
+
+        for document in &self.removed {
+            writeln!(
+                f,
+                "- {} (id {})",
+                field_as_str(document, "route"),
+                field_as_str(document, "id")
+            )?;
+        }
+
+        for change in &self.changed {
+            writeln!(
+                f,
+                "~ {} (id {})",
+                field_as_str(&change.next, "route"),
+                field_as_str(&change.next, "id")
+            )?;
+            for field in &change.fields {
+                writeln!(
+                    f,
+                    "      {}: {} -> {}",
+                    field.field, field.previous, field.next
+                )?;
+            }
+        }
+
+        write!(
+            f,
+            "{} added, {} removed, {} changed.",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn live(documents: &[Value]) -> HashMap<String, Value> {
+        documents
+            .iter()
+            .map(|document| (field_as_str(document, "id").to_string(), document.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_rules_produce_an_empty_diff() {
+        let rule = json!({"id": "a", "route": "/a", "limit": 10, "expiration": 60});
+
+        let diff = diff_rules(&live(std::slice::from_ref(&rule)), &[rule]);
+
+        assert!(diff.is_empty());
+        assert_eq!(diff.len(), 0);
+        assert_eq!(diff.to_string(), "0 added, 0 removed, 0 changed.");
+    }
+
+    #[test]
+    fn rules_are_paired_by_id() {
+        let kept = json!({"id": "a", "route": "/a", "limit": 10});
+        let dropped = json!({"id": "b", "route": "/b", "limit": 10});
+        let created = json!({"id": "c", "route": "/c", "limit": 5});
+        // Same route as the dropped rule but a different id: a removal and an addition
+        let recreated = json!({"id": "d", "route": "/b", "limit": 10});
+
+        let diff = diff_rules(
+            &live(&[kept.clone(), dropped.clone()]),
+            &[kept, created.clone(), recreated.clone()],
+        );
+
+        assert_eq!(diff.added, vec![recreated, created]);
+        assert_eq!(diff.removed, vec![dropped]);
+        assert!(diff.changed.is_empty());
+        assert_eq!(diff.len(), 3);
+    }
+
+    #[test]
+    fn changed_fields_are_listed_with_their_values() {
+        let previous = json!({"id": "a", "route": "/a", "limit": 10, "active": true});
+        let next = json!({"id": "a", "route": "/a", "limit": 20, "priority": 1, "active": true});
+
+        let diff = diff_rules(&live(&[previous]), std::slice::from_ref(&next));
+
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.changed.len(), 1);
+        let change = &diff.changed[0];
+        assert_eq!(change.next, next);
+        let fields: Vec<(&str, &Value, &Value)> = change
+            .fields
+            .iter()
+            .map(|field| (field.field.as_str(), &field.previous, &field.next))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("limit", &json!(10), &json!(20)),
+                ("priority", &Value::Null, &json!(1)),
+            ]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "~ /a (id a)\n      limit: 10 -> 20\n      priority: null -> 1\n0 added, 0 removed, 1 changed."
+        );
+    }
+}
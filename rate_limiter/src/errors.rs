@@ -4,19 +4,22 @@ use anyhow::anyhow;
 
 use bytes::Bytes;
 use http_body_util::Full;
-use hyper::{Response, StatusCode};
+use hyper::{
+    Response, StatusCode,
+    header::{CONTENT_TYPE, HeaderValue},
+};
 use opentelemetry::{KeyValue, metrics::Counter};
 use redis::RedisError;
 use thiserror::Error;
 
-use crate::rate_limiter::RateLimiterHeaders;
+use crate::rate_limiter::{LimitLevel, RateLimiterHeaders};
 
 #[derive(Error, Debug)]
 pub enum LimiterError {
     #[error("No match found for route {0}")]
     NoRouteMatch(String),
 
-    #[error("Tracked key {0} not found in request headers")]
+    #[error("Tracked key {0} not found in request")]
     TrackedKeyNotFound(String),
 
     #[error(
@@ -24,13 +27,38 @@ pub enum LimiterError {
     )]
     NoIpFound,
 
+    #[error("{0}")]
+    InvalidRequest(String),
+
+    #[error("Access denied for {0}")]
+    IpDenied(String),
+
+    #[error("A valid admin token is required")]
+    Unauthorized,
+
     #[error("Rate limit exceeded for {key} on route {route}")]
     RateLimitExceeded {
-        headers: RateLimiterHeaders,
+        headers: Box<RateLimiterHeaders>,
         key: String,
         msg: String,
         route: String,
+        level: LimitLevel, // Level of the rule which rejected the request: key, route or global
+    },
+
+    #[error("Rate limit exceeded, rejected with a response prepared by the handler")]
+    CustomRejection {
+        response: Box<Response<Full<Bytes>>>,
+        level: LimitLevel,
+        banned: bool, // Rejected because of a penalty ban rather than by the algorithm
+    },
+
+    #[error("{key} is banned from route {route} for {retry_after} seconds")]
+    Banned {
+        key: String,
+        route: String,
+        retry_after: u64,
     },
+
     #[error("Internal Server Error")]
     RedisError(#[from] RedisError),
 
@@ -41,25 +69,33 @@ pub enum LimiterError {
 impl LimiterError {
     pub fn into_hyper_response(self) -> Response<Full<Bytes>> {
         tracing::debug!("Limiter Error : {:#?}", &self,);
-        match &self {
+        let message = self.to_string();
+        match self {
             LimiterError::NoRouteMatch(_msg) => Response::builder()
-                .status(StatusCode::OK)
-                .body(Full::new(Bytes::from(self.to_string())))
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::new(Bytes::from(message)))
                 .unwrap(),
             LimiterError::TrackedKeyNotFound(_msg) => Response::builder()
                 .status(StatusCode::BAD_REQUEST)
-                .body(Full::new(Bytes::from(self.to_string())))
+                .body(Full::new(Bytes::from(message)))
                 .unwrap(),
             LimiterError::NoIpFound => Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body(Full::new(Bytes::from(LimiterError::NoIpFound.to_string())))
                 .unwrap(),
-            LimiterError::RateLimitExceeded {
-                headers,
-                key: _,
-                msg: _,
-                route: _,
-            } => Response::builder()
+            LimiterError::InvalidRequest(_) => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Full::new(Bytes::from(message)))
+                .unwrap(),
+            LimiterError::IpDenied(_) => Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Full::new(Bytes::from(message)))
+                .unwrap(),
+            LimiterError::Unauthorized => Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(Full::new(Bytes::from(message)))
+                .unwrap(),
+            LimiterError::RateLimitExceeded { headers, .. } => Response::builder()
                 .status(StatusCode::TOO_MANY_REQUESTS)
                 .header("limit", headers.limit)
                 .header("remaining", headers.remaining)
@@ -67,6 +103,14 @@ impl LimiterError {
                 .header("policy", headers.policy.clone())
                 .body(Full::new(Bytes::from("Rate limit exceeded!")))
                 .unwrap(),
+            LimiterError::CustomRejection { response, .. } => *response,
+            LimiterError::Banned { retry_after, .. } => Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header("retry-after", retry_after)
+                .body(Full::new(Bytes::from(
+                    "Too many rejections, try again later.",
+                )))
+                .unwrap(),
             LimiterError::RedisError(_) => Response::builder()
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .body(Full::new(Bytes::from("Internal Server Error")))
@@ -78,17 +122,55 @@ impl LimiterError {
         }
     }
 
+    /// Same response as `into_hyper_response`, with a JSON document as body for the callers
+    /// accepting JSON. Rejections prepared by the handler are returned as is.
+    pub fn into_json_response(self) -> Response<Full<Bytes>> {
+        let decision = match &self {
+            LimiterError::CustomRejection { .. } => return self.into_hyper_response(),
+            LimiterError::NoRouteMatch(_) => "unmatched",
+            LimiterError::TrackedKeyNotFound(_)
+            | LimiterError::NoIpFound
+            | LimiterError::InvalidRequest(_) => "invalid",
+            LimiterError::IpDenied(_) | LimiterError::Unauthorized => "denied",
+            LimiterError::RateLimitExceeded { .. } => "rejected",
+            LimiterError::Banned { .. } => "banned",
+            LimiterError::RedisError(_) | LimiterError::Unknown(_) => "error",
+        };
+        let error = match &self {
+            LimiterError::RedisError(_) | LimiterError::Unknown(_) => {
+                "Internal Server Error".to_string()
+            }
+            _ => self.to_string(),
+        };
+        let document = serde_json::json!({ "decision": decision, "error": error });
+
+        let mut response = self.into_hyper_response();
+        *response.body_mut() = Full::new(Bytes::from(document.to_string()));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        response
+    }
+
     pub fn emit_metric(&self, counter: Counter<u64>, key_values: &mut Vec<KeyValue>) {
         let http = match &self {
             LimiterError::NoRouteMatch(_) => KeyValue::new("http", "404"),
             LimiterError::TrackedKeyNotFound(_) => KeyValue::new("http", "400"),
             LimiterError::NoIpFound => KeyValue::new("http", "400"),
-            LimiterError::RateLimitExceeded {
-                headers: _,
-                key: _,
-                msg: _,
-                route: _,
-            } => KeyValue::new("http", "429"),
+            LimiterError::InvalidRequest(_) => KeyValue::new("http", "400"),
+            LimiterError::IpDenied(_) => KeyValue::new("http", "403"),
+            LimiterError::Unauthorized => KeyValue::new("http", "401"),
+            LimiterError::RateLimitExceeded { level, .. } => {
+                key_values.push(KeyValue::new("level", level.to_string()));
+                KeyValue::new("http", "429")
+            }
+            LimiterError::CustomRejection {
+                response, level, ..
+            } => {
+                key_values.push(KeyValue::new("level", level.to_string()));
+                KeyValue::new("http", response.status().as_str().to_string())
+            }
+            LimiterError::Banned { .. } => KeyValue::new("http", "429"),
             LimiterError::RedisError(_) => KeyValue::new("http", "500"),
             LimiterError::Unknown(_) => KeyValue::new("http", "500"),
         };
@@ -104,3 +186,43 @@ impl<T> From<PoisonError<T>> for LimiterError {
         error.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn json_body(response: Response<Full<Bytes>>) -> serde_json::Value {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn errors_are_json_documents_for_json_callers() {
+        let denied = LimiterError::IpDenied("10.0.0.1".to_string()).into_json_response();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        assert_eq!(denied.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(
+            json_body(denied).await,
+            serde_json::json!({ "decision": "denied", "error": "Access denied for 10.0.0.1" })
+        );
+
+        let unmatched = LimiterError::NoRouteMatch("/nowhere".to_string()).into_json_response();
+        assert_eq!(unmatched.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json_body(unmatched).await["decision"], "unmatched");
+
+        let banned = LimiterError::Banned {
+            key: "key".to_string(),
+            route: "/api".to_string(),
+            retry_after: 60,
+        }
+        .into_json_response();
+        assert_eq!(banned.headers()["retry-after"], "60");
+        assert_eq!(json_body(banned).await["decision"], "banned");
+
+        // Internal errors are not leaked.
+        let unknown = LimiterError::Unknown(anyhow!("secret")).into_json_response();
+        assert_eq!(unknown.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(json_body(unknown).await["error"], "Internal Server Error");
+    }
+}
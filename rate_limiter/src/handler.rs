@@ -1,82 +1,160 @@
 use anyhow::anyhow;
 use bytes::Bytes;
+use chrono::Utc;
 use opentelemetry::KeyValue;
-use std::sync::Arc;
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
 
 use crate::{
+    adaptive::get_multiplier,
     errors::LimiterError,
-    rate_limiter::execute_rate_limiting,
+    history::checksum,
+    penalty::{Penalty, get_ban, record_rejection},
+    rate_limiter::{LimitLevel, RateLimiterHeaders, Shaping, execute_rate_limiting},
+    rules::{DEFAULT_ROUTE, LoadedRule, Rule, UnmatchedRoutes},
+    schedules::active_schedule,
     server_state::States,
-    utils::{get_rules_information_by_redis_json_key, get_tracked_key_from_header},
+    tiers::resolve_tier,
+    utils::{RequestParts, RouteMatching, combine_tracked_keys, get_tracked_keys},
 };
 
 use http_body_util::Full;
-use hyper::{Request, Response};
+use hyper::{
+    Request, Response, StatusCode,
+    header::{ACCEPT, CONTENT_TYPE, RETRY_AFTER},
+    http::response::Builder,
+};
+use serde::Serialize;
+
+/// Admin endpoint answering the remaining requests of a caller for the path following it,
+/// ex: `/peek/api/v1/orders`, without counting the request.
+pub const PEEK_PATH: &str = "/peek";
+
+/// Path peeked at by a request to the peek endpoint.
+pub fn peeked_path(path: &str) -> Option<&str> {
+    match path.strip_prefix(PEEK_PATH)? {
+        "" => Some("/"),
+        peeked if peeked.starts_with('/') => Some(peeked),
+        _ => None,
+    }
+}
+
+fn allowed_response() -> Result<Response<Full<Bytes>>, LimiterError> {
+    Response::builder()
+        .body(Full::new(Bytes::from("Rate limit not exceeded.")))
+        .map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response")))
+}
 
 pub async fn limiter_handler(
     states: Arc<States>,
     request: Request<hyper::body::Incoming>,
 ) -> anyhow::Result<Response<Full<Bytes>>, LimiterError> {
-    let path = request.uri().path();
+    limit_request(states, request, false).await
+}
+
+pub async fn peek_handler(
+    states: Arc<States>,
+    request: Request<hyper::body::Incoming>,
+) -> anyhow::Result<Response<Full<Bytes>>, LimiterError> {
+    limit_request(states, request, true).await
+}
+
+/// Rate limits the request with the rules matching its path, or only reads the remaining
+/// requests of the caller when peeking.
+async fn limit_request(
+    states: Arc<States>,
+    request: Request<hyper::body::Incoming>,
+    peek: bool,
+) -> anyhow::Result<Response<Full<Bytes>>, LimiterError> {
+    let path = match peek {
+        true => peeked_path(request.uri().path()).unwrap_or("/"),
+        false => request.uri().path(),
+    };
     let mut metrics_properties = vec![];
     let res = async {
-        // Retrieve the key associated with this route using the matcher.
-        // That key will be used to index the rule information inside the from the cache.
-        let associated_key = states
-            .route_matcher
-            .clone()
-            .read()
-            .at(path)
-            .map_err(|_err| LimiterError::NoRouteMatch(path.to_string()))?
-            .value
-            .clone();
-
-        // Retrieve the rule informations from the redis cache.
-        let limiter_rule =
-            get_rules_information_by_redis_json_key(&mut states.pool.clone(), &associated_key)
-                .await?;
-
-        metrics_properties = limiter_rule.clone().into();
-
-        // In case the rule is disabled (active=false)
-        if let Some(v) = &limiter_rule.active
-            && !(*v)
-        {
-            let response = Response::builder()
-                .body(Full::new(Bytes::from("Rate limit not exceeded.")))
-                .map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response")))?;
-
-            return Ok(response);
-        }
-
-        let tracking_key = get_tracked_key_from_header(
-            request.headers(),
-            &limiter_rule.tracking_type,
-            limiter_rule.custom_tracking_key.as_deref(),
-        )?;
-
-        let headers = execute_rate_limiting(
-            states.pool.clone(),
-            &tracking_key,
-            &associated_key,
-            &limiter_rule.algorithm,
-            limiter_rule.limit as u64,
-            limiter_rule.expiration as u64,
-            path,
-        )
-        .await?;
+        // Global lists apply to every request, whether a rule matches it or not.
+        let client_ip = states.trusted_proxies.client_ip(request.headers());
+        if let Some(ip) = client_ip {
+            if states.ip_filter.denies(ip) {
+                return Err(LimiterError::IpDenied(ip.to_string()));
+            }
+            if states.ip_filter.allows(ip) {
+                metrics_properties.push(KeyValue::new("ip_filter", "allowed"));
+                return allowed_response();
+            }
+        }
 
-        let response = Response::builder()
-            .header("limit", headers.limit)
-            .header("remaining", headers.remaining)
-            .header("reset", headers.reset)
-            .header("policy", headers.policy)
-            .body(Full::new(Bytes::from("Rate limit not exceeded")))
-            .map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response")));
+        // Retrieve the keys associated with this route using the matcher.
+        // Those keys will be used to index the rule information inside the from the cache.
+        // The route parameters are kept as they may be used as tracked keys.
+        let mut matched = match states.route_matching {
+            RouteMatching::MostSpecific => {
+                let most_specific = states.route_matcher.read().most_specific(path);
+                most_specific.into_iter().collect()
+            }
+            RouteMatching::All => states.route_matcher.read().at(path),
+        };
+        if matched.is_empty()
+            && states.unmatched_routes == UnmatchedRoutes::Default
+            && let Some(default_rule) = states.route_matcher.read().default_rule.clone()
+        {
+            matched.push((default_rule, HashMap::new()));
+        }
+        if matched.is_empty() {
+            return match states.unmatched_routes {
+                UnmatchedRoutes::Reject => {
+                    metrics_properties.push(KeyValue::new("unmatched", "rejected"));
+                    Err(LimiterError::NoRouteMatch(path.to_string()))
+                }
+                _ => {
+                    metrics_properties.push(KeyValue::new("unmatched", "allowed"));
+                    allowed_response()
+                }
+            };
+        }
 
+        // Every matched rule must allow the request, the response is the one of the first rule.
+        let passes = passes(peek, matched.len());
+        let mut response = None;
+        for (
+            pass,
+            &Pass {
+                consume,
+                record_penalties,
+            },
+        ) in passes.iter().enumerate()
+        {
+            let answering = pass + 1 == passes.len();
+            for (associated_key, path_params) in &matched {
+                let mut rule_properties = vec![];
+                let rule_response = apply_rule(
+                    &states,
+                    &request,
+                    path,
+                    associated_key,
+                    path_params,
+                    client_ip,
+                    consume,
+                    record_penalties,
+                    peek,
+                    &mut rule_properties,
+                )
+                .await;
+                // The metrics are labelled with the rule answering the request.
+                if rule_response.is_err() || (answering && response.is_none()) {
+                    metrics_properties = rule_properties;
+                }
+                let rule_response = rule_response?;
+                if answering {
+                    response.get_or_insert(rule_response);
+                }
+            }
+        }
+        if matched.len() > 1 {
+            metrics_properties.push(KeyValue::new("matched_rules", matched.len() as i64));
+        }
         metrics_properties.push(KeyValue::new("http", "200"));
 
-        response
+        Ok(response.unwrap())
     };
 
     return match res.await {
@@ -87,8 +165,678 @@ pub async fn limiter_handler(
         }
         Err(err) => {
             states.rl_total_requests.add(1, &metrics_properties);
+            if matches!(
+                err,
+                LimiterError::Banned { .. } | LimiterError::CustomRejection { banned: true, .. }
+            ) {
+                states.rl_banned_requests.add(1, &metrics_properties);
+            }
             err.emit_metric(states.rl_rejected_requests.clone(), &mut metrics_properties);
-            Ok(err.into_hyper_response())
+            match accepts_json(&request) {
+                true => Ok(err.into_json_response()),
+                false => Ok(err.into_hyper_response()),
+            }
+        }
+    };
+}
+
+/// How a pass over the matched rules treats the request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Pass {
+    consume: bool,
+    // Whether rejections count towards the penalty of the rule
+    record_penalties: bool,
+}
+
+/// The passes over the matched rules. With several rules, they are all checked before any is
+/// counted, so that a rule rejecting the request does not leave the previous ones consumed.
+/// Rejections are recorded by the check pass, the only one to see them. Both passes are not
+/// atomic: concurrent requests may be counted in between, and a rule may then reject the request
+/// after the previous ones counted it.
+fn passes(peek: bool, matched_rules: usize) -> &'static [Pass] {
+    const PEEK: Pass = Pass {
+        consume: false,
+        record_penalties: false,
+    };
+    const CHECK: Pass = Pass {
+        consume: false,
+        record_penalties: true,
+    };
+    const COUNT: Pass = Pass {
+        consume: true,
+        record_penalties: false,
+    };
+    const CHECK_AND_COUNT: Pass = Pass {
+        consume: true,
+        record_penalties: true,
+    };
+    match (peek, matched_rules > 1) {
+        (true, _) => &[PEEK],
+        (false, true) => &[CHECK, COUNT],
+        (false, false) => &[CHECK_AND_COUNT],
+    }
+}
+
+/// Rate limits the request according to one of the rules matching it, and renders its rejections.
+/// The request is only checked, not counted, unless `consume` is set. When peeking, the remaining
+/// requests are answered whatever the decision.
+#[allow(clippy::too_many_arguments)]
+async fn apply_rule(
+    states: &States,
+    request: &Request<hyper::body::Incoming>,
+    path: &str,
+    associated_key: &str,
+    path_params: &HashMap<String, String>,
+    client_ip: Option<IpAddr>,
+    consume: bool,
+    record_penalties: bool,
+    peek: bool,
+    metrics_properties: &mut Vec<KeyValue>,
+) -> Result<Response<Full<Bytes>>, LimiterError> {
+    let loaded_rule = states.rule(associated_key)?;
+    let limiter_rule = &loaded_rule.rule;
+
+    *metrics_properties = limiter_rule.clone().into();
+    if limiter_rule.route == DEFAULT_ROUTE {
+        metrics_properties.push(KeyValue::new("unmatched", "default"));
+    }
+
+    let decision = limit_with_rule(
+        states,
+        request,
+        path,
+        &loaded_rule,
+        path_params,
+        client_ip,
+        consume,
+        record_penalties,
+        metrics_properties,
+    )
+    .await;
+
+    // Callers asking for JSON get the decision document, whether the request is allowed or not.
+    let json = accepts_json(request);
+
+    if peek {
+        metrics_properties.push(KeyValue::new("peek", true));
+        return peek_response(decision, limiter_rule, json);
+    }
+
+    match decision {
+        Ok(Allowed {
+            headers,
+            tracked_key,
+        }) if json => {
+            let document = DecisionDocument::new(
+                "allowed",
+                limiter_rule,
+                headers.as_ref(),
+                tracked_key.as_deref(),
+            );
+            let builder = match &headers {
+                Some(headers) => with_rate_limit_headers(Response::builder(), headers),
+                None => Response::builder(),
+            };
+            builder
+                .header(CONTENT_TYPE, "application/json")
+                .body(Full::new(Bytes::from(json_document(&document)?)))
+                .map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response")))
+        }
+        Ok(Allowed {
+            headers: Some(headers),
+            ..
+        }) => with_rate_limit_headers(Response::builder(), &headers)
+            .body(Full::new(Bytes::from("Rate limit not exceeded")))
+            .map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response"))),
+        // The request was let through without being counted.
+        Ok(_) => allowed_response(),
+        Err(LimiterError::RateLimitExceeded {
+            headers,
+            key,
+            level,
+            ..
+        }) if json => Err(LimiterError::CustomRejection {
+            response: Box::new(json_rejection(
+                DecisionDocument::new("rejected", limiter_rule, Some(&headers), Some(&key)),
+                with_rate_limit_headers(Response::builder(), &headers),
+            )?),
+            level,
+            banned: false,
+        }),
+        Err(LimiterError::Banned {
+            key, retry_after, ..
+        }) if json => {
+            let headers = ban_headers(limiter_rule, retry_after);
+            Err(LimiterError::CustomRejection {
+                response: Box::new(json_rejection(
+                    DecisionDocument::new("banned", limiter_rule, Some(&headers), Some(&key)),
+                    Response::builder().header(RETRY_AFTER, retry_after),
+                )?),
+                level: LimitLevel::Key,
+                banned: true,
+            })
+        }
+        // Rejections and bans are answered with the response of the rule, if it has one.
+        Err(LimiterError::Banned {
+            key,
+            route,
+            retry_after,
+        }) if limiter_rule.rejection.is_some() => {
+            let headers = ban_headers(limiter_rule, retry_after);
+            Err(LimiterError::CustomRejection {
+                response: Box::new(
+                    limiter_rule
+                        .rejection
+                        .as_ref()
+                        .unwrap()
+                        .to_ban_response(&headers, &route, &key),
+                ),
+                level: LimitLevel::Key,
+                banned: true,
+            })
+        }
+        Err(LimiterError::RateLimitExceeded {
+            headers,
+            key,
+            route,
+            level,
+            ..
+        }) if limiter_rule.rejection.is_some() => Err(LimiterError::CustomRejection {
+            response: Box::new(
+                limiter_rule
+                    .rejection
+                    .as_ref()
+                    .unwrap()
+                    .to_response(&headers, &route, &key),
+            ),
+            level,
+            banned: false,
+        }),
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether the caller asked for JSON documents rather than plain text.
+fn accepts_json(request: &Request<hyper::body::Incoming>) -> bool {
+    request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
+/// Headers of a banned key: the limit of the rule, nothing remaining until the ban ends.
+pub fn ban_headers(rule: &Rule, retry_after: u64) -> RateLimiterHeaders {
+    RateLimiterHeaders::new(
+        rule.limit as u64,
+        0,
+        retry_after,
+        rule.algorithm.to_string(),
+    )
+}
+
+/// Remaining requests of the caller, answered with a `200` even when the next request would be rejected.
+fn peek_response(
+    decision: Result<Allowed, LimiterError>,
+    rule: &Rule,
+    json: bool,
+) -> Result<Response<Full<Bytes>>, LimiterError> {
+    let (headers, tracked_key) = match decision {
+        Ok(Allowed {
+            headers,
+            tracked_key,
+        }) => (headers, tracked_key),
+        Err(LimiterError::RateLimitExceeded { headers, key, .. }) => (Some(*headers), Some(key)),
+        Err(LimiterError::Banned {
+            key, retry_after, ..
+        }) => (Some(ban_headers(rule, retry_after)), Some(key)),
+        Err(err) => return Err(err),
+    };
+
+    let builder = match &headers {
+        Some(headers) => with_rate_limit_headers(Response::builder(), headers),
+        None => Response::builder(),
+    };
+    let response = match json {
+        true => builder
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(json_document(
+                &DecisionDocument::new("peek", rule, headers.as_ref(), tracked_key.as_deref()),
+            )?))),
+        false => builder.body(Full::new(Bytes::from("Rate limit not consumed"))),
+    };
+    response.map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response")))
+}
+
+/// Outcome of a rule letting a request through.
+#[derive(Default)]
+struct Allowed {
+    headers: Option<RateLimiterHeaders>, // None when the request was not counted
+    tracked_key: Option<String>,
+}
+
+/// Decision returned to the callers accepting JSON.
+#[derive(Serialize)]
+struct DecisionDocument<'a> {
+    decision: &'static str, // allowed, rejected, banned or peek
+    rule_id: &'a str,
+    route: &'a str, // Route of the rule, not the path of the request
+    algorithm: String,
+    limit: Option<u64>,
+    remaining: Option<u64>,
+    reset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delay: Option<u32>, // Milliseconds to wait before sending the request, when shaped
+    key_hash: Option<String>, // SHA-256 of the tracked key, which may be personal data
+}
+
+impl<'a> DecisionDocument<'a> {
+    fn new(
+        decision: &'static str,
+        rule: &'a Rule,
+        headers: Option<&RateLimiterHeaders>,
+        tracked_key: Option<&str>,
+    ) -> Self {
+        DecisionDocument {
+            decision,
+            rule_id: &rule.id,
+            route: &rule.route,
+            algorithm: rule.algorithm.to_string(),
+            limit: headers.map(|headers| headers.limit),
+            remaining: headers.map(|headers| headers.remaining),
+            reset: headers.map(|headers| headers.reset),
+            delay: headers.and_then(|headers| headers.delay),
+            key_hash: tracked_key.map(checksum),
         }
+    }
+}
+
+fn json_document(document: &DecisionDocument) -> Result<String, LimiterError> {
+    serde_json::to_string(document)
+        .map_err(|err| LimiterError::Unknown(anyhow!("Unable to serialize decision: {err}")))
+}
+
+fn with_rate_limit_headers(builder: Builder, headers: &RateLimiterHeaders) -> Builder {
+    let builder = builder
+        .header("limit", headers.limit)
+        .header("remaining", headers.remaining)
+        .header("reset", headers.reset)
+        .header("policy", headers.policy.clone());
+    match headers.delay {
+        Some(delay) => builder.header("delay", delay),
+        None => builder,
+    }
+}
+
+fn json_rejection(
+    document: DecisionDocument,
+    builder: Builder,
+) -> Result<Response<Full<Bytes>>, LimiterError> {
+    builder
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(json_document(&document)?)))
+        .map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response")))
+}
+
+/// Runs the checks of the rule, from its IP lists to its algorithm.
+/// Nothing is counted, nor held, unless `consume` is set, and rejections only count towards
+/// a ban when `record_penalties` is.
+#[allow(clippy::too_many_arguments)]
+async fn limit_with_rule(
+    states: &States,
+    request: &Request<hyper::body::Incoming>,
+    path: &str,
+    loaded_rule: &LoadedRule,
+    path_params: &HashMap<String, String>,
+    client_ip: Option<IpAddr>,
+    consume: bool,
+    record_penalties: bool,
+    metrics_properties: &mut Vec<KeyValue>,
+) -> Result<Allowed, LimiterError> {
+    let limiter_rule = &loaded_rule.rule;
+
+    // In case the rule is disabled (active=false)
+    if let Some(v) = &limiter_rule.active
+        && !(*v)
+    {
+        return Ok(Allowed::default());
+    }
+
+    // Deny lists reject the request, allow lists exempt it.
+    if let Some(ip) = client_ip {
+        if loaded_rule.ip_filter.denies(ip) {
+            return Err(LimiterError::IpDenied(ip.to_string()));
+        }
+        if loaded_rule.ip_filter.allows(ip) {
+            metrics_properties.push(KeyValue::new("ip_filter", "allowed"));
+            return Ok(Allowed::default());
+        }
+    }
+
+    let request_parts = RequestParts {
+        headers: request.headers(),
+        query: request.uri().query(),
+        path_params,
+        jwt_verifier: &states.jwt_verifier,
+        client_ip,
+    };
+    // In case a component of a composite key is missing and the rule lets such requests through
+    let Some(tracking_keys) = get_tracked_keys(&request_parts, limiter_rule)? else {
+        return Ok(Allowed::default());
     };
+    let tracked_key = combine_tracked_keys(&tracking_keys);
+
+    // Banned keys are rejected before any other lookup, without running the algorithm.
+    if limiter_rule.penalty.is_some()
+        && let Some(retry_after) =
+            get_ban(&mut states.pool.clone(), &limiter_rule.id, &tracked_key).await?
+    {
+        return Err(LimiterError::Banned {
+            key: tracked_key,
+            route: path.to_string(),
+            retry_after,
+        });
+    }
+
+    // The limit of the client tier, if the rule has tiers, replaces the rule one.
+    let mut limit = limiter_rule.limit as u64;
+    let mut expiration = limiter_rule.expiration as u64;
+    if let Some((tier, tier_limit)) = resolve_tier(
+        &mut states.pool.clone(),
+        &states.tier_cache,
+        &request_parts,
+        limiter_rule,
+        &tracked_key,
+    )
+    .await?
+    {
+        limit = tier_limit.limit as u64;
+        expiration = tier_limit.expiration.unwrap_or(limiter_rule.expiration) as u64;
+        metrics_properties.push(KeyValue::new("tier", tier));
+    }
+
+    // During a scheduled range, its limits apply instead, unless the rule is closed.
+    let schedules = limiter_rule.schedules.as_deref().unwrap_or_default();
+    if let Some((schedule, until_end)) = active_schedule(schedules, Utc::now()) {
+        if schedule.closed {
+            metrics_properties.push(KeyValue::new("schedule", "closed"));
+            return Err(LimiterError::RateLimitExceeded {
+                headers: Box::new(RateLimiterHeaders::new(
+                    0,
+                    0,
+                    until_end,
+                    limiter_rule.algorithm.to_string(),
+                )),
+                key: tracked_key,
+                msg: "Route closed".to_string(),
+                route: path.to_string(),
+                level: LimitLevel::Key,
+            });
+        }
+        (limit, expiration) = schedule.apply(limit, expiration, limiter_rule.limit);
+        metrics_properties.push(KeyValue::new("schedule", "active"));
+    }
+
+    // Adaptive rules are scaled down while their backend is unhealthy.
+    if let Some(adaptive) = &limiter_rule.adaptive {
+        let multiplier = get_multiplier(&mut states.pool.clone(), &limiter_rule.id).await?;
+        let rule_properties: Vec<KeyValue> = limiter_rule.clone().into();
+        states
+            .rl_adaptive_multiplier
+            .record(multiplier, &rule_properties);
+        limit = adaptive.scale(limit, multiplier);
+    }
+
+    // The override of this very key, if any, has the last word.
+    if let Some(key_override) = states.key_override(&limiter_rule.id, &tracked_key) {
+        let Some((override_limit, override_expiration)) = key_override.apply(limit, expiration)
+        else {
+            metrics_properties.push(KeyValue::new("override", "unlimited"));
+            return Ok(Allowed {
+                headers: None,
+                tracked_key: Some(tracked_key),
+            });
+        };
+        (limit, expiration) = (override_limit, override_expiration);
+        metrics_properties.push(KeyValue::new("override", "limit"));
+    }
+
+    let mut headers = match execute_rate_limiting(
+        states.pool.clone(),
+        &tracking_keys,
+        limiter_rule,
+        limit,
+        expiration,
+        path,
+        consume,
+    )
+    .await
+    {
+        Ok(headers) => headers,
+        Err(err) => {
+            let Some(penalty) = recorded_penalty(limiter_rule, &err, record_penalties) else {
+                return Err(err);
+            };
+            let ban = record_rejection(
+                &mut states.pool.clone(),
+                &limiter_rule.id,
+                &tracked_key,
+                penalty,
+            )
+            .await?;
+            return match ban {
+                Some(retry_after) => {
+                    tracing::info!("{tracked_key} banned from {path} for {retry_after}s.");
+                    Err(LimiterError::Banned {
+                        key: tracked_key,
+                        route: path.to_string(),
+                        retry_after,
+                    })
+                }
+                None => Err(err),
+            };
+        }
+    };
+
+    if let Some(shaped) = shape(limiter_rule.shaping.as_ref(), &mut headers, !consume).await {
+        metrics_properties.push(KeyValue::new("shaped", shaped));
+    }
+
+    Ok(Allowed {
+        headers: Some(headers),
+        tracked_key: Some(tracked_key),
+    })
+}
+
+/// The penalty the rejection counts towards, if any. Only the rejections of the key itself count
+/// towards a ban, not those of the aggregates.
+fn recorded_penalty<'a>(
+    rule: &'a Rule,
+    err: &LimiterError,
+    record_penalties: bool,
+) -> Option<&'a Penalty> {
+    let key_rejection = matches!(
+        err,
+        LimiterError::RateLimitExceeded {
+            level: LimitLevel::Key,
+            ..
+        }
+    );
+    rule.penalty
+        .as_ref()
+        .filter(|_| record_penalties && key_rejection)
+}
+
+/// Shaped requests are delayed rather than rejected, the limiter waits itself when holding them.
+/// Returns how the request was shaped, if it was.
+async fn shape(
+    shaping: Option<&Shaping>,
+    headers: &mut RateLimiterHeaders,
+    peek: bool,
+) -> Option<&'static str> {
+    let delay = headers.delay.filter(|delay| *delay > 0)?;
+    match shaping {
+        Some(shaping) if shaping.hold && !peek => {
+            tokio::time::sleep(Duration::from_millis(delay as u64)).await;
+            headers.delay = Some(0);
+            Some("held")
+        }
+        _ => Some("delayed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limiter::{LimiterTrackingType, RateLimiterAlgorithms};
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn banned_documents_report_the_rule_limit() {
+        let rule = Rule::new(
+            "/api".to_string(),
+            RateLimiterAlgorithms::FixedWindow,
+            100,
+            60,
+            LimiterTrackingType::IP,
+            None,
+            None,
+        );
+        let headers = ban_headers(&rule, 300);
+
+        let response = json_rejection(
+            DecisionDocument::new("banned", &rule, Some(&headers), Some("key")),
+            Response::builder().header(RETRY_AFTER, 300),
+        )
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let document: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(document["decision"], "banned");
+        assert_eq!(document["limit"], 100);
+        assert_eq!(document["remaining"], 0);
+        assert_eq!(document["reset"], 300);
+        assert_eq!(document["key_hash"], checksum("key"));
+    }
+
+    fn key_rejection(level: LimitLevel) -> LimiterError {
+        LimiterError::RateLimitExceeded {
+            headers: Box::new(RateLimiterHeaders::new(10, 0, 60, "fw".to_string())),
+            key: "key".to_string(),
+            msg: "Rate limit exceeded".to_string(),
+            route: "/api".to_string(),
+            level,
+        }
+    }
+
+    #[test]
+    fn rejections_of_several_matched_rules_count_towards_their_penalty() {
+        let plain = Rule::new(
+            "/api/{*rest}".to_string(),
+            RateLimiterAlgorithms::FixedWindow,
+            100,
+            60,
+            LimiterTrackingType::IP,
+            None,
+            None,
+        );
+        let mut penalized = plain.clone();
+        penalized.route = "/api/login".to_string();
+        penalized.penalty = Some(Penalty {
+            rejections: 3,
+            window: 60,
+            ban: 300,
+            exponential: false,
+            max_ban: None,
+        });
+        let rejection = key_rejection(LimitLevel::Key);
+
+        // A rejection ends the request on the pass seeing it, the first one with several rules.
+        let recorded = |passes: &[Pass]| {
+            [&plain, &penalized]
+                .iter()
+                .filter_map(|rule| recorded_penalty(rule, &rejection, passes[0].record_penalties))
+                .count()
+        };
+        assert!(!passes(false, 2)[0].consume);
+        assert_eq!(recorded(passes(false, 2)), 1);
+        assert_eq!(recorded(passes(false, 1)), 1);
+        assert_eq!(recorded(passes(true, 2)), 0);
+        // The counting pass does not record them twice.
+        assert!(!passes(false, 2)[1].record_penalties);
+        // Nor do the rejections of the aggregates.
+        let route_rejection = key_rejection(LimitLevel::Route);
+        assert!(recorded_penalty(&penalized, &route_rejection, true).is_none());
+    }
+
+    #[test]
+    fn peeked_paths_follow_the_peek_endpoint() {
+        assert_eq!(peeked_path("/peek/api/v1/orders"), Some("/api/v1/orders"));
+        assert_eq!(peeked_path("/peek"), Some("/"));
+        assert_eq!(peeked_path("/peeking/api"), None);
+        assert_eq!(peeked_path("/api/peek"), None);
+    }
+
+    fn shaped_headers(delay: u32) -> RateLimiterHeaders {
+        let mut headers = RateLimiterHeaders::new(10, 3, 60, "lb".to_string());
+        headers.delay = Some(delay);
+        headers
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_requests_are_answered_after_their_delay() {
+        let shaping = Shaping {
+            max_wait: Some(5000),
+            hold: true,
+        };
+        let mut headers = shaped_headers(1500);
+        let start = tokio::time::Instant::now();
+
+        assert_eq!(
+            shape(Some(&shaping), &mut headers, false).await,
+            Some("held")
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+        let response = with_rate_limit_headers(Response::builder(), &headers)
+            .body(())
+            .unwrap();
+        assert_eq!(response.headers()["delay"], "0");
+
+        // Peeking never waits, it reports the delay of the next request.
+        let mut headers = shaped_headers(1500);
+        assert_eq!(
+            shape(Some(&shaping), &mut headers, true).await,
+            Some("delayed")
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+        assert_eq!(headers.delay, Some(1500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delayed_requests_are_answered_at_once_with_their_delay() {
+        let shaping = Shaping {
+            max_wait: None,
+            hold: false,
+        };
+        let start = tokio::time::Instant::now();
+
+        let mut headers = shaped_headers(1500);
+        assert_eq!(
+            shape(Some(&shaping), &mut headers, false).await,
+            Some("delayed")
+        );
+        let response = with_rate_limit_headers(Response::builder(), &headers)
+            .body(())
+            .unwrap();
+        assert_eq!(response.headers()["delay"], "1500");
+
+        let mut headers = shaped_headers(0);
+        assert_eq!(shape(Some(&shaping), &mut headers, false).await, None);
+        let mut headers = RateLimiterHeaders::new(10, 3, 60, "fw".to_string());
+        assert_eq!(shape(None, &mut headers, false).await, None);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
 }
@@ -0,0 +1,272 @@
+use anyhow::Context;
+use lazy_static::lazy_static;
+use redis::{Connection, Script};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+
+use crate::{configurations_loader::connect_to_redis, rules::get_rules_documents};
+
+const RULES_VERSION_KEY: &str = "rules_version";
+const RULES_HISTORY_KEY: &str = "rules_history";
+const DEFAULT_HISTORY_SIZE: u64 = 10;
+
+/// Lua snippet recording the current `rules` document as a new immutable version.
+/// Expects ARGV[1] = author, ARGV[2] = source checksum, ARGV[3] = number of versions to keep.
+/// Evaluates to the new version number.
+pub const RECORD_VERSION_SCRIPT: &str = r"
+        local version = redis.call('INCR', 'rules_version')
+        local history_key = 'rules_history:' .. version
+        local metadata = {
+            version = version,
+            timestamp = tonumber(redis.call('TIME')[1]),
+            author = ARGV[1],
+            checksum = ARGV[2],
+        }
+        redis.call('JSON.SET', history_key, '$', cjson.encode(metadata))
+        redis.call('JSON.SET', history_key, '$.rules', redis.call('JSON.GET', 'rules'))
+
+        local keep = tonumber(ARGV[3])
+        redis.call('LPUSH', 'rules_history', version)
+        local expired = redis.call('LRANGE', 'rules_history', keep, -1)
+        for _, expired_version in ipairs(expired) do
+            redis.call('DEL', 'rules_history:' .. expired_version)
+        end
+        redis.call('LTRIM', 'rules_history', 0, keep - 1)
+";
+
+lazy_static! {
+    // ARGV[4] is the version to restore, ARGV[5] the version the rollback was prepared against,
+    // then the ids of the rules it removes, whose overrides are dropped with them.
+    static ref ROLLBACK_SOURCE: String = format!(
+        r"
+        local current_version = tonumber(redis.call('GET', 'rules_version') or '0')
+        if current_version ~= tonumber(ARGV[5]) then
+            return redis.error_reply('Rules were modified concurrently (expected version ' .. ARGV[5] .. ', found ' .. current_version .. '). Re-run the rollback to review the new changes.')
+        end
+        local target = redis.call('JSON.GET', 'rules_history:' .. ARGV[4], '.rules')
+        if not target then
+            return redis.error_reply('Version ' .. ARGV[4] .. ' not found in history')
+        end
+        redis.call('JSON.SET', 'rules', '$', target)
+        for i = 6, #ARGV do
+            redis.call('DEL', 'overrides:' .. ARGV[i])
+        end
+        {RECORD_VERSION_SCRIPT}
+        redis.call('PUBLISH', 'rl_update', 'update')
+        return version
+        "
+    );
+    static ref ROLLBACK_SCRIPT: Script = Script::new(&ROLLBACK_SOURCE);
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RuleSetVersion {
+    pub version: u64,
+    pub timestamp: u64,
+    pub author: String,
+    pub checksum: String,
+    #[serde(default)]
+    pub rules: HashMap<String, Value>,
+}
+
+/// Number of versions kept in the history, configured with `RL_RULES_HISTORY_SIZE`.
+pub fn history_size() -> u64 {
+    std::env::var("RL_RULES_HISTORY_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_HISTORY_SIZE)
+}
+
+/// Author recorded with a new version when none is given on the command line.
+pub fn default_author() -> String {
+    std::env::var("USER").unwrap_or("unknown".to_string())
+}
+
+pub fn checksum(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Current version of the rules, 0 when nothing was ever loaded.
+pub fn get_rules_version(connection: &mut Connection) -> anyhow::Result<u64> {
+    let version: Option<u64> = redis::cmd("GET").arg(RULES_VERSION_KEY).query(connection)?;
+    Ok(version.unwrap_or_default())
+}
+
+pub fn get_rules_history(connection: &mut Connection) -> anyhow::Result<Vec<RuleSetVersion>> {
+    let versions: Vec<u64> = redis::cmd("LRANGE")
+        .arg(RULES_HISTORY_KEY)
+        .arg(0)
+        .arg(-1)
+        .query(connection)?;
+
+    let mut history = vec![];
+    for version in versions {
+        let maybe_document: Option<String> = redis::cmd("JSON.GET")
+            .arg(format!("{RULES_HISTORY_KEY}:{version}"))
+            .query(connection)?;
+        let Some(document) = maybe_document else {
+            tracing::warn!("Version {version} is listed in history but has no document.");
+            continue;
+        };
+
+        history.push(
+            serde_json::from_str(&document)
+                .with_context(|| format!("Invalid document for version {version}"))?,
+        );
+    }
+
+    Ok(history)
+}
+
+pub async fn show_history() -> anyhow::Result<()> {
+    let mut con = connect_to_redis()?;
+    let history = get_rules_history(&mut con)?;
+
+    if history.is_empty() {
+        println!("No version recorded yet.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<10} {:<12} {:<20} {:<8} CHECKSUM",
+        "VERSION", "TIMESTAMP", "AUTHOR", "RULES"
+    );
+    for (index, version) in history.iter().enumerate() {
+        let current = if index == 0 { " (current)" } else { "" };
+        println!(
+            "{:<10} {:<12} {:<20} {:<8} {}{}",
+            version.version,
+            version.timestamp,
+            version.author,
+            version.rules.len(),
+            version.checksum,
+            current
+        );
+    }
+
+    Ok(())
+}
+
+/// Arguments of `ROLLBACK_SCRIPT` restoring `target` over `live_rules`, read at `base_version`.
+fn rollback_args(
+    author: String,
+    target: &RuleSetVersion,
+    base_version: u64,
+    live_rules: &HashMap<String, Value>,
+) -> Vec<String> {
+    let mut args = vec![
+        author,
+        target.checksum.clone(),
+        history_size().to_string(),
+        target.version.to_string(),
+        base_version.to_string(),
+    ];
+    args.extend(
+        live_rules
+            .keys()
+            .filter(|id| !target.rules.contains_key(*id))
+            .cloned(),
+    );
+    args
+}
+
+pub async fn rollback_configuration(version: u64, author: Option<String>) -> anyhow::Result<()> {
+    let mut con = connect_to_redis()?;
+    // Read before the rules themselves: a load happening in between makes the rollback fail
+    // instead of being overwritten.
+    let base_version = get_rules_version(&mut con)?;
+    let live_rules = get_rules_documents(&mut con).map_err(anyhow::Error::from_boxed)?;
+    let history = get_rules_history(&mut con)?;
+    let target = history
+        .iter()
+        .find(|v| v.version == version)
+        .with_context(|| format!("Version {version} not found in history."))?;
+
+    tracing::info!(
+        "Rolling back to version {} ({} rules) by {}...",
+        target.version,
+        target.rules.len(),
+        target.author
+    );
+    let args = rollback_args(
+        author.unwrap_or_else(default_author),
+        target,
+        base_version,
+        &live_rules,
+    );
+    let new_version: u64 = ROLLBACK_SCRIPT.arg(args).invoke(&mut con)?;
+
+    tracing::info!("Rules rolled back to version {version}, recorded as version {new_version}.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm_tests::FakeRedis;
+    use serde_json::json;
+
+    /// Stores `rules` as a new version, the way a load does.
+    fn record(redis: &FakeRedis, rules: &Value) -> i64 {
+        let source = format!(
+            "redis.call('JSON.SET', 'rules', '$', ARGV[4])
+            {RECORD_VERSION_SCRIPT}
+            return version"
+        );
+        let args = ["author", "checksum", "10", &rules.to_string()].map(String::from);
+        redis.eval(&source, &[], &args)[0]
+    }
+
+    fn version(redis: &FakeRedis, version: u64) -> RuleSetVersion {
+        serde_json::from_value(redis.json(&format!("rules_history:{version}")).unwrap()).unwrap()
+    }
+
+    fn live_rules(redis: &FakeRedis) -> HashMap<String, Value> {
+        serde_json::from_value(redis.json("rules").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rollbacks_restore_the_rules_and_drop_the_overrides_of_removed_ones() {
+        let redis = FakeRedis::new();
+        record(&redis, &json!({"a": {"id": "a"}}));
+        record(&redis, &json!({"a": {"id": "a"}, "b": {"id": "b"}}));
+        let set_overrides =
+            "redis.call('SET', 'overrides:a', '1') redis.call('SET', 'overrides:b', '1') return 0";
+        redis.eval(set_overrides, &[], &[]);
+
+        let args = rollback_args(
+            "author".to_string(),
+            &version(&redis, 1),
+            2,
+            &live_rules(&redis),
+        );
+        assert_eq!(redis.eval(&ROLLBACK_SOURCE, &[], &args), vec![3]);
+
+        assert_eq!(live_rules(&redis), version(&redis, 1).rules);
+        let exists =
+            "return {redis.call('EXISTS', 'overrides:a'), redis.call('EXISTS', 'overrides:b')}";
+        assert_eq!(redis.eval(exists, &[], &[]), vec![1, 0]);
+        assert_eq!(redis.list("rules_history"), vec!["3", "2", "1"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Rules were modified concurrently")]
+    fn rollbacks_refuse_to_overwrite_a_concurrent_load() {
+        let redis = FakeRedis::new();
+        record(&redis, &json!({"a": {"id": "a"}}));
+        record(&redis, &json!({"b": {"id": "b"}}));
+        let args = rollback_args(
+            "author".to_string(),
+            &version(&redis, 1),
+            2,
+            &live_rules(&redis),
+        );
+
+        record(&redis, &json!({"c": {"id": "c"}}));
+        redis.eval(&ROLLBACK_SOURCE, &[], &args);
+    }
+}
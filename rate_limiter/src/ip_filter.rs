@@ -0,0 +1,203 @@
+use anyhow::Context;
+use hyper::HeaderMap;
+use ipnet::IpNet;
+
+use std::net::IpAddr;
+
+/// Allow and deny lists of IP ranges.
+/// Denied addresses are rejected, allowed ones bypass rate limiting. Deny wins over allow.
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    allowed: Vec<IpNet>,
+    denied: Vec<IpNet>,
+}
+
+fn parse_cidrs(cidrs: &[String]) -> anyhow::Result<Vec<IpNet>> {
+    cidrs
+        .iter()
+        .map(|cidr| {
+            let cidr = cidr.trim();
+            // A single address is accepted as the range containing only itself.
+            cidr.parse::<IpNet>()
+                .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("Invalid CIDR {cidr}"))
+        })
+        .collect()
+}
+
+fn cidrs_from_env(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .filter(|cidr| !cidr.trim().is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl IpFilter {
+    pub fn new(allowed: &[String], denied: &[String]) -> anyhow::Result<Self> {
+        Ok(IpFilter {
+            allowed: parse_cidrs(allowed)?,
+            denied: parse_cidrs(denied)?,
+        })
+    }
+
+    /// Global lists, configured with the comma separated `RL_ALLOWED_CIDRS` and `RL_DENIED_CIDRS`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let filter = IpFilter::new(
+            &cidrs_from_env("RL_ALLOWED_CIDRS"),
+            &cidrs_from_env("RL_DENIED_CIDRS"),
+        )?;
+        tracing::info!(
+            "Global IP filter configured with {} allowed and {} denied range(s).",
+            filter.allowed.len(),
+            filter.denied.len()
+        );
+        Ok(filter)
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.allowed.iter().any(|range| range.contains(&ip))
+    }
+
+    pub fn denies(&self, ip: IpAddr) -> bool {
+        self.denied.iter().any(|range| range.contains(&ip))
+    }
+}
+
+/// Proxies in front of the limiter, each appending the address of its peer to `X-Forwarded-For`.
+/// Configured with `RL_TRUSTED_PROXY_DEPTH`, 1 by default for the bundled nginx.
+#[derive(Clone, Copy, Debug)]
+pub struct TrustedProxies {
+    depth: usize,
+}
+
+impl Default for TrustedProxies {
+    fn default() -> Self {
+        TrustedProxies { depth: 1 }
+    }
+}
+
+/// Address of a hop: `client`, `"for=client;proto=http"`, `v4:port` or `[v6]:port`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.split(';').next()?.trim();
+    let address = hop.strip_prefix("for=").unwrap_or(hop).trim_matches('"');
+
+    address.parse().ok().or_else(|| {
+        address
+            .strip_prefix('[')
+            .and_then(|v6| v6.split(']').next())
+            .or_else(|| address.rsplit_once(':').map(|(host, _)| host))
+            .and_then(|host| host.parse().ok())
+    })
+}
+
+impl TrustedProxies {
+    pub fn new(depth: usize) -> anyhow::Result<Self> {
+        if depth == 0 {
+            anyhow::bail!("At least one trusted proxy is required to find the client address.");
+        }
+        Ok(TrustedProxies { depth })
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let proxies = match std::env::var("RL_TRUSTED_PROXY_DEPTH") {
+            Ok(depth) => TrustedProxies::new(
+                depth
+                    .parse()
+                    .with_context(|| format!("Invalid RL_TRUSTED_PROXY_DEPTH {depth}"))?,
+            )?,
+            Err(_) => TrustedProxies::default(),
+        };
+        tracing::info!(
+            "Client addresses are read behind {} proxies.",
+            proxies.depth
+        );
+        Ok(proxies)
+    }
+
+    /// Address of the client, as seen by the outermost trusted proxy.
+    /// Clients can prepend anything to `X-Forwarded-For` and `Forwarded`, only the hops appended by
+    /// the trusted proxies are read: the one `depth` hops from the end, or the first one when
+    /// fewer proxies were crossed. `X-Real-IP`, set by the proxy itself, is used otherwise.
+    pub fn client_ip(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        for header in ["x-forwarded-for", "forwarded"] {
+            let hops: Vec<&str> = headers
+                .get_all(header)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .collect();
+            if !hops.is_empty() {
+                return parse_hop(hops[hops.len().saturating_sub(self.depth)]);
+            }
+        }
+
+        headers
+            .get("x-real-ip")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_hop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn spoofed_forwarded_hops_are_ignored() {
+        let proxies = TrustedProxies::default();
+
+        // nginx appends the address of its peer to whatever the client sent.
+        let spoofed = headers(&[("x-forwarded-for", "10.0.0.1, 203.0.113.7")]);
+        assert_eq!(proxies.client_ip(&spoofed), ip("203.0.113.7"));
+        let rotated = headers(&[
+            ("x-forwarded-for", "192.0.2.99"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ]);
+        assert_eq!(proxies.client_ip(&rotated), ip("203.0.113.7"));
+        let forwarded = headers(&[(
+            "forwarded",
+            "for=10.0.0.1, for=\"[2001:db8::1]:4711\";proto=https",
+        )]);
+        assert_eq!(proxies.client_ip(&forwarded), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn hops_are_read_behind_every_trusted_proxy() {
+        let proxies = TrustedProxies::new(2).unwrap();
+
+        let behind_two = headers(&[("x-forwarded-for", "10.0.0.1, 203.0.113.7, 172.16.0.2")]);
+        assert_eq!(proxies.client_ip(&behind_two), ip("203.0.113.7"));
+        let behind_one = headers(&[("x-forwarded-for", "203.0.113.7:5000")]);
+        assert_eq!(proxies.client_ip(&behind_one), ip("203.0.113.7"));
+        assert!(TrustedProxies::new(0).is_err());
+    }
+
+    #[test]
+    fn real_ip_is_used_without_forwarded_hops() {
+        let proxies = TrustedProxies::default();
+
+        assert_eq!(
+            proxies.client_ip(&headers(&[("x-real-ip", "203.0.113.7")])),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            proxies.client_ip(&headers(&[("x-forwarded-for", "not-an-ip")])),
+            None
+        );
+        assert_eq!(proxies.client_ip(&HeaderMap::new()), None);
+    }
+}
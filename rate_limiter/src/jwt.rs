@@ -0,0 +1,94 @@
+use anyhow::Context;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, jwk::JwkSet};
+use serde_json::Value;
+
+use std::collections::HashMap;
+
+/// Verifies the bearer tokens used to track requests by one of their claims.
+///
+/// Keys are configured through the environment:
+/// * `RL_JWT_HS256_SECRETS` - Comma separated secrets accepted for HS256 tokens.
+/// * `RL_JWT_JWKS_FILE` - Path to a local JWKS file, its keys are selected by the token `kid`.
+#[derive(Default)]
+pub struct JwtVerifier {
+    hs256_keys: Vec<DecodingKey>,
+    jwks_keys: HashMap<String, DecodingKey>,
+}
+
+impl JwtVerifier {
+    pub fn new(hs256_secrets: &[String], jwks: Option<JwkSet>) -> anyhow::Result<Self> {
+        let hs256_keys = hs256_secrets
+            .iter()
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()))
+            .collect();
+
+        let mut jwks_keys = HashMap::new();
+        for jwk in jwks.map(|jwks| jwks.keys).unwrap_or_default() {
+            let kid = jwk
+                .common
+                .key_id
+                .clone()
+                .context("Every key of the JWKS file must have a kid")?;
+            let key = DecodingKey::from_jwk(&jwk)
+                .with_context(|| format!("Unsupported key {kid} in JWKS file"))?;
+            jwks_keys.insert(kid, key);
+        }
+
+        Ok(JwtVerifier {
+            hs256_keys,
+            jwks_keys,
+        })
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let hs256_secrets: Vec<String> = std::env::var("RL_JWT_HS256_SECRETS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|secret| !secret.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let jwks = match std::env::var("RL_JWT_JWKS_FILE") {
+            Ok(path) => {
+                let content = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read JWKS file: {path}"))?;
+                Some(serde_json::from_str(&content).context("Invalid JWKS file")?)
+            }
+            Err(_) => None,
+        };
+
+        let verifier = JwtVerifier::new(&hs256_secrets, jwks)?;
+        tracing::info!(
+            "JWT verification configured with {} HS256 secret(s) and {} JWKS key(s).",
+            verifier.hs256_keys.len(),
+            verifier.jwks_keys.len()
+        );
+        Ok(verifier)
+    }
+
+    /// Verifies the token and returns its claims.
+    pub fn verify(&self, token: &str) -> Result<HashMap<String, Value>, String> {
+        let header = decode_header(token).map_err(|err| err.to_string())?;
+        let mut validation = Validation::new(header.alg);
+        validation.validate_aud = false;
+
+        // HS256 tokens are only checked against the secrets, the others against the JWKS keys
+        // so that a public key can never be used as an HMAC secret.
+        let candidates: Vec<&DecodingKey> = match (header.alg, &header.kid) {
+            (Algorithm::HS256, _) => self.hs256_keys.iter().collect(),
+            (_, Some(kid)) => self.jwks_keys.get(kid).into_iter().collect(),
+            (_, None) => vec![],
+        };
+
+        let mut last_error = format!("No key available for {:?} tokens", header.alg);
+        for key in candidates {
+            match decode::<HashMap<String, Value>>(token, key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(err) => last_error = err.to_string(),
+            }
+        }
+
+        Err(last_error)
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::Context;
+use redis::{Commands, Connection};
+
+use std::collections::HashMap;
+
+use crate::{
+    configurations_loader::connect_to_redis,
+    overrides::{KeyOverride, overrides_key},
+    penalty::penalty_keys,
+    rate_limiter::RateLimiterAlgorithms,
+    rules::{Rule, get_rules_documents, get_rules_route_and_id},
+    utils::make_redis_key,
+};
+
+/// State of a tracked key, as stored by the script of its algorithm.
+#[derive(Debug)]
+enum KeyState {
+    Missing,
+    Counter(f64),                     // Fixed window
+    Buckets(HashMap<String, f64>),    // Sliding window counter, with the last written `window`
+    Log(Vec<u64>),                    // Sliding window log, timestamps of the requests
+    Bucket { count: f64, last: u64 }, // Token and leaky buckets, `last` request timestamp
+}
+
+impl KeyState {
+    /// Human readable state of the key at `now`, given the limits applied to it.
+    fn describe(
+        &self,
+        algorithm: &RateLimiterAlgorithms,
+        limit: u64,
+        expiration: u64,
+        now: u64,
+    ) -> Vec<(&'static str, String)> {
+        let limit = limit as f64;
+        let rate = limit / expiration as f64;
+        match (self, algorithm) {
+            (KeyState::Missing, _) => vec![("remaining", format!("{limit} (no state)"))],
+            (KeyState::Counter(count), _) => vec![
+                ("requests", count.to_string()),
+                ("remaining", (limit - count).max(0.0).to_string()),
+            ],
+            (KeyState::Buckets(buckets), _) => {
+                let window = now / expiration;
+                let last_window = buckets.get("window").map_or(window, |last| *last as u64);
+                // Buckets are only valid for the windows following the last written one.
+                let bucket = |window: u64, valid: bool| match valid {
+                    true => buckets
+                        .get(&(window % 3).to_string())
+                        .copied()
+                        .unwrap_or_default(),
+                    false => 0.0,
+                };
+                let current = bucket(window, window == last_window);
+                let previous = bucket(window + 2, window.saturating_sub(last_window) < 2);
+                let elapsed = (now % expiration) as f64 / expiration as f64;
+                let weight = (1.0 - elapsed) * previous + current;
+                vec![
+                    ("current bucket", current.to_string()),
+                    ("previous bucket", previous.to_string()),
+                    ("weighted requests", format!("{weight:.2}")),
+                    ("remaining", format!("{:.2}", (limit - weight).max(0.0))),
+                ]
+            }
+            (KeyState::Log(timestamps), _) => {
+                let window: Vec<_> = timestamps
+                    .iter()
+                    .filter(|timestamp| **timestamp + expiration > now)
+                    .collect();
+                let mut lines = vec![
+                    ("requests in window", window.len().to_string()),
+                    (
+                        "remaining",
+                        (limit - window.len() as f64).max(0.0).to_string(),
+                    ),
+                ];
+                if let Some(oldest) = window.first() {
+                    lines.push((
+                        "oldest expires in",
+                        format!("{}s", *oldest + expiration - now),
+                    ));
+                }
+                lines
+            }
+            (KeyState::Bucket { count, last }, RateLimiterAlgorithms::TokenBucket) => {
+                let tokens = (count + now.saturating_sub(*last) as f64 * rate).min(limit);
+                vec![
+                    ("stored tokens", count.to_string()),
+                    ("tokens", format!("{tokens:.2}")),
+                ]
+            }
+            (KeyState::Bucket { count, last }, _) => {
+                let level = (count - now.saturating_sub(*last) as f64 * rate).max(0.0);
+                vec![
+                    ("stored level", count.to_string()),
+                    ("level", format!("{level:.2}")),
+                    ("remaining", (limit - level.ceil()).max(0.0).to_string()),
+                ]
+            }
+        }
+    }
+}
+
+fn get_rule(connection: &mut Connection, route: &str) -> anyhow::Result<Rule> {
+    let rule_id = get_rules_route_and_id(connection)
+        .map_err(anyhow::Error::from_boxed)?
+        .remove(route)
+        .with_context(|| format!("No rule found for route {route}"))?;
+    let document = get_rules_documents(connection)
+        .map_err(anyhow::Error::from_boxed)?
+        .remove(&rule_id)
+        .with_context(|| format!("No rule found with id {rule_id}"))?;
+    serde_json::from_str(&document.to_string()).with_context(|| format!("Invalid rule {rule_id}"))
+}
+
+/// Redis keys holding the state of a tracked key, the first one being the main one.
+fn state_keys(rule: &Rule, key: &str) -> Vec<String> {
+    let redis_key = make_redis_key(&[key.to_string()], &rule.id, &rule.algorithm);
+    match rule.algorithm {
+        RateLimiterAlgorithms::SlidingWindowLog => {
+            vec![format!("{redis_key}:ss"), format!("{redis_key}:counter")]
+        }
+        _ => vec![redis_key],
+    }
+}
+
+fn read_state(
+    connection: &mut Connection,
+    algorithm: &RateLimiterAlgorithms,
+    redis_key: &str,
+) -> anyhow::Result<KeyState> {
+    if !connection.exists::<_, bool>(redis_key)? {
+        return Ok(KeyState::Missing);
+    }
+    let state = match algorithm {
+        RateLimiterAlgorithms::FixedWindow => KeyState::Counter(connection.get(redis_key)?),
+        RateLimiterAlgorithms::SlidingWindowCounter => {
+            KeyState::Buckets(connection.hgetall(redis_key)?)
+        }
+        RateLimiterAlgorithms::SlidingWindowLog => {
+            let log: Vec<(String, u64)> = connection.zrange_withscores(redis_key, 0, -1)?;
+            KeyState::Log(log.into_iter().map(|(_, timestamp)| timestamp).collect())
+        }
+        RateLimiterAlgorithms::TokenBucket | RateLimiterAlgorithms::LeakyBucket => {
+            let (count, last): (f64, u64) =
+                connection.hget(redis_key, &["count", "last_rq_timestamp"])?;
+            KeyState::Bucket { count, last }
+        }
+    };
+    Ok(state)
+}
+
+/// Raw content of a redis key, whatever its type.
+fn read_raw(connection: &mut Connection, redis_key: &str) -> anyhow::Result<String> {
+    let key_type: String = redis::cmd("TYPE").arg(redis_key).query(connection)?;
+    let raw = match key_type.as_str() {
+        "string" => connection.get::<_, String>(redis_key)?,
+        "hash" => {
+            let mut fields: Vec<(String, String)> = connection.hgetall(redis_key)?;
+            fields.sort();
+            format!("{fields:?}")
+        }
+        "zset" => format!(
+            "{:?}",
+            connection.zrange_withscores::<_, Vec<(String, u64)>>(redis_key, 0, -1)?
+        ),
+        "none" => "-".to_string(),
+        other => format!("<{other}>"),
+    };
+    Ok(raw)
+}
+
+/// Prints the raw and interpreted state of a tracked key of the rule of `route`.
+pub async fn inspect_key(route: &str, key: &str) -> anyhow::Result<()> {
+    let mut con = connect_to_redis()?;
+    let rule = get_rule(&mut con, route)?;
+    let (now, _): (u64, u64) = redis::cmd("TIME").query(&mut con)?;
+
+    let mut limit = rule.limit as u64;
+    let mut expiration = rule.expiration as u64;
+    let maybe_override: Option<String> = con.hget(overrides_key(&rule.id), key)?;
+    if let Some(key_override) = maybe_override {
+        let key_override: KeyOverride = serde_json::from_str(&key_override)
+            .with_context(|| format!("Invalid override for key {key}"))?;
+        limit = key_override.limit.map_or(limit, |limit| limit as u64);
+        expiration = key_override
+            .expiration
+            .map_or(expiration, |expiration| expiration as u64);
+        println!("override:  {key_override:?}");
+    }
+    println!(
+        "rule:      {} ({} requests per {}s, {})",
+        rule.id, limit, expiration, rule.algorithm
+    );
+
+    let keys = state_keys(&rule, key);
+    for redis_key in &keys {
+        let ttl: i64 = con.ttl(redis_key)?;
+        println!("{redis_key}");
+        println!("  raw:     {}", read_raw(&mut con, redis_key)?);
+        println!("  ttl:     {ttl}");
+    }
+
+    let state = read_state(&mut con, &rule.algorithm, &keys[0])?;
+    for (name, value) in state.describe(&rule.algorithm, limit, expiration, now) {
+        println!("{name:<20} {value}");
+    }
+
+    if rule.penalty.is_some() {
+        let [violations_key, ban_key, _] = penalty_keys(&rule.id, key);
+        let violations: Option<u64> = con.get(violations_key)?;
+        let ban: i64 = con.ttl(ban_key)?;
+        println!("{:<20} {}", "violations", violations.unwrap_or_default());
+        if ban > 0 {
+            println!("{:<20} {ban}s", "banned for");
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the state and the penalties of a tracked key, as if it had never been seen.
+pub async fn reset_key(route: &str, key: &str) -> anyhow::Result<()> {
+    let mut con = connect_to_redis()?;
+    let rule = get_rule(&mut con, route)?;
+
+    let mut keys = state_keys(&rule, key);
+    keys.extend(penalty_keys(&rule.id, key));
+    let deleted: u64 = con.del(&keys)?;
+
+    if deleted == 0 {
+        anyhow::bail!("No state found for key {key} on route {route}");
+    }
+    tracing::info!("State of key {key} on route {route} reset.");
+    Ok(())
+}
+
+/// Lists the tracked keys having a state for the rule of `route`.
+pub async fn list_keys(route: &str) -> anyhow::Result<()> {
+    let mut con = connect_to_redis()?;
+    let rule = get_rule(&mut con, route)?;
+    let prefix = make_redis_key(&[String::new()], &rule.id, &rule.algorithm);
+
+    let mut keys: Vec<String> = con
+        .scan_match::<_, String>(format!("{prefix}*"))?
+        .filter_map(|redis_key| {
+            let key = redis_key.strip_prefix(&prefix)?.to_string();
+            match rule.algorithm {
+                RateLimiterAlgorithms::SlidingWindowLog => {
+                    key.strip_suffix(":ss").map(String::from)
+                }
+                _ => Some(key),
+            }
+        })
+        .collect();
+    keys.sort();
+
+    if keys.is_empty() {
+        println!("No tracked key for route {route}.");
+        return Ok(());
+    }
+    println!("{:<40} TTL", "KEY");
+    for key in keys {
+        let ttl: i64 = con.ttl(&state_keys(&rule, &key)[0])?;
+        println!("{key:<40} {ttl}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_are_interpreted_at_the_time_of_inspection() {
+        let tokens = KeyState::Bucket {
+            count: 2.0,
+            last: 100,
+        };
+        // 10 tokens per 10 seconds, refilled by 5 since the last request.
+        assert_eq!(
+            tokens.describe(&RateLimiterAlgorithms::TokenBucket, 10, 10, 105)[1],
+            ("tokens", "7.00".to_string())
+        );
+        assert_eq!(
+            tokens.describe(&RateLimiterAlgorithms::LeakyBucket, 10, 10, 101)[2],
+            ("remaining", "9".to_string())
+        );
+
+        // At 75s, a quarter into the current bucket (1) of 60s windows.
+        let buckets = KeyState::Buckets(HashMap::from([
+            ("0".to_string(), 8.0),
+            ("1".to_string(), 2.0),
+            ("window".to_string(), 1.0),
+        ]));
+        assert_eq!(
+            buckets.describe(&RateLimiterAlgorithms::SlidingWindowCounter, 10, 60, 75)[2],
+            ("weighted requests", "8.00".to_string())
+        );
+
+        let log = KeyState::Log(vec![10, 50, 65]);
+        assert_eq!(
+            log.describe(&RateLimiterAlgorithms::SlidingWindowLog, 10, 60, 75),
+            vec![
+                ("requests in window", "2".to_string()),
+                ("remaining", "8".to_string()),
+                ("oldest expires in", "35s".to_string()),
+            ]
+        );
+    }
+}
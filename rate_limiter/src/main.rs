@@ -1,7 +1,13 @@
 use std::path::PathBuf;
 use std::time::Duration;
 
-use crate::{configurations_loader::load_configuration, server::run};
+use crate::{
+    configurations_loader::{ExportFormat, export_configuration, load_configuration},
+    history::{rollback_configuration, show_history},
+    keys::{inspect_key, list_keys, reset_key},
+    overrides::{KeyOverride, list_overrides, remove_override, set_override},
+    server::run,
+};
 use clap::{Parser, Subcommand};
 use opentelemetry::global;
 use opentelemetry_appender_tracing::layer;
@@ -15,13 +21,29 @@ use opentelemetry_otlp::WithExportConfig;
 
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod adaptive;
+mod admin;
+#[cfg(test)]
+mod algorithm_tests;
+mod batch;
 mod configurations_loader;
+mod diff;
 mod errors;
 mod handler;
+mod history;
+mod ip_filter;
+mod jwt;
+mod keys;
+mod overrides;
+mod penalty;
 mod rate_limiter;
+mod refund;
+mod rejections;
 mod rules;
+mod schedules;
 mod server;
 mod server_state;
+mod tiers;
 mod utils;
 
 #[derive(Parser, Debug)]
@@ -45,6 +67,102 @@ enum Commands {
         /// Path to the configuration file to be loaded
         #[arg(short, long)]
         file: PathBuf,
+        /// Print the differences with the rules stored in redis without applying them.
+        /// Exits with a non-zero code if any rule would change.
+        #[arg(long)]
+        dry_run: bool,
+        /// Author recorded with the new rules version. Defaults to the current user.
+        #[arg(short, long)]
+        author: Option<String>,
+    },
+    /// Export the rules stored in redis into a configuration file that can be loaded back.
+    Export {
+        /// Format of the exported configuration.
+        #[arg(long, value_enum, default_value_t = ExportFormat::Yaml)]
+        format: ExportFormat,
+        /// Path of the file to write. The configuration is printed when omitted.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// List the rules versions kept in history, most recent first.
+    History,
+    /// Restore a previous rules version and notify the rate limiters.
+    Rollback {
+        /// Version to restore, as listed by the history command.
+        #[arg(long)]
+        to: u64,
+        /// Author recorded with the new rules version. Defaults to the current user.
+        #[arg(short, long)]
+        author: Option<String>,
+    },
+    /// Manage the limits of specific tracked keys of a rule.
+    Overrides {
+        #[command(subcommand)]
+        command: OverridesCommands,
+    },
+    /// Inspect and reset the state of the tracked keys of a rule.
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum OverridesCommands {
+    /// Set the limit of a tracked key, or exempt it from rate limiting.
+    Set {
+        /// Route of the rule, as written in the configuration file.
+        #[arg(short, long)]
+        route: String,
+        /// Tracked key. Components of composite keys are separated by `|`.
+        #[arg(short, long)]
+        key: String,
+        /// Maximum number of requests for this key.
+        #[arg(long, required_unless_present = "unlimited")]
+        limit: Option<i32>,
+        /// Time window in seconds for this key. The rule one is used when omitted.
+        #[arg(long)]
+        expiration: Option<i32>,
+        /// Do not rate limit this key at all.
+        #[arg(long, conflicts_with_all = ["limit", "expiration"])]
+        unlimited: bool,
+    },
+    /// Remove the override of a tracked key.
+    Remove {
+        #[arg(short, long)]
+        route: String,
+        #[arg(short, long)]
+        key: String,
+    },
+    /// List the overrides of a rule.
+    List {
+        #[arg(short, long)]
+        route: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeysCommands {
+    /// Show the raw and interpreted state of a tracked key.
+    Inspect {
+        /// Route of the rule, as written in the configuration file.
+        #[arg(short, long)]
+        route: String,
+        /// Tracked key. Components of composite keys are separated by `|`.
+        #[arg(short, long)]
+        key: String,
+    },
+    /// Delete the state and the penalties of a tracked key.
+    Reset {
+        #[arg(short, long)]
+        route: String,
+        #[arg(short, long)]
+        key: String,
+    },
+    /// List the tracked keys having a state for a rule.
+    List {
+        #[arg(short, long)]
+        route: String,
     },
 }
 
@@ -110,7 +228,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| format!("{}=debug", env!("CARGO_CRATE_NAME")).into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        // Logs go to stderr so that stdout only carries commands output (export, history...)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .with(otel_layer)
         .init();
 
@@ -118,7 +237,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match &cli.command {
         Commands::Run => run().await?,
-        Commands::Load { file } => load_configuration(file).await?,
+        Commands::Load {
+            file,
+            dry_run,
+            author,
+        } => load_configuration(file, *dry_run, author.clone()).await?,
+        Commands::Export { format, output } => {
+            export_configuration(*format, output.as_deref()).await?
+        }
+        Commands::History => show_history().await?,
+        Commands::Overrides { command } => match command {
+            OverridesCommands::Set {
+                route,
+                key,
+                limit,
+                expiration,
+                unlimited,
+            } => {
+                let key_override = KeyOverride {
+                    limit: *limit,
+                    expiration: *expiration,
+                    unlimited: *unlimited,
+                };
+                set_override(route, key, key_override).await?
+            }
+            OverridesCommands::Remove { route, key } => remove_override(route, key).await?,
+            OverridesCommands::List { route } => list_overrides(route).await?,
+        },
+        Commands::Keys { command } => match command {
+            KeysCommands::Inspect { route, key } => inspect_key(route, key).await?,
+            KeysCommands::Reset { route, key } => reset_key(route, key).await?,
+            KeysCommands::List { route } => list_keys(route).await?,
+        },
+        Commands::Rollback { to, author } => rollback_configuration(*to, author.clone()).await?,
     }
 
     Ok(())
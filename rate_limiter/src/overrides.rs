@@ -0,0 +1,230 @@
+use anyhow::Context;
+use redis::{Commands, RedisError, aio::ConnectionManager};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+use crate::{configurations_loader::connect_to_redis, rules::get_rules_route_and_id};
+
+/// Limit applied to one tracked key of a rule instead of the rule (or tier) one.
+/// Overrides of a rule are stored in the `overrides:<rule id>` hash, indexed by tracked key.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct KeyOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<i32>,
+    #[serde(default)]
+    pub unlimited: bool, // The key is not rate limited at all
+}
+
+pub fn overrides_key(rule_id: &str) -> String {
+    format!("overrides:{rule_id}")
+}
+
+impl KeyOverride {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.unlimited && self.limit.is_none() {
+            anyhow::bail!("An override needs either a limit or to be unlimited.");
+        }
+        if self.limit.is_some_and(|limit| limit < 0) || self.expiration.is_some_and(|e| e <= 0) {
+            anyhow::bail!("The override limit and expiration can't be negative.");
+        }
+        Ok(())
+    }
+
+    /// The limit and expiration of the key given the ones of its rule, `None` when it is unlimited.
+    pub fn apply(&self, limit: u64, expiration: u64) -> Option<(u64, u64)> {
+        if self.unlimited {
+            return None;
+        }
+        Some((
+            self.limit.map_or(limit, |limit| limit as u64),
+            self.expiration
+                .map_or(expiration, |expiration| expiration as u64),
+        ))
+    }
+}
+
+/// Overrides of every rule having some, indexed by rule id then tracked key.
+/// Kept in memory and reloaded on `rl_update`, which the override commands publish.
+pub type Overrides = HashMap<String, HashMap<String, KeyOverride>>;
+
+/// Parses the `overrides:<rule id>` hash of a rule, invalid overrides are ignored.
+fn parse_overrides(rule_id: &str, stored: HashMap<String, String>) -> HashMap<String, KeyOverride> {
+    stored
+        .into_iter()
+        .filter_map(
+            |(key, key_override)| match serde_json::from_str(&key_override) {
+                Ok(key_override) => Some((key, key_override)),
+                Err(err) => {
+                    tracing::warn!("Ignoring invalid override for {key} on rule {rule_id}: {err}");
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+/// Reads the overrides of the given rules in a single round trip.
+pub async fn get_overrides<'a>(
+    pool: &mut ConnectionManager,
+    rule_ids: impl Iterator<Item = &'a String>,
+) -> Result<Overrides, RedisError> {
+    let rule_ids: Vec<&String> = rule_ids.collect();
+    if rule_ids.is_empty() {
+        return Ok(Overrides::new());
+    }
+    let mut pipeline = redis::pipe();
+    for rule_id in &rule_ids {
+        pipeline.hgetall(overrides_key(rule_id));
+    }
+    let stored: Vec<HashMap<String, String>> = pipeline.query_async(pool).await?;
+
+    Ok(rule_ids
+        .into_iter()
+        .zip(stored)
+        .filter(|(_, stored)| !stored.is_empty())
+        .map(|(rule_id, stored)| (rule_id.clone(), parse_overrides(rule_id, stored)))
+        .collect())
+}
+
+/// Tells the rate limiters to reload the overrides.
+fn publish_update(connection: &mut redis::Connection) -> anyhow::Result<()> {
+    let _: () = connection.publish("rl_update", "overrides")?;
+    Ok(())
+}
+
+fn get_rule_id(connection: &mut redis::Connection, route: &str) -> anyhow::Result<String> {
+    get_rules_route_and_id(connection)
+        .map_err(anyhow::Error::from_boxed)?
+        .remove(route)
+        .with_context(|| format!("No rule found for route {route}"))
+}
+
+pub async fn set_override(route: &str, key: &str, key_override: KeyOverride) -> anyhow::Result<()> {
+    key_override.validate()?;
+
+    let mut con = connect_to_redis()?;
+    let rule_id = get_rule_id(&mut con, route)?;
+    let _: () = con.hset(
+        overrides_key(&rule_id),
+        key,
+        serde_json::to_string(&key_override)?,
+    )?;
+    publish_update(&mut con)?;
+
+    tracing::info!("Override set for key {key} on route {route}.");
+    Ok(())
+}
+
+pub async fn remove_override(route: &str, key: &str) -> anyhow::Result<()> {
+    let mut con = connect_to_redis()?;
+    let rule_id = get_rule_id(&mut con, route)?;
+    let removed: u64 = con.hdel(overrides_key(&rule_id), key)?;
+
+    if removed == 0 {
+        anyhow::bail!("No override found for key {key} on route {route}");
+    }
+    publish_update(&mut con)?;
+    tracing::info!("Override removed for key {key} on route {route}.");
+    Ok(())
+}
+
+pub async fn list_overrides(route: &str) -> anyhow::Result<()> {
+    let mut con = connect_to_redis()?;
+    let rule_id = get_rule_id(&mut con, route)?;
+    let overrides: HashMap<String, String> = con.hgetall(overrides_key(&rule_id))?;
+
+    if overrides.is_empty() {
+        println!("No override for route {route}.");
+        return Ok(());
+    }
+
+    let mut keys: Vec<_> = overrides.keys().collect();
+    keys.sort();
+    println!(
+        "{:<40} {:<10} {:<10} UNLIMITED",
+        "KEY", "LIMIT", "EXPIRATION"
+    );
+    for key in keys {
+        let key_override: KeyOverride = serde_json::from_str(&overrides[key])
+            .with_context(|| format!("Invalid override for key {key}"))?;
+        println!(
+            "{:<40} {:<10} {:<10} {}",
+            key,
+            key_override
+                .limit
+                .map(|v| v.to_string())
+                .unwrap_or("-".to_string()),
+            key_override
+                .expiration
+                .map(|v| v.to_string())
+                .unwrap_or("-".to_string()),
+            key_override.unlimited
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_replace_the_limits_they_set() {
+        let limit = KeyOverride {
+            limit: Some(1000),
+            ..Default::default()
+        };
+        let both = KeyOverride {
+            limit: Some(5),
+            expiration: Some(3600),
+            unlimited: false,
+        };
+        let unlimited = KeyOverride {
+            unlimited: true,
+            ..Default::default()
+        };
+
+        assert_eq!(limit.apply(10, 60), Some((1000, 60)));
+        assert_eq!(both.apply(10, 60), Some((5, 3600)));
+        assert_eq!(unlimited.apply(10, 60), None);
+    }
+
+    #[test]
+    fn overrides_need_a_limit_or_to_be_unlimited() {
+        assert!(KeyOverride::default().validate().is_err());
+        let expiration_only = KeyOverride {
+            expiration: Some(60),
+            ..Default::default()
+        };
+        assert!(expiration_only.validate().is_err());
+        let negative = KeyOverride {
+            limit: Some(-1),
+            ..Default::default()
+        };
+        assert!(negative.validate().is_err());
+        let unlimited = KeyOverride {
+            unlimited: true,
+            ..Default::default()
+        };
+        assert!(unlimited.validate().is_ok());
+    }
+
+    #[test]
+    fn stored_overrides_are_parsed_and_invalid_ones_ignored() {
+        let stored = HashMap::from([
+            ("acme".to_string(), r#"{"limit":1000}"#.to_string()),
+            ("globex".to_string(), r#"{"unlimited":true}"#.to_string()),
+            ("initech".to_string(), "not json".to_string()),
+        ]);
+
+        let overrides = parse_overrides("rule", stored);
+
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides["acme"].limit, Some(1000));
+        assert!(overrides["globex"].unlimited);
+    }
+}
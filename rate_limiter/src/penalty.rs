@@ -0,0 +1,214 @@
+use lazy_static::lazy_static;
+use redis::{AsyncCommands, Script, aio::ConnectionManager};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::LimiterError;
+
+/// Escalation policy of a rule: after `rejections` rejections within `window` seconds,
+/// the tracked key is banned for `ban` seconds without reaching the algorithm.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Penalty {
+    pub rejections: u32,
+    pub window: u32,
+    pub ban: u32,
+    #[serde(default)]
+    pub exponential: bool, // Each new ban of the key lasts twice as long as the previous one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_ban: Option<u32>, // Upper bound of exponential bans
+}
+
+/// Upper bound of exponential bans without a `max_ban`, one day.
+pub const DEFAULT_MAX_BAN: u32 = 86_400;
+
+impl Penalty {
+    pub fn validate(&self, route: &str) -> anyhow::Result<()> {
+        if self.rejections == 0 || self.window == 0 || self.ban == 0 {
+            anyhow::bail!("Penalty rejections, window and ban must be positive. Route: {route}");
+        }
+        if self.max_ban.is_some_and(|max_ban| max_ban < self.ban) {
+            anyhow::bail!("Penalty max_ban must not be lower than ban. Route: {route}");
+        }
+        Ok(())
+    }
+}
+
+// KEYS[1] = violations counter, KEYS[2] = ban, KEYS[3] = number of bans already served
+// ARGV = rejections, window, ban, exponential ('1'/'0'), max ban
+// Returns the duration of the ban issued by this rejection, 0 if none.
+const RECORD_REJECTION_SOURCE: &str = r"
+        local violations = redis.call('INCR', KEYS[1])
+        if violations == 1 then
+            redis.call('EXPIRE', KEYS[1], ARGV[2])
+        end
+        if violations < tonumber(ARGV[1]) then
+            return 0
+        end
+
+        local ban = tonumber(ARGV[3])
+        local strikes = tonumber(redis.call('GET', KEYS[3]) or '0')
+        if ARGV[4] == '1' then
+            ban = ban * (2 ^ strikes)
+        end
+        -- Also keeps the ban an integer, 2 ^ strikes soon overflows to inf.
+        ban = math.min(ban, tonumber(ARGV[5]))
+
+        redis.call('DEL', KEYS[1])
+        redis.call('SET', KEYS[2], '1', 'EX', ban)
+        -- Strikes are forgotten once the key behaved for twice its last ban.
+        redis.call('SET', KEYS[3], strikes + 1, 'EX', ban * 2)
+        return ban
+        ";
+
+lazy_static! {
+    static ref RECORD_REJECTION_SCRIPT: Script = Script::new(RECORD_REJECTION_SOURCE);
+}
+
+pub fn penalty_keys(rule_id: &str, tracked_key: &str) -> [String; 3] {
+    [
+        format!("penalty:violations:{rule_id}:{tracked_key}"),
+        format!("penalty:ban:{rule_id}:{tracked_key}"),
+        format!("penalty:strikes:{rule_id}:{tracked_key}"),
+    ]
+}
+
+/// Remaining seconds of the ban of the key, if it is banned.
+pub async fn get_ban(
+    pool: &mut ConnectionManager,
+    rule_id: &str,
+    tracked_key: &str,
+) -> Result<Option<u64>, LimiterError> {
+    let [_, ban_key, _] = penalty_keys(rule_id, tracked_key);
+    // -2 when the key does not exist, -1 when it has no expiration.
+    let ttl: i64 = pool.ttl(ban_key).await?;
+    Ok((ttl > 0).then_some(ttl as u64))
+}
+
+fn record_rejection_args(penalty: &Penalty) -> [String; 5] {
+    [
+        penalty.rejections.to_string(),
+        penalty.window.to_string(),
+        penalty.ban.to_string(),
+        if penalty.exponential { "1" } else { "0" }.to_string(),
+        penalty
+            .max_ban
+            .unwrap_or(DEFAULT_MAX_BAN.max(penalty.ban))
+            .to_string(),
+    ]
+}
+
+/// Counts a rejection of the key and returns the duration of the ban it triggers, if any.
+pub async fn record_rejection(
+    pool: &mut ConnectionManager,
+    rule_id: &str,
+    tracked_key: &str,
+    penalty: &Penalty,
+) -> Result<Option<u64>, LimiterError> {
+    let [violations_key, ban_key, strikes_key] = penalty_keys(rule_id, tracked_key);
+    let ban: u64 = RECORD_REJECTION_SCRIPT
+        .key(violations_key)
+        .key(ban_key)
+        .key(strikes_key)
+        .arg(&record_rejection_args(penalty))
+        .invoke_async(pool)
+        .await?;
+    Ok((ban > 0).then_some(ban))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm_tests::FakeRedis;
+
+    const TTL_SOURCE: &str = "return redis.call('TTL', KEYS[1])";
+
+    fn reject(redis: &FakeRedis, penalty: &Penalty) -> i64 {
+        let keys = penalty_keys("rule", "acme");
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        redis.eval(
+            RECORD_REJECTION_SOURCE,
+            &keys,
+            &record_rejection_args(penalty),
+        )[0]
+    }
+
+    fn ban_ttl(redis: &FakeRedis) -> i64 {
+        let [_, ban_key, _] = penalty_keys("rule", "acme");
+        redis.eval(TTL_SOURCE, &[&ban_key], &[])[0]
+    }
+
+    fn penalty(exponential: bool, max_ban: Option<u32>) -> Penalty {
+        Penalty {
+            rejections: 3,
+            window: 60,
+            ban: 100,
+            exponential,
+            max_ban,
+        }
+    }
+
+    #[test]
+    fn keys_are_banned_after_enough_rejections_within_the_window() {
+        let redis = FakeRedis::new();
+        let penalty = penalty(false, None);
+        redis.set_time(1_000);
+
+        assert_eq!(reject(&redis, &penalty), 0);
+        assert_eq!(reject(&redis, &penalty), 0);
+        // The first violations expired, this one starts a new window.
+        redis.set_time(1_060);
+        assert_eq!(reject(&redis, &penalty), 0);
+        assert_eq!(ban_ttl(&redis), -2);
+
+        assert_eq!(reject(&redis, &penalty), 0);
+        assert_eq!(reject(&redis, &penalty), 100);
+        assert_eq!(ban_ttl(&redis), 100);
+    }
+
+    #[test]
+    fn exponential_bans_double_up_to_the_max_ban() {
+        let redis = FakeRedis::new();
+        let penalty = penalty(true, Some(300));
+        let mut now = 1_000;
+
+        let mut bans = vec![];
+        for _ in 0..4 {
+            redis.set_time(now);
+            let ban = (0..3).map(|_| reject(&redis, &penalty)).max().unwrap();
+            bans.push(ban);
+            // The next rejections come as soon as the ban is over.
+            now += ban as u64;
+        }
+
+        assert_eq!(bans, vec![100, 200, 300, 300]);
+    }
+
+    #[test]
+    fn exponential_bans_are_capped_without_a_max_ban() {
+        let redis = FakeRedis::new();
+        let penalty = penalty(true, None);
+        redis.set_time(1_000);
+        let [_, _, strikes_key] = penalty_keys("rule", "acme");
+        redis.eval(
+            "return redis.call('SET', KEYS[1], ARGV[1])",
+            &[&strikes_key],
+            &["2000".to_string()],
+        );
+
+        let ban = (0..3).map(|_| reject(&redis, &penalty)).max();
+        assert_eq!(ban, Some(DEFAULT_MAX_BAN as i64));
+        assert_eq!(ban_ttl(&redis), DEFAULT_MAX_BAN as i64);
+    }
+
+    #[test]
+    fn strikes_are_forgotten_after_twice_the_last_ban() {
+        let redis = FakeRedis::new();
+        let penalty = penalty(true, None);
+        redis.set_time(1_000);
+        (0..3).for_each(|_| {
+            reject(&redis, &penalty);
+        });
+
+        redis.set_time(1_000 + 200);
+        assert_eq!((0..3).map(|_| reject(&redis, &penalty)).max(), Some(100));
+    }
+}
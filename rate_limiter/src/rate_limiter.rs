@@ -1,17 +1,24 @@
 use lazy_static::lazy_static;
-use redis::{Script, aio::ConnectionManager};
+use redis::{Script, Value, aio::ConnectionLike, aio::ConnectionManager};
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use anyhow::Context;
+use std::{collections::HashMap, fmt, sync::OnceLock};
 
-use crate::{errors::LimiterError, utils::make_redis_key};
+use crate::{
+    errors::LimiterError,
+    rules::Rule,
+    utils::{GLOBAL_REDIS_KEY, combine_tracked_keys, make_redis_key, make_route_redis_key},
+};
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct RateLimiterHeaders {
     pub limit: u64,     // Maximum number of requests allowed
     pub remaining: u64, // Number of requests remaining in the current window
     pub reset: u64,     // Time in seconds until the rate limit resets
     pub policy: String, // The rate limiting policy used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u32>, // Milliseconds to wait before sending the request, when shaped
 }
 
 impl RateLimiterHeaders {
@@ -21,6 +28,7 @@ impl RateLimiterHeaders {
             remaining,
             reset,
             policy,
+            delay: None,
         }
     }
 }
@@ -33,28 +41,32 @@ const TOKEN_BUCKET: &str = "tb";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RateLimiterAlgorithms {
-    #[serde(alias = "fw")]
+    #[serde(rename = "fw", alias = "FixedWindow")]
     FixedWindow,
-    #[serde(alias = "swc")]
+    #[serde(rename = "swc", alias = "SlidingWindowCounter")]
     SlidingWindowCounter,
-    #[serde(alias = "swl")]
+    #[serde(rename = "swl", alias = "SlidingWindowLog")]
     SlidingWindowLog,
-    #[serde(alias = "tb")]
+    #[serde(rename = "tb", alias = "TokenBucket")]
     TokenBucket,
-    #[serde(alias = "lb")]
+    #[serde(rename = "lb", alias = "LeakyBucket")]
     LeakyBucket,
 }
 
-impl RateLimiterAlgorithms {
-    pub fn to_string(&self) -> String {
-        match self {
-            RateLimiterAlgorithms::FixedWindow => FIXED_WINDOW.to_string(),
-            RateLimiterAlgorithms::SlidingWindowCounter => SLIDING_WINDOW_COUNTER.to_string(),
-            RateLimiterAlgorithms::SlidingWindowLog => SLIDING_WINDOW_LOG.to_string(),
-            RateLimiterAlgorithms::TokenBucket => TOKEN_BUCKET.to_string(),
-            RateLimiterAlgorithms::LeakyBucket => LEAKY_BUCKET.to_string(),
-        }
+impl fmt::Display for RateLimiterAlgorithms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RateLimiterAlgorithms::FixedWindow => FIXED_WINDOW,
+            RateLimiterAlgorithms::SlidingWindowCounter => SLIDING_WINDOW_COUNTER,
+            RateLimiterAlgorithms::SlidingWindowLog => SLIDING_WINDOW_LOG,
+            RateLimiterAlgorithms::TokenBucket => TOKEN_BUCKET,
+            RateLimiterAlgorithms::LeakyBucket => LEAKY_BUCKET,
+        };
+        f.write_str(name)
     }
+}
+
+impl RateLimiterAlgorithms {
     pub fn from_string(s: &str) -> Result<Self, ()> {
         match s {
             FIXED_WINDOW => Ok(RateLimiterAlgorithms::FixedWindow),
@@ -66,232 +78,363 @@ impl RateLimiterAlgorithms {
         }
     }
 
+    /// Lua function `rate_limit(key, limit, expiration, now, consume, max_wait)` implementing the
+    /// algorithm, `now` being the current unix time in seconds.
+    /// Returns `{limit, remaining, reset, '1'}` when the request is allowed, `'0'` instead when not.
+    /// `remaining` never goes below 0, even when the limit dropped below what was already counted.
+    /// Nothing is written when `consume` is false, the request is only checked.
+    /// When shaping, the leaky bucket appends the milliseconds to wait before the request leaks out
+    /// of the bucket, and rejects it if that is longer than `max_wait` (unbounded when 0).
     pub fn get_script(&self) -> &'static str {
         match self {
             RateLimiterAlgorithms::FixedWindow => {
                 r#"
-                local key = KEYS[1]
-                local limit = tonumber(ARGV[1])
-                local expiration = tonumber(ARGV[2])
-
-    
-                if redis.call('EXISTS', key) == 0 then
-                    redis.call('SET', key, 0)
-                    redis.call('EXPIRE', key, expiration)
+                local function rate_limit(key, limit, expiration, now, consume)
+                    if redis.call('EXISTS', key) == 0 then
+                        if not consume then
+                            return {limit, math.max(0, limit - 1), expiration, '1'}
+                        end
+                        redis.call('SET', key, 0)
+                        redis.call('EXPIRE', key, expiration)
+                    end
+
+                    if redis.call('GET', key) + 1 > limit then
+                        local remaining = math.max(0, limit - redis.call('GET', key))
+                        local reset = redis.call('TTL', key)
+                        return {
+                            limit,
+                            remaining,
+                            reset,
+                            '0',
+                        }
+                    elseif not consume then
+                        local remaining = math.max(0, limit - redis.call('GET', key) - 1)
+                        local reset = redis.call('TTL', key)
+                        return {
+                            limit,
+                            remaining,
+                            reset,
+                            '1',
+                        }
+                    else
+                        redis.call('INCR', key)
+                        local remaining = math.max(0, limit - redis.call('GET', key))
+                        local reset = redis.call('TTL', key)
+                        return {
+                            limit,
+                            remaining,
+                            reset,
+                            '1',
+                        }
+                    end
                 end
+                "#
+            }
+            RateLimiterAlgorithms::SlidingWindowLog => {
+                r#"
+                local function rate_limit(k, limit, expiration, now, consume)
+                    local key = k .. ':ss'
+                    local key_counter = k .. ':counter'
+                    local window_start = '(' .. (now - expiration)
+
+                    -- Requests out of the window are only removed when counting, checks write nothing.
+                    if consume then
+                        redis.call('ZREMRANGEBYSCORE', key, 0, now - expiration)
+                    end
+                    local count = redis.call('ZCOUNT', key, window_start, '+inf')
+
+                    if count + 1 > limit then
+                        local oldest_time_and_member = redis.call('ZRANGEBYSCORE', key, window_start, '+inf', 'WITHSCORES', 'LIMIT', 0, 1)
+                        local oldest_time = tonumber(oldest_time_and_member[2])
+                        local reset = (oldest_time + expiration) - now
+
+                        return {
+                            limit,
+                            0,
+                            reset,
+                            '0',
+                        }
+                    else
+                        if consume then
+                            redis.call('ZADD', key, now, now .. ':' .. redis.call('INCR', key_counter))
+                            redis.call('EXPIRE', key, expiration + 1)
+                            redis.call('EXPIRE', key_counter, expiration + 1)
+                        end
+                        -- The log is still empty when only checking the first request.
+                        local oldest_time_and_member = redis.call('ZRANGEBYSCORE', key, window_start, '+inf', 'WITHSCORES', 'LIMIT', 0, 1)
+                        local oldest_time = tonumber(oldest_time_and_member[2] or now)
+                        local reset = (oldest_time + expiration) - now
+                        local remaining = math.max(0, limit - count - 1)
 
-                if redis.call('GET', key) + 1 > limit then
-                    local remaining = limit - redis.call('GET', key)
-                    local reset = redis.call('TTL', key)
-                    return {
-                        limit,
-                        remaining,
-                        reset,
-                        '0',
-                    }
-
-                else
-                    redis.call('INCR', key)
-                    local remaining = limit - redis.call('GET', key)
-                    local reset = redis.call('TTL', key)
-                    return {
-                        limit,
-                        remaining,
-                        reset,
-                        '1',
-                    }
+                        return {
+                            limit,
+                            remaining,
+                            reset,
+                            '1',
+                        }
+                    end
                 end
+                "#
+            }
+            RateLimiterAlgorithms::SlidingWindowCounter => {
+                r#"
+                local function rate_limit(key, limit, expiration, now, consume)
+                    -- we got three buckets of 'expiration' seconds each, used in turn
+                    local window = math.floor(now / expiration)
+                    local current_bucket = window % 3
+                    local previous_bucket = (window + 2) % 3
+                    local reset = expiration - (now % expiration)
+
+                    if not consume and redis.call('EXISTS', key) == 0 then
+                        return {limit, math.max(0, limit - 1), reset, '1'}
+                    end
+
+                    local previous_bucket_count = tonumber(redis.call('HGET', key, previous_bucket) or '0')
+                    local current_bucket_count = tonumber(redis.call('HGET', key, current_bucket) or '0')
+
+                    -- Buckets are only valid for the windows following the last written one.
+                    local last_window = tonumber(redis.call('HGET', key, 'window') or window)
+                    if window - last_window >= 2 then
+                        previous_bucket_count = 0
+                    end
+                    if window ~= last_window then
+                        current_bucket_count = 0
+                    end
 
+                    local percentage_in_bucket = (now % expiration) / expiration
+                    local weight = (1-percentage_in_bucket) * previous_bucket_count + current_bucket_count
+                    -- Counting this request must not take the weight over the limit.
+                    if weight + 1 > limit then
+
+                        return {
+                            limit,
+                            0,
+                            reset,
+                            '0',
+                        }
+                    else
+                        if consume then
+                            redis.call('HSET', key,
+                                previous_bucket, previous_bucket_count,
+                                current_bucket, current_bucket_count + 1,
+                                'window', window)
+                            -- The bucket still weighs during the next window.
+                            redis.call('EXPIRE', key, expiration * 2)
+                        end
+                        local remaining = math.max(0, limit - weight - 1)
+
+                        return {
+                            limit,
+                            remaining,
+                            reset,
+                            '1',
+                        }
+                    end
+                end
+                "#
+            }
+            RateLimiterAlgorithms::TokenBucket => {
+                r#"
+                local function rate_limit(key, limit, expiration, now, consume)
+                    local drop_rate = limit / expiration
+
+                    -- A missing tokens bucket is full, it expires once it had the time to refill.
+                    local current_count = tonumber(redis.call('HGET', key, 'count') or limit)
+                    local last_rq_timestamp = tonumber(redis.call('HGET', key, 'last_rq_timestamp') or now)
+                    local ttl = redis.call('TTL', key)
+                    if ttl < 0 then
+                        ttl = expiration
+                    end
+
+                    local elapsed = now - last_rq_timestamp
+                    local bucket_refill_rate = elapsed * drop_rate
+                    local new_count = math.min(limit, current_count + bucket_refill_rate)
+
+                    -- Rejections write nothing, the refill is computed from the last counted request.
+                    if new_count - 1 < 0 then
+                        return {
+                            limit,
+                            0,
+                            ttl,
+                            '0',
+                        }
+                    else
+                        if consume then
+                            redis.call('HSET', key, 'count', new_count - 1, 'last_rq_timestamp', now)
+                            redis.call('EXPIRE', key, expiration)
+                            ttl = expiration
+                        end
+                        return {
+                            limit,
+                            new_count - 1,
+                            ttl,
+                            '1',
+                        }
+                    end
+                end
+                "#
+            }
+            RateLimiterAlgorithms::LeakyBucket => {
+                r#"
+                local function rate_limit(key, limit, expiration, now, consume, max_wait)
+                    local drop_rate = limit / expiration
+
+                    -- A missing leaky bucket is empty, it expires once it had the time to drain.
+                    local current_count = tonumber(redis.call('HGET', key, 'count') or '0')
+                    local last_rq_timestamp = tonumber(redis.call('HGET', key, 'last_rq_timestamp') or now)
+                    local ttl = redis.call('TTL', key)
+                    if ttl < 0 then
+                        ttl = expiration
+                    end
+
+                    local elapsed = now - last_rq_timestamp
+                    local request_lazily_dropped = elapsed * drop_rate
+                    local new_count = math.max(0, current_count - request_lazily_dropped)
+
+                    -- The requests already in the bucket leak out before this one.
+                    local delay = math.ceil(new_count / drop_rate * 1000)
+
+                    -- Rejections write nothing, the leak is computed from the last counted request.
+                    if new_count + 1 > limit or (max_wait and max_wait > 0 and delay > max_wait) then
+                        return {
+                            limit,
+                            0,
+                            ttl,
+                            '0',
+                        }
+                    else
+                        if consume then
+                            redis.call('HSET', key, 'count', new_count + 1, 'last_rq_timestamp', now)
+                            redis.call('EXPIRE', key, expiration)
+                            ttl = expiration
+                        end
+                        local result = {
+                            limit,
+                            math.max(0, limit - math.ceil(new_count) - 1),
+                            ttl,
+                            '1',
+                        }
+                        if max_wait then
+                            table.insert(result, delay)
+                        end
+                        return result
+                    end
+                end
+                "#
+            }
+        }
+    }
+
+    /// Lua function `refund(key, limit, expiration, now, units)` giving back up to `units` requests.
+    /// Returns the number of requests actually given back, never more than were counted.
+    pub fn get_refund_script(&self) -> &'static str {
+        match self {
+            RateLimiterAlgorithms::FixedWindow => {
+                r#"
+                local function refund(key, limit, expiration, now, units)
+                    local count = tonumber(redis.call('GET', key) or '0')
+                    local refunded = math.min(count, units)
+                    if refunded > 0 then
+                        redis.call('DECRBY', key, refunded)
+                    end
+                    return refunded
+                end
                 "#
             }
             RateLimiterAlgorithms::SlidingWindowLog => {
                 r#"
-                local k = KEYS[1]
-                local key = k .. ':ss'
-                local key_counter = k .. ':counter'
-                
-                local limit = tonumber(ARGV[1])
-                local expiration = tonumber(ARGV[2])
-                local now = redis.call('TIME')[1]
-                
-                redis.call('ZREMRANGEBYSCORE', key, 0, now - expiration)
-                local count = redis.call('ZCARD', key)
-                
-
-                if count + 1 > limit then
-                    redis.call('EXPIRE', key, expiration + 1)
-                    redis.call('EXPIRE', key_counter, expiration + 1)
-                    local oldest_time_and_member = redis.call('ZRANGE', key, 0, 0, 'WITHSCORES')
-                    local oldest_time = tonumber(oldest_time_and_member[2])
-                    local reset = (oldest_time + expiration) - now
-                    
-                    return {
-                        limit,
-                        0,
-                        reset,
-                        '0',
-                    }
-                else
-                    redis.call('ZADD', key, now, now .. ':' .. redis.call('INCR', key_counter))
-                    redis.call('EXPIRE', key, expiration + 1)
-                    redis.call('EXPIRE', key_counter, expiration + 1)
-                    local oldest_time_and_member = redis.call('ZRANGE', key, 0, 0, 'WITHSCORES')
-                    local oldest_time = tonumber(oldest_time_and_member[2])
-                    local reset = (oldest_time + expiration) - now
-                    local remaining = limit - count - 1
-                    
-                    return {
-                        limit,
-                        remaining,
-                        reset,
-                        '1',
-                    }
+                local function refund(k, limit, expiration, now, units)
+                    local key = k .. ':ss'
+
+                    redis.call('ZREMRANGEBYSCORE', key, 0, now - expiration)
+                    local refunded = math.min(redis.call('ZCARD', key), units)
+                    -- The most recent entries of the log are removed.
+                    if refunded > 0 then
+                        redis.call('ZREMRANGEBYRANK', key, -refunded, -1)
+                    end
+                    return refunded
                 end
                 "#
             }
             RateLimiterAlgorithms::SlidingWindowCounter => {
                 r#"
-                local key = KEYS[1]
-                local limit = tonumber(ARGV[1])
-                local expiration = tonumber(ARGV[2])
-                local now = redis.call('TIME')[1]
-                local mod_value = expiration * 3 -- we got three buckets of 'expiration' seconds each
-
-                -- verify that the buckets exists
-                if redis.call('EXISTS', key) == 0 then
-                    redis.call('HMSET', key, '0', '0', '1', '0', '2', '0')
-                end
-                
-                redis.call('EXPIRE', key, expiration + 1)
-
-                local normalized_now = now % mod_value
-
-                local current_bucket = math.floor(normalized_now / expiration)
-                local previous_bucket = (current_bucket + 2) % 3
-                local next_bucket = (current_bucket + 1) % 3
-                
-                redis.call('HSET', key, next_bucket, 0) -- reset the counter for the bucket to come.
-
-                local normalized_to_window = normalized_now % expiration
-                local percentage_in_bucket = normalized_to_window / expiration
-                
-                
-                local previous_bucket_count = redis.call('HGET', key, previous_bucket)
-                local current_bucket_count = redis.call('HGET', key, current_bucket)
-
-                local weight = (1-percentage_in_bucket) * tonumber(previous_bucket_count) + tonumber(current_bucket_count)
-                local reset = expiration - (now % expiration)
-                if weight > limit then
-
-                    return {
-                        limit,
-                        0,
-                        reset,
-                        '0',
-                    }
-                else
-                    redis.call('HINCRBY', key, current_bucket, 1)
-                    local remaining = limit - (1-percentage_in_bucket) * tonumber(previous_bucket_count) - tonumber(current_bucket_count) - 1
-
-                    return {
-                        limit,
-                        remaining,
-                        reset,
-                        '1',
-                    }
+                local function refund(key, limit, expiration, now, units)
+                    local window = math.floor(now / expiration)
+                    local current_bucket = window % 3
+
+                    -- Only the requests of the current bucket are given back, the previous one fades out anyway.
+                    if tonumber(redis.call('HGET', key, 'window') or window) ~= window then
+                        return 0
+                    end
+                    local count = tonumber(redis.call('HGET', key, current_bucket) or '0')
+                    local refunded = math.min(count, units)
+                    if refunded > 0 then
+                        redis.call('HINCRBY', key, current_bucket, -refunded)
+                    end
+                    return refunded
                 end
                 "#
             }
             RateLimiterAlgorithms::TokenBucket => {
                 r#"
-                local key = KEYS[1]
-                local limit = tonumber(ARGV[1])
-                local expiration = tonumber(ARGV[2])
-                local now = tonumber(redis.call('TIME')[1])
-                local drop_rate = limit / expiration
-
-
-                -- init the tokens bucket
-                redis.call('HSETNX', key, 'count', limit)
-                redis.call('HSETNX', key, 'last_rq_timestamp', now)
-                redis.call('EXPIRE', key, expiration, 'NX')
-
-                local ttl = redis.call('TTL', key)
-                
-                local elapsed = now - tonumber(redis.call('HGET', key, 'last_rq_timestamp'))
-                local bucket_refill_rate = elapsed * drop_rate
-                
-                local current_count = tonumber(redis.call('HGET', key, 'count'))
-                local new_count = math.min(limit, current_count + bucket_refill_rate)
-
-                redis.call('HSET', key, 'count', new_count) 
-
-
-                if new_count - 1 < 0 then
-                    return {
-                        limit,
-                        0,
-                        ttl,
-                        '0',
-                    }
-                else 
-                    redis.call('HSET', key, 'count', new_count - 1)
+                local function refund(key, limit, expiration, now, units)
+                    if redis.call('EXISTS', key) == 0 then
+                        return 0
+                    end
+                    local drop_rate = limit / expiration
+
+                    local elapsed = now - tonumber(redis.call('HGET', key, 'last_rq_timestamp'))
+                    local current_count = tonumber(redis.call('HGET', key, 'count'))
+                    local refilled = math.min(limit, current_count + elapsed * drop_rate)
+                    local refunded = math.floor(math.min(units, limit - refilled))
+
+                    redis.call('HSET', key, 'count', refilled + refunded)
                     redis.call('HSET', key, 'last_rq_timestamp', now)
-                    return {
-                        limit,
-                        new_count - 1,
-                        ttl,
-                        '1',
-                    }
+                    return refunded
                 end
                 "#
             }
             RateLimiterAlgorithms::LeakyBucket => {
                 r#"
-                local key = KEYS[1]
-                local limit = tonumber(ARGV[1])
-                local expiration = tonumber(ARGV[2])
-                local now = tonumber(redis.call('TIME')[1])
-                local drop_rate = limit / expiration
-
-
-                -- init the leaky bucket
-                redis.call('HSETNX', key, 'count', 0)
-                redis.call('HSETNX', key, 'last_rq_timestamp', now)
-                redis.call('EXPIRE', key, expiration, 'NX')
-
-                local ttl = redis.call('TTL', key)
-                
-                local elapsed = now - tonumber(redis.call('HGET', key, 'last_rq_timestamp'))
-                local request_lazily_dropped = elapsed * drop_rate
-                
-                local current_count = tonumber(redis.call('HGET', key, 'count'))
-                local new_count = math.max(0, current_count - request_lazily_dropped)
-
-                redis.call('HSET', key, 'count', new_count) 
-
-
-                if new_count + 1 > limit then
-                    return {
-                        limit,
-                        0,
-                        ttl,
-                        '0',
-                    }
-                else 
-                    redis.call('HSET', key, 'count', new_count + 1)
+                local function refund(key, limit, expiration, now, units)
+                    if redis.call('EXISTS', key) == 0 then
+                        return 0
+                    end
+                    local drop_rate = limit / expiration
+
+                    local elapsed = now - tonumber(redis.call('HGET', key, 'last_rq_timestamp'))
+                    local current_count = tonumber(redis.call('HGET', key, 'count'))
+                    local drained = math.max(0, current_count - elapsed * drop_rate)
+                    local refunded = math.floor(math.min(units, drained))
+
+                    redis.call('HSET', key, 'count', drained - refunded)
                     redis.call('HSET', key, 'last_rq_timestamp', now)
-                    return {
-                        limit,
-                        limit - math.ceil(new_count) - 1,
-                        ttl,
-                        '1',
-                    }
+                    return refunded
                 end
                 "#
             }
         }
     }
+
+    /// Script rate limiting every level of a rule with the algorithm, see `LEVELS_SCRIPT`.
+    /// The global level is always a fixed window, `global_rate_limit`.
+    pub fn get_levels_script(&self) -> String {
+        let global = RateLimiterAlgorithms::FixedWindow.get_script().replacen(
+            "local function rate_limit(",
+            "local function global_rate_limit(",
+            1,
+        );
+        format!("{global}{}{LEVELS_SCRIPT}", self.get_script())
+    }
+
+    /// Script refunding every level of a rule with the algorithm, see `REFUND_LEVELS_SCRIPT`.
+    /// The global level is always a fixed window, `global_refund`.
+    pub fn get_refund_levels_script(&self) -> String {
+        let global = RateLimiterAlgorithms::FixedWindow
+            .get_refund_script()
+            .replacen("local function refund(", "local function global_refund(", 1);
+        format!("{global}{}{REFUND_LEVELS_SCRIPT}", self.get_refund_script())
+    }
 }
 
 impl TryFrom<String> for RateLimiterAlgorithms {
@@ -311,21 +454,77 @@ impl TryFrom<String> for RateLimiterAlgorithms {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum LimiterTrackingType {
-    #[serde(alias = "ip")]
+    #[serde(rename = "ip", alias = "IP")]
     IP, // Should be tracked by the ip address of the requester
-    #[serde(alias = "header")]
+    #[serde(rename = "header", alias = "Header")]
     Header, // A custom header should be tracked
+    #[serde(rename = "query", alias = "Query")]
+    Query, // A query string parameter should be tracked
+    #[serde(rename = "cookie", alias = "Cookie")]
+    Cookie, // A cookie should be tracked
+    #[serde(rename = "path_param", alias = "PathParam")]
+    PathParam, // A parameter of the route, ex: `id` in `api/v1/orders/{id}`
+    #[serde(rename = "jwt_claim", alias = "JwtClaim")]
+    JwtClaim, // A claim of the verified bearer token, see `JwtTracking`
+    #[serde(rename = "composite", alias = "Composite")]
+    Composite, // An ordered combination of the above, see `TrackingComponent`
 }
 
 impl LimiterTrackingType {
-    pub fn to_string(&self) -> String {
+    /// Whether the name of the tracked header, parameter or cookie must be given as `custom_tracking_key`.
+    pub fn requires_custom_key(&self) -> bool {
+        matches!(
+            self,
+            LimiterTrackingType::Header
+                | LimiterTrackingType::Query
+                | LimiterTrackingType::Cookie
+                | LimiterTrackingType::PathParam
+                | LimiterTrackingType::JwtClaim
+        )
+    }
+}
+
+impl fmt::Display for LimiterTrackingType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            LimiterTrackingType::IP => "ip".to_string(),
-            LimiterTrackingType::Header => "header".to_string(),
+            LimiterTrackingType::IP => f.write_str("ip"),
+            LimiterTrackingType::Header => f.write_str("header"),
+            LimiterTrackingType::Query => f.write_str("query"),
+            LimiterTrackingType::Cookie => f.write_str("cookie"),
+            LimiterTrackingType::PathParam => f.write_str("path_param"),
+            LimiterTrackingType::JwtClaim => f.write_str("jwt_claim"),
+            LimiterTrackingType::Composite => f.write_str("composite"),
         }
     }
 }
 
+/// One part of a composite tracking key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrackingComponent {
+    pub tracking_type: LimiterTrackingType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub custom_tracking_key: Option<String>,
+}
+
+/// Where the bearer token of a `jwt_claim` tracked key is read, and what is tracked without a valid one.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct JwtTracking {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>, // Header carrying the bearer token, `authorization` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<TrackingComponent>, // Tracked when the token is missing or invalid, ip by default
+}
+
+/// What to do with a request when a component of a composite tracking key can't be found.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingComponentBehaviour {
+    #[default]
+    Reject, // The request is rejected as when a single tracked key is missing
+    Skip,   // The component is left empty in the key, the other ones are still tracked
+    Bypass, // The request is allowed without being rate limited
+}
+
 impl TryFrom<String> for LimiterTrackingType {
     type Error = String;
 
@@ -333,6 +532,11 @@ impl TryFrom<String> for LimiterTrackingType {
         match value.as_str() {
             "header" => Ok(LimiterTrackingType::Header),
             "ip" => Ok(LimiterTrackingType::IP), // Ip should be the default
+            "query" => Ok(LimiterTrackingType::Query),
+            "cookie" => Ok(LimiterTrackingType::Cookie),
+            "path_param" => Ok(LimiterTrackingType::PathParam),
+            "jwt_claim" => Ok(LimiterTrackingType::JwtClaim),
+            "composite" => Ok(LimiterTrackingType::Composite),
             _ => Err(format!("{value} is not a valid tracking type.")),
         }
     }
@@ -343,70 +547,698 @@ impl From<LimiterTrackingType> for String {
         match value {
             LimiterTrackingType::Header => "header".to_string(),
             LimiterTrackingType::IP => "ip".to_string(),
+            LimiterTrackingType::Query => "query".to_string(),
+            LimiterTrackingType::Cookie => "cookie".to_string(),
+            LimiterTrackingType::PathParam => "path_param".to_string(),
+            LimiterTrackingType::JwtClaim => "jwt_claim".to_string(),
+            LimiterTrackingType::Composite => "composite".to_string(),
         }
     }
 }
 
+/// Runs `rate_limit` for every level of a rule, KEYS[i] being limited by ARGV[2i - 1] requests
+/// per ARGV[2i] seconds. The tracked key comes first, then the route and global aggregates.
+/// A request is only counted when every level allows it, the result of the tracked key is returned.
+/// Otherwise the result of the first rejecting level is returned, with its position appended.
+/// The next ARGV is '1' to count the request, '0' to only peek at the remaining requests, then
+/// the maximum wait in milliseconds of shaped requests, empty when not shaping, then '1' when the
+/// last level is the global one, limited by `global_rate_limit`, and optionally the current unix
+/// time in seconds, redis' clock being used otherwise.
+const LEVELS_SCRIPT: &str = r#"
+                local consume = ARGV[2 * #KEYS + 1] == '1'
+                local max_wait = tonumber(ARGV[2 * #KEYS + 2])
+                local global = ARGV[2 * #KEYS + 3] == '1'
+                local now = tonumber(ARGV[2 * #KEYS + 4]) or tonumber(redis.call('TIME')[1])
+
+                local function level(i)
+                    local limiter = rate_limit
+                    if global and i == #KEYS then
+                        limiter = global_rate_limit
+                    end
+                    return limiter, KEYS[i], tonumber(ARGV[2 * i - 1]), tonumber(ARGV[2 * i])
+                end
+
+                if #KEYS > 1 or not consume then
+                    local first
+                    for i = 1, #KEYS do
+                        local limiter, key, limit, expiration = level(i)
+                        local result = limiter(key, limit, expiration, now, false, max_wait)
+                        if result[4] == '0' then
+                            table.insert(result, i)
+                            return result
+                        end
+                        first = first or result
+                    end
+                    if not consume then
+                        -- Nothing was counted, the checked request is still part of the remaining ones.
+                        first[2] = first[2] + 1
+                        return first
+                    end
+                end
+
+                local result
+                for i = #KEYS, 1, -1 do
+                    local limiter, key, limit, expiration = level(i)
+                    result = limiter(key, limit, expiration, now, true, max_wait)
+                    if result[4] == '0' then
+                        table.insert(result, i)
+                        return result
+                    end
+                end
+                return result
+"#;
+
+/// Runs `refund` for every level of a rule, laid out as for `LEVELS_SCRIPT`, with the number of
+/// units to give back as next ARGV, then '1' when the last level is the global one, refunded by
+/// `global_refund`, and optionally the current unix time in seconds.
+/// The tracked key is refunded first, the aggregates only get back what the key got back: the rest
+/// of their count was consumed by other keys. The result of the tracked key is returned.
+const REFUND_LEVELS_SCRIPT: &str = r#"
+                local units = tonumber(ARGV[2 * #KEYS + 1])
+                local global = ARGV[2 * #KEYS + 2] == '1'
+                local now = tonumber(ARGV[2 * #KEYS + 3]) or tonumber(redis.call('TIME')[1])
+
+                local function refund_level(i, units)
+                    local limit, expiration = tonumber(ARGV[2 * i - 1]), tonumber(ARGV[2 * i])
+                    local refunder = refund
+                    if global and i == #KEYS then
+                        refunder = global_refund
+                    end
+                    return refunder(KEYS[i], limit, expiration, now, units)
+                end
+
+                local result = refund_level(1, units)
+                if result > 0 then
+                    for i = 2, #KEYS do
+                        refund_level(i, result)
+                    end
+                end
+                return result
+"#;
+
 lazy_static! {
-    static ref SCRIPTS: HashMap<String, Script> = {
-        let mut scripts = HashMap::new();
-        scripts.insert(
-            RateLimiterAlgorithms::FixedWindow.to_string(),
-            Script::new(RateLimiterAlgorithms::FixedWindow.get_script()),
-        );
-        scripts.insert(
-            RateLimiterAlgorithms::SlidingWindowCounter.to_string(),
-            Script::new(RateLimiterAlgorithms::SlidingWindowCounter.get_script()),
-        );
-        scripts.insert(
-            RateLimiterAlgorithms::SlidingWindowLog.to_string(),
-            Script::new(RateLimiterAlgorithms::SlidingWindowLog.get_script()),
-        );
-        scripts.insert(
-            RateLimiterAlgorithms::TokenBucket.to_string(),
-            Script::new(RateLimiterAlgorithms::TokenBucket.get_script()),
-        );
-        scripts.insert(
-            RateLimiterAlgorithms::LeakyBucket.to_string(),
-            Script::new(RateLimiterAlgorithms::LeakyBucket.get_script()),
+    static ref SCRIPTS: HashMap<String, Script> = [
+        RateLimiterAlgorithms::FixedWindow,
+        RateLimiterAlgorithms::SlidingWindowCounter,
+        RateLimiterAlgorithms::SlidingWindowLog,
+        RateLimiterAlgorithms::TokenBucket,
+        RateLimiterAlgorithms::LeakyBucket,
+    ]
+    .iter()
+    .map(|algorithm| {
+        (
+            algorithm.to_string(),
+            Script::new(&algorithm.get_levels_script()),
+        )
+    })
+    .collect();
+    static ref REFUND_SCRIPTS: HashMap<String, Script> = [
+        RateLimiterAlgorithms::FixedWindow,
+        RateLimiterAlgorithms::SlidingWindowCounter,
+        RateLimiterAlgorithms::SlidingWindowLog,
+        RateLimiterAlgorithms::TokenBucket,
+        RateLimiterAlgorithms::LeakyBucket,
+    ]
+    .iter()
+    .map(|algorithm| {
+        (
+            algorithm.to_string(),
+            Script::new(&algorithm.get_refund_levels_script()),
+        )
+    })
+    .collect();
+}
+
+/// Level of a rule at which a request is limited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitLevel {
+    Key,    // The tracked key alone
+    Route,  // Every key of the route
+    Global, // Every rule taking part in the global limit
+}
+
+impl fmt::Display for LimitLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitLevel::Key => f.write_str("key"),
+            LimitLevel::Route => f.write_str("route"),
+            LimitLevel::Global => f.write_str("global"),
+        }
+    }
+}
+
+/// Leaky bucket mode smoothing bursts: requests are given the delay after which they leak out of
+/// the bucket instead of being rejected, as long as the bucket is not full.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Shaping {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_wait: Option<u64>, // Milliseconds, requests needing a longer wait are rejected
+    #[serde(default)]
+    pub hold: bool, // The limiter waits itself before answering, instead of returning the delay
+}
+
+/// Longest wait of held requests, each of them keeps a connection open meanwhile.
+pub const MAX_HOLD_WAIT: u64 = 30_000;
+
+impl Shaping {
+    pub fn validate(&self, route: &str) -> anyhow::Result<()> {
+        if self.max_wait == Some(0) {
+            anyhow::bail!("Shaping max_wait must be positive. Route: {route}");
+        }
+        if self.hold {
+            match self.max_wait {
+                None => anyhow::bail!("Held shaping requires a max_wait. Route: {route}"),
+                Some(max_wait) if max_wait > MAX_HOLD_WAIT => anyhow::bail!(
+                    "Held shaping max_wait must not exceed {MAX_HOLD_WAIT} ms. Route: {route}"
+                ),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Limits of the route aggregate of a rule, the rule expiration is used when not set.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AggregateLimit {
+    pub limit: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<i32>,
+}
+
+/// Cap on the requests of every rule taking part in it, counted by a single fixed window
+/// whatever the algorithm of the rules. Configured with `RL_GLOBAL_LIMIT` and `RL_GLOBAL_EXPIRATION`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GlobalLimit {
+    pub limit: u64,
+    pub expiration: u64,
+}
+
+static GLOBAL_LIMIT: OnceLock<GlobalLimit> = OnceLock::new();
+
+impl GlobalLimit {
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(limit) = std::env::var("RL_GLOBAL_LIMIT") else {
+            return Ok(None);
+        };
+        let expiration = std::env::var("RL_GLOBAL_EXPIRATION")
+            .context("RL_GLOBAL_EXPIRATION is required with RL_GLOBAL_LIMIT")?;
+        let global_limit = GlobalLimit {
+            limit: limit
+                .parse()
+                .with_context(|| format!("Invalid RL_GLOBAL_LIMIT {limit}"))?,
+            expiration: expiration
+                .parse()
+                .with_context(|| format!("Invalid RL_GLOBAL_EXPIRATION {expiration}"))?,
+        };
+        if global_limit.limit == 0 || global_limit.expiration == 0 {
+            anyhow::bail!("The global limit and expiration must be positive.");
+        }
+        tracing::info!(
+            "Global limit of {} requests per {}s.",
+            global_limit.limit,
+            global_limit.expiration
         );
-        scripts
-    };
+        Ok(Some(global_limit))
+    }
+
+    /// Makes the limit apply to the rules taking part in the global limit.
+    pub fn init(self) {
+        if GLOBAL_LIMIT.set(self).is_err() {
+            tracing::warn!("The global limit is already configured.");
+        }
+    }
+
+    pub fn get() -> Option<GlobalLimit> {
+        GLOBAL_LIMIT.get().copied()
+    }
+}
+
+/// Keys checked for a request with their limit and expiration:
+/// the tracked key first, then the route aggregate of the rule and the global limit.
+fn limit_levels(
+    tracked_keys: &[String],
+    rule: &Rule,
+    limit: u64,
+    expiration: u64,
+    global_limit: Option<GlobalLimit>,
+) -> Vec<(LimitLevel, String, u64, u64)> {
+    let algorithm = &rule.algorithm;
+    let redis_key = make_redis_key(tracked_keys, &rule.id, algorithm);
+    let mut levels = vec![(LimitLevel::Key, redis_key, limit, expiration)];
+    if let Some(route_limit) = &rule.route_limit {
+        levels.push((
+            LimitLevel::Route,
+            make_route_redis_key(&rule.id, algorithm),
+            route_limit.limit as u64,
+            route_limit.expiration.unwrap_or(rule.expiration) as u64,
+        ));
+    }
+    if rule.global_limit == Some(true)
+        && let Some(global_limit) = global_limit
+    {
+        levels.push((
+            LimitLevel::Global,
+            GLOBAL_REDIS_KEY.to_string(),
+            global_limit.limit,
+            global_limit.expiration,
+        ));
+    }
+    levels
+}
+
+/// '1' when the last level is the global one, for the scripts to count it with a fixed window.
+fn global_arg(levels: &[(LimitLevel, String, u64, u64)]) -> &'static str {
+    match levels.last() {
+        Some((LimitLevel::Global, ..)) => "1",
+        _ => "0",
+    }
+}
+
+/// Maximum wait given to the scripts, empty when the rule is not shaping.
+fn max_wait_arg(rule: &Rule) -> String {
+    rule.shaping
+        .as_ref()
+        .map(|shaping| shaping.max_wait.unwrap_or_default().to_string())
+        .unwrap_or_default()
+}
+
+/// ARGV of the levels script, laid out as `LEVELS_SCRIPT` expects. The current time is left to redis.
+fn levels_args(
+    levels: &[(LimitLevel, String, u64, u64)],
+    rule: &Rule,
+    consume: bool,
+) -> Vec<String> {
+    let mut args = Vec::with_capacity(2 * levels.len() + 3);
+    for (_, _, limit, expiration) in levels {
+        args.extend([limit.to_string(), expiration.to_string()]);
+    }
+    args.extend([
+        if consume { "1" } else { "0" }.to_string(),
+        max_wait_arg(rule),
+        global_arg(levels).to_string(),
+    ]);
+    args
+}
+
+/// ARGV of the refund levels script, laid out as `REFUND_LEVELS_SCRIPT` expects.
+fn refund_args(levels: &[(LimitLevel, String, u64, u64)], units: u64) -> Vec<String> {
+    let mut args = Vec::with_capacity(2 * levels.len() + 2);
+    for (_, _, limit, expiration) in levels {
+        args.extend([limit.to_string(), expiration.to_string()]);
+    }
+    args.extend([units.to_string(), global_arg(levels).to_string()]);
+    args
+}
+
+/// Headers of the result of a script, or the rejection it stands for.
+fn into_headers(
+    result: &[u64],
+    levels: &[(LimitLevel, String, u64, u64)],
+    algorithm: &RateLimiterAlgorithms,
+    tracked_key: String,
+    route: &str,
+) -> Result<RateLimiterHeaders, LimiterError> {
+    let mut headers =
+        RateLimiterHeaders::new(result[0], result[1], result[2], algorithm.to_string());
+    tracing::debug!("Resulting headers after rate limiting: {:#?}", headers);
+
+    if result[3] == 0 {
+        let level = result
+            .get(4)
+            .and_then(|position| levels.get(*position as usize - 1))
+            .map_or(LimitLevel::Key, |(level, ..)| *level);
+        return Err(LimiterError::RateLimitExceeded {
+            headers: Box::new(headers),
+            key: tracked_key,
+            msg: "Rate limit exceeded".to_string(),
+            route: route.to_string(),
+            level,
+        });
+    }
+
+    headers.delay = result.get(4).map(|delay| *delay as u32);
+    Ok(headers)
 }
 
 pub async fn execute_rate_limiting(
     mut pool: ConnectionManager,
-    tracked_key: &str,
-    rule_redis_config_key: &str,
-    algorithm: &RateLimiterAlgorithms,
+    tracked_keys: &[String],
+    rule: &Rule,
     limit: u64,
     expiration: u64,
     route: &str,
+    consume: bool,
 ) -> Result<RateLimiterHeaders, LimiterError> {
+    let algorithm = &rule.algorithm;
+    let tracked_key = combine_tracked_keys(tracked_keys);
     tracing::debug!(
-        "Executing rate limiting with key {tracked_key}, algorithm {algorithm:?}, limit {limit}, expiration {expiration} and rule_redis_config_key {rule_redis_config_key}"
+        "Executing rate limiting with key {tracked_key}, algorithm {algorithm:?}, limit {limit}, expiration {expiration} and rule_redis_config_key {}",
+        rule.id
     );
-    let redis_key = make_redis_key(tracked_key, rule_redis_config_key, algorithm);
     let script = SCRIPTS.get(&algorithm.to_string()).unwrap();
+    let levels = limit_levels(tracked_keys, rule, limit, expiration, GlobalLimit::get());
 
-    let result: Vec<u64> = script
-        .key(redis_key)
-        .arg(limit)
-        .arg(expiration)
-        .invoke_async(&mut pool)
-        .await?;
+    let mut invocation = script.prepare_invoke();
+    for (_, key, ..) in &levels {
+        invocation.key(key);
+    }
+    invocation.arg(levels_args(&levels, rule, consume));
+    let result: Vec<u64> = invocation.invoke_async(&mut pool).await?;
 
-    let headers = RateLimiterHeaders::new(result[0], result[1], result[2], algorithm.to_string());
-    tracing::debug!("Resulting headers after rate limiting: {:#?}", headers);
+    into_headers(&result, &levels, algorithm, tracked_key, route)
+}
 
-    if result[3] == 0 {
-        return Err(LimiterError::RateLimitExceeded {
-            headers,
-            key: tracked_key.to_string(),
-            msg: "Rate limit exceeded".to_string(),
-            route: route.to_string(),
+/// One check of a batch, rate limited as a request would be.
+pub struct RateLimitCheck<'a> {
+    pub tracked_keys: Vec<String>,
+    pub rule: &'a Rule,
+    pub limit: u64,
+    pub expiration: u64,
+    pub route: String,
+}
+
+/// Loads the scripts of every algorithm, so that batches can send them by their hash only.
+pub async fn load_scripts(pool: &mut ConnectionManager) -> Result<(), LimiterError> {
+    for script in SCRIPTS.values().chain(REFUND_SCRIPTS.values()) {
+        script.load_async(pool).await?;
+    }
+    Ok(())
+}
+
+/// EVALSHA of the levels script of every check, laid out as `execute_rate_limiting` does.
+fn batch_pipeline<'a>(
+    checks: impl Iterator<
+        Item = (
+            &'a RateLimitCheck<'a>,
+            &'a Vec<(LimitLevel, String, u64, u64)>,
+        ),
+    >,
+) -> redis::Pipeline {
+    let mut pipeline = redis::pipe();
+    for (check, levels) in checks {
+        let script = SCRIPTS.get(&check.rule.algorithm.to_string()).unwrap();
+        pipeline
+            .cmd("EVALSHA")
+            .arg(script.get_hash())
+            .arg(levels.len());
+        for (_, key, ..) in levels {
+            pipeline.arg(key);
+        }
+        pipeline.arg(levels_args(levels, check.rule, true));
+    }
+    pipeline
+}
+
+/// Result of every command of the pipeline, errors included, rather than the first error.
+async fn query_each(
+    pool: &mut ConnectionManager,
+    pipeline: &redis::Pipeline,
+) -> Result<Vec<Value>, LimiterError> {
+    Ok(pool
+        .req_packed_commands(pipeline, 0, pipeline.len())
+        .await?)
+}
+
+fn is_noscript(value: &Value) -> bool {
+    matches!(value, Value::ServerError(err) if err.code() == "NOSCRIPT")
+}
+
+/// Rate limits every check with its algorithm script, all of them pipelined in a single round trip.
+/// Scripts are loaded at startup, the checks whose script the server lost since, ex: after a
+/// restart, are run again once the scripts are loaded. Only those, the others already counted.
+pub async fn execute_rate_limiting_batch(
+    mut pool: ConnectionManager,
+    checks: &[RateLimitCheck<'_>],
+) -> Result<Vec<Result<RateLimiterHeaders, LimiterError>>, LimiterError> {
+    let levels: Vec<_> = checks
+        .iter()
+        .map(|check| {
+            limit_levels(
+                &check.tracked_keys,
+                check.rule,
+                check.limit,
+                check.expiration,
+                GlobalLimit::get(),
+            )
+        })
+        .collect();
+
+    let pipeline = batch_pipeline(checks.iter().zip(&levels));
+    let mut values = query_each(&mut pool, &pipeline).await?;
+
+    let missing: Vec<usize> = (0..values.len())
+        .filter(|index| is_noscript(&values[*index]))
+        .collect();
+    if !missing.is_empty() {
+        tracing::warn!("{} batch checks ran into unknown scripts.", missing.len());
+        load_scripts(&mut pool).await?;
+        let retry = batch_pipeline(
+            missing
+                .iter()
+                .map(|index| (&checks[*index], &levels[*index])),
+        );
+        for (index, value) in missing
+            .into_iter()
+            .zip(query_each(&mut pool, &retry).await?)
+        {
+            values[index] = value;
+        }
+    }
+
+    Ok(checks
+        .iter()
+        .zip(&levels)
+        .zip(values)
+        .map(|((check, levels), value)| {
+            let result: Vec<u64> = redis::from_owned_redis_value(value)?;
+            let tracked_key = combine_tracked_keys(&check.tracked_keys);
+            into_headers(
+                &result,
+                levels,
+                &check.rule.algorithm,
+                tracked_key,
+                &check.route,
+            )
+        })
+        .collect())
+}
+
+/// Gives back up to `units` requests counted for the tracked key, and for the aggregates of the rule.
+/// Returns the number of requests given back to the tracked key.
+pub async fn refund_rate_limiting(
+    mut pool: ConnectionManager,
+    tracked_keys: &[String],
+    rule: &Rule,
+    limit: u64,
+    expiration: u64,
+    units: u64,
+) -> Result<u64, LimiterError> {
+    let script = REFUND_SCRIPTS.get(&rule.algorithm.to_string()).unwrap();
+    let levels = limit_levels(tracked_keys, rule, limit, expiration, GlobalLimit::get());
+
+    let mut invocation = script.prepare_invoke();
+    for (_, key, ..) in &levels {
+        invocation.key(key);
+    }
+    invocation.arg(refund_args(&levels, units));
+    Ok(invocation.invoke_async(&mut pool).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm_tests::FakeRedis;
+
+    fn args_of(command: &redis::Cmd) -> Vec<String> {
+        command
+            .args_iter()
+            .map(|arg| match arg {
+                redis::Arg::Simple(arg) => String::from_utf8_lossy(arg).to_string(),
+                redis::Arg::Cursor => unreachable!("No cursor in scripts"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_checks_run_the_levels_scripts() {
+        let mut rule = Rule::new(
+            "/api".to_string(),
+            RateLimiterAlgorithms::TokenBucket,
+            2,
+            60,
+            LimiterTrackingType::IP,
+            None,
+            None,
+        );
+        rule.route_limit = Some(AggregateLimit {
+            limit: 3,
+            expiration: None,
         });
+        let checks: Vec<RateLimitCheck> = ["a", "a", "a", "b", "c"]
+            .iter()
+            .map(|key| RateLimitCheck {
+                tracked_keys: vec![key.to_string()],
+                rule: &rule,
+                limit: 2,
+                expiration: 60,
+                route: "/api".to_string(),
+            })
+            .collect();
+        let levels: Vec<_> = checks
+            .iter()
+            .map(|check| {
+                limit_levels(
+                    &check.tracked_keys,
+                    check.rule,
+                    check.limit,
+                    check.expiration,
+                    None,
+                )
+            })
+            .collect();
+
+        let redis = FakeRedis::new();
+        redis.set_time(1_700_000_000);
+        let script = SCRIPTS.get(&rule.algorithm.to_string()).unwrap();
+        let allowed: Vec<i64> = batch_pipeline(checks.iter().zip(&levels))
+            .cmd_iter()
+            .map(|command| {
+                let args = args_of(command);
+                assert_eq!(args[..2], ["EVALSHA", script.get_hash()]);
+                let (keys, args) = args[3..].split_at(args[2].parse().unwrap());
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                redis.eval(&rule.algorithm.get_levels_script(), &keys, args)[3]
+            })
+            .collect();
+
+        // Each key allows 2 requests, the route 3 over every key.
+        assert_eq!(allowed, [1, 1, 0, 1, 0]);
     }
 
-    Ok(headers)
+    /// Runs the levels script of the rule as `execute_rate_limiting` does, under the redis clock.
+    fn limit(
+        redis: &FakeRedis,
+        rule: &Rule,
+        levels: &[(LimitLevel, String, u64, u64)],
+        consume: bool,
+    ) -> Result<RateLimiterHeaders, LimiterError> {
+        let keys: Vec<&str> = levels.iter().map(|(_, key, ..)| key.as_str()).collect();
+        let result = redis.eval(
+            &rule.algorithm.get_levels_script(),
+            &keys,
+            &levels_args(levels, rule, consume),
+        );
+        let result: Vec<u64> = result.into_iter().map(|value| value as u64).collect();
+        into_headers(
+            &result,
+            levels,
+            &rule.algorithm,
+            "key".to_string(),
+            &rule.route,
+        )
+    }
+
+    fn rejected_level(result: Result<RateLimiterHeaders, LimiterError>) -> Option<LimitLevel> {
+        match result {
+            Err(LimiterError::RateLimitExceeded { level, .. }) => Some(level),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn shaped_requests_get_their_delay_from_the_script() {
+        let mut rule = Rule::new(
+            "/exports".to_string(),
+            RateLimiterAlgorithms::LeakyBucket,
+            10,
+            10,
+            LimiterTrackingType::IP,
+            None,
+            None,
+        );
+        rule.shaping = Some(Shaping {
+            max_wait: Some(2500),
+            hold: false,
+        });
+        let levels = limit_levels(&["key".to_string()], &rule, 10, 10, None);
+        let redis = FakeRedis::new();
+        redis.set_time(1_700_000_000);
+
+        let delays: Vec<_> = (0..3)
+            .map(|_| limit(&redis, &rule, &levels, true).unwrap().delay)
+            .collect();
+        assert_eq!(delays, [Some(0), Some(1000), Some(2000)]);
+        // The next request would wait 3 seconds, longer than max_wait.
+        let checked = limit(&redis, &rule, &levels, false);
+        assert_eq!(rejected_level(checked), Some(LimitLevel::Key));
+
+        // A second later, one request leaked out. Checks report the remaining requests as they stand.
+        redis.set_time(1_700_000_001);
+        let peeked = limit(&redis, &rule, &levels, false).unwrap();
+        assert_eq!((peeked.remaining, peeked.delay), (8, Some(2000)));
+        assert_eq!(
+            limit(&redis, &rule, &levels, true).unwrap().delay,
+            Some(2000)
+        );
+    }
+
+    #[test]
+    fn requests_are_rejected_by_the_level_over_its_limit() {
+        let mut rule = Rule::new(
+            "/api".to_string(),
+            RateLimiterAlgorithms::SlidingWindowCounter,
+            5,
+            60,
+            LimiterTrackingType::IP,
+            None,
+            None,
+        );
+        rule.route_limit = Some(AggregateLimit {
+            limit: 2,
+            expiration: None,
+        });
+        rule.global_limit = Some(true);
+        let global_limit = GlobalLimit {
+            limit: 4,
+            expiration: 60,
+        };
+        let levels = |key: &str| limit_levels(&[key.to_string()], &rule, 5, 60, Some(global_limit));
+        // The global window is shared with the other rules.
+        let other_rule = levels("other").split_off(2);
+        let redis = FakeRedis::new();
+        redis.set_time(1_700_000_000);
+
+        assert!(limit(&redis, &rule, &other_rule, true).is_ok());
+        for key in ["a", "b"] {
+            assert!(limit(&redis, &rule, &levels(key), true).is_ok());
+        }
+        let rejected = limit(&redis, &rule, &levels("c"), true);
+        assert_eq!(rejected_level(rejected), Some(LimitLevel::Route));
+
+        // Refunding a gives back its request to the route and global levels as well.
+        let a_levels = levels("a");
+        let refund = rule.algorithm.get_refund_levels_script();
+        let keys: Vec<&str> = a_levels.iter().map(|(_, key, ..)| key.as_str()).collect();
+        assert_eq!(redis.eval(&refund, &keys, &refund_args(&a_levels, 2)), [1]);
+        assert!(limit(&redis, &rule, &levels("c"), true).is_ok());
+
+        assert!(limit(&redis, &rule, &other_rule, true).is_ok());
+        let rejected = limit(&redis, &rule, &other_rule, true);
+        assert_eq!(rejected_level(rejected), Some(LimitLevel::Global));
+    }
+
+    #[test]
+    fn held_shaping_requires_a_bounded_wait() {
+        let shaping = |max_wait, hold| Shaping { max_wait, hold };
+        assert!(shaping(None, false).validate("/api").is_ok());
+        assert!(shaping(Some(MAX_HOLD_WAIT), true).validate("/api").is_ok());
+        assert!(shaping(Some(0), false).validate("/api").is_err());
+        assert!(shaping(None, true).validate("/api").is_err());
+        assert!(
+            shaping(Some(MAX_HOLD_WAIT + 1), true)
+                .validate("/api")
+                .is_err()
+        );
+    }
 }
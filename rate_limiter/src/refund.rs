@@ -0,0 +1,163 @@
+use anyhow::anyhow;
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{Request, Response, header::CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+use crate::{
+    errors::LimiterError, rate_limiter::refund_rate_limiting, schedules::active_schedule,
+    server_state::States,
+};
+
+/// Admin endpoint giving back requests counted for a key, ex: when the backend failed them.
+pub const REFUND_PATH: &str = "/refund";
+const MAX_REFUND_BODY_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+struct RefundRequest {
+    #[serde(default)]
+    rule_id: Option<String>, // Rule to refund, the most specific rule of `route` otherwise
+    #[serde(default)]
+    route: Option<String>, // Path the request was sent to
+    key: String, // Tracked key, components of composite keys joined as they are by the limiter
+    #[serde(default = "default_units")]
+    units: u64,
+}
+
+fn default_units() -> u64 {
+    1
+}
+
+#[derive(Serialize)]
+struct RefundResponse<'a> {
+    rule_id: &'a str,
+    key: &'a str,
+    refunded: u64, // May be lower than the requested units, nothing is refunded beyond what was counted
+}
+
+pub async fn refund_handler(
+    states: Arc<States>,
+    request: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, LimiterError> {
+    match refund(states, request).await {
+        Ok(response) => Ok(response),
+        Err(err) => Ok(err.into_hyper_response()),
+    }
+}
+
+fn parse_refund(body: &[u8]) -> Result<RefundRequest, LimiterError> {
+    let refund: RefundRequest = serde_json::from_slice(body)
+        .map_err(|err| LimiterError::InvalidRequest(format!("Invalid refund: {err}")))?;
+    if refund.units == 0 {
+        return Err(LimiterError::InvalidRequest(
+            "Refunded units must be positive".to_string(),
+        ));
+    }
+    if refund.rule_id.is_none() && refund.route.is_none() {
+        return Err(LimiterError::InvalidRequest(
+            "Either a rule_id or a route is required".to_string(),
+        ));
+    }
+    Ok(refund)
+}
+
+/// Gives back requests of a key to its rule, and to the route and global aggregates of the rule.
+/// The limits of the rule, of its active schedule and of the key override are used to size the
+/// buckets. Tiers, which depend on the request, are ignored.
+async fn refund(
+    states: Arc<States>,
+    request: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, LimiterError> {
+    let body = Limited::new(request.into_body(), MAX_REFUND_BODY_SIZE)
+        .collect()
+        .await
+        .map_err(|err| LimiterError::InvalidRequest(err.to_string()))?
+        .to_bytes();
+    let refund = parse_refund(&body)?;
+
+    let rule_id = states.resolve_rule_id(refund.rule_id.as_deref(), refund.route.as_deref())?;
+    let loaded_rule = states
+        .rules
+        .read()
+        .get(&rule_id)
+        .cloned()
+        .ok_or_else(|| LimiterError::InvalidRequest(format!("No rule with id {rule_id}")))?;
+    let rule = &loaded_rule.rule;
+
+    let mut limit = rule.limit as u64;
+    let mut expiration = rule.expiration as u64;
+    let schedules = rule.schedules.as_deref().unwrap_or_default();
+    if let Some((schedule, _)) = active_schedule(schedules, Utc::now()) {
+        (limit, expiration) = schedule.apply(limit, expiration, rule.limit);
+    }
+    if let Some((override_limit, override_expiration)) = states
+        .key_override(&rule.id, &refund.key)
+        .and_then(|key_override| key_override.apply(limit, expiration))
+    {
+        (limit, expiration) = (override_limit, override_expiration);
+    }
+
+    let refunded = refund_rate_limiting(
+        states.pool.clone(),
+        std::slice::from_ref(&refund.key),
+        rule,
+        limit,
+        expiration,
+        refund.units,
+    )
+    .await?;
+    tracing::info!(
+        "{refunded} of {} requests refunded to {} on rule {}.",
+        refund.units,
+        refund.key,
+        rule.id
+    );
+
+    let body = serde_json::to_string(&RefundResponse {
+        rule_id: &rule.id,
+        key: &refund.key,
+        refunded,
+    })
+    .map_err(|err| LimiterError::Unknown(anyhow!("Unable to serialize refund: {err}")))?;
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|_err| LimiterError::Unknown(anyhow!("Unable to build response")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refunds_are_validated() {
+        let refund = parse_refund(br#"{"route": "/api/v1/orders", "key": "acme"}"#).unwrap();
+        assert_eq!(refund.route.as_deref(), Some("/api/v1/orders"));
+        assert_eq!((refund.key.as_str(), refund.units), ("acme", 1));
+
+        let refund = parse_refund(br#"{"rule_id": "orders", "key": "acme", "units": 3}"#).unwrap();
+        assert_eq!(
+            (refund.rule_id.as_deref(), refund.units),
+            (Some("orders"), 3)
+        );
+
+        for invalid in [
+            r#"{"route": "/api/v1/orders", "key": "acme", "units": 0}"#,
+            r#"{"route": "/api/v1/orders", "key": "acme", "units": -1}"#,
+            r#"{"route": "/api/v1/orders"}"#,
+            r#"{"key": "acme"}"#,
+            "acme",
+        ] {
+            assert!(
+                matches!(
+                    parse_refund(invalid.as_bytes()),
+                    Err(LimiterError::InvalidRequest(_))
+                ),
+                "{invalid}"
+            );
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use anyhow::Context;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    Response, StatusCode,
+    header::{CONTENT_TYPE, HeaderName, HeaderValue, RETRY_AFTER},
+};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+use crate::rate_limiter::RateLimiterHeaders;
+
+/// Response returned instead of the default `429` when a rule rejects a request.
+/// The body and the extra headers may contain the `{limit}`, `{remaining}`, `{reset}`,
+/// `{route}` and `{key}` placeholders.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RejectionResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>, // 429 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<BTreeMap<String, String>>,
+}
+
+impl RejectionResponse {
+    pub fn validate(&self, route: &str) -> anyhow::Result<()> {
+        if let Some(status) = self.status {
+            StatusCode::from_u16(status)
+                .with_context(|| format!("Invalid rejection status {status}. Route: {route}"))?;
+            // Callers would read any other status as the request being allowed.
+            if status < 400 {
+                anyhow::bail!("Rejection status {status} must be an error. Route: {route}");
+            }
+        }
+        let content_type = self
+            .content_type
+            .iter()
+            .map(|value| (CONTENT_TYPE.as_str(), value));
+        let headers = self.headers.iter().flatten();
+        for (name, value) in content_type.chain(headers.map(|(n, v)| (n.as_str(), v))) {
+            HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Invalid rejection header {name}. Route: {route}"))?;
+            HeaderValue::from_str(value).with_context(|| {
+                format!("Invalid rejection header value {value}. Route: {route}")
+            })?;
+        }
+        Ok(())
+    }
+
+    fn is_json(&self) -> bool {
+        self.content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.contains("json"))
+    }
+
+    fn render(
+        &self,
+        template: &str,
+        headers: &RateLimiterHeaders,
+        route: &str,
+        key: &str,
+    ) -> String {
+        // Values are escaped so that they can't break out of a JSON string.
+        let escape = |value: &str| match self.is_json() {
+            true => serde_json::to_string(value)
+                .unwrap_or_default()
+                .trim_matches('"')
+                .to_string(),
+            false => value.to_string(),
+        };
+        template
+            .replace("{limit}", &headers.limit.to_string())
+            .replace("{remaining}", &headers.remaining.to_string())
+            .replace("{reset}", &headers.reset.to_string())
+            .replace("{route}", &escape(route))
+            .replace("{key}", &escape(key))
+    }
+
+    pub fn to_response(
+        &self,
+        headers: &RateLimiterHeaders,
+        route: &str,
+        key: &str,
+    ) -> Response<Full<Bytes>> {
+        let status = self
+            .status
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or(StatusCode::TOO_MANY_REQUESTS);
+        let body = self
+            .body
+            .as_deref()
+            .map(|body| self.render(body, headers, route, key))
+            .unwrap_or("Rate limit exceeded!".to_string());
+
+        let mut response = Response::builder()
+            .status(status)
+            .header("limit", headers.limit)
+            .header("remaining", headers.remaining)
+            .header("reset", headers.reset)
+            .header("policy", headers.policy.clone())
+            .body(Full::new(Bytes::from(body)))
+            .unwrap();
+
+        let content_type = self
+            .content_type
+            .iter()
+            .map(|value| (CONTENT_TYPE.as_str(), value.clone()));
+        let extra_headers = self
+            .headers
+            .iter()
+            .flatten()
+            .map(|(name, value)| (name.as_str(), self.render(value, headers, route, key)));
+        for (name, value) in content_type.chain(extra_headers) {
+            if let Some((name, value)) = header(name, &value) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+        response
+    }
+
+    /// Response to a banned key, with the remaining ban as `{reset}` and `retry-after`.
+    pub fn to_ban_response(
+        &self,
+        headers: &RateLimiterHeaders,
+        route: &str,
+        key: &str,
+    ) -> Response<Full<Bytes>> {
+        let mut response = self.to_response(headers, route, key);
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(headers.reset));
+        response
+    }
+}
+
+/// Header rendered from the configuration, skipped if a placeholder made it invalid.
+fn header(name: &str, value: &str) -> Option<(HeaderName, HeaderValue)> {
+    let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+    let value = HeaderValue::from_str(value)
+        .inspect_err(|_| tracing::warn!("Invalid value for rejection header {name}: {value}"))
+        .ok()?;
+    Some((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn placeholders_are_rendered_and_escaped_in_json() {
+        let rejection = RejectionResponse {
+            status: Some(503),
+            content_type: Some("application/json".to_string()),
+            body: Some(
+                r#"{"error": {"code": "rate_limited", "key": "{key}", "retry_in": {reset}}}"#
+                    .to_string(),
+            ),
+            headers: Some(BTreeMap::from([(
+                "x-retry-in".to_string(),
+                "{reset}s".to_string(),
+            )])),
+        };
+        let headers = RateLimiterHeaders::new(10, 0, 42, "fw".to_string());
+
+        let response = rejection.to_response(&headers, "/api", r#"k"ey"#);
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()["x-retry-in"], "42s");
+        assert_eq!(response.headers()["limit"], "10");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["key"], r#"k"ey"#);
+        assert_eq!(body["error"]["retry_in"], 42);
+    }
+
+    #[tokio::test]
+    async fn banned_keys_get_the_rule_envelope() {
+        let rejection = RejectionResponse {
+            status: None,
+            content_type: Some("application/json".to_string()),
+            body: Some(r#"{"error": "banned", "retry_in": {reset}}"#.to_string()),
+            headers: None,
+        };
+        let headers = RateLimiterHeaders::new(10, 0, 300, "fw".to_string());
+
+        let response = rejection.to_ban_response(&headers, "/api", "key");
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "300");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["retry_in"], 300);
+    }
+
+    #[test]
+    fn rejection_statuses_must_be_errors() {
+        let rejection = |status| RejectionResponse {
+            status: Some(status),
+            content_type: None,
+            body: None,
+            headers: None,
+        };
+
+        assert!(rejection(503).validate("/api").is_ok());
+        assert!(rejection(400).validate("/api").is_ok());
+        assert!(rejection(200).validate("/api").is_err());
+        assert!(rejection(302).validate("/api").is_err());
+    }
+}
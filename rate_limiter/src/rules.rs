@@ -1,10 +1,47 @@
+use anyhow::Context;
 use opentelemetry::KeyValue;
 use redis::Connection;
 use serde::{Deserialize, Serialize, de};
-use std::collections::HashMap;
+use serde_json::{Value, json};
+use std::{collections::HashMap, sync::Arc};
 use uuid::Uuid;
 
-use crate::rate_limiter::{LimiterTrackingType, RateLimiterAlgorithms};
+use crate::adaptive::Adaptive;
+use crate::ip_filter::IpFilter;
+use crate::penalty::Penalty;
+use crate::rate_limiter::{
+    AggregateLimit, JwtTracking, LimiterTrackingType, MissingComponentBehaviour,
+    RateLimiterAlgorithms, Shaping, TrackingComponent,
+};
+use crate::rejections::RejectionResponse;
+use crate::schedules::Schedule;
+use crate::tiers::Tiers;
+use crate::utils::RoutePattern;
+
+/// Route of the rule applied to the requests matching no other rule.
+pub const DEFAULT_ROUTE: &str = "*";
+
+/// What happens to the requests matching no route, configured with `RL_UNMATCHED_ROUTES`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnmatchedRoutes {
+    #[default]
+    Allow, // Allowed without being rate limited
+    Reject,  // Rejected with a 404
+    Default, // Rate limited by the rule whose route is `DEFAULT_ROUTE`, allowed if there is none
+}
+
+impl UnmatchedRoutes {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("RL_UNMATCHED_ROUTES").as_deref() {
+            Err(_) | Ok("allow") => Ok(UnmatchedRoutes::Allow),
+            Ok("reject") => Ok(UnmatchedRoutes::Reject),
+            Ok("default") => Ok(UnmatchedRoutes::Default),
+            Ok(value) => anyhow::bail!(
+                "Invalid RL_UNMATCHED_ROUTES {value}, expected allow, reject or default."
+            ),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Rule {
@@ -17,6 +54,34 @@ pub struct Rule {
     pub custom_tracking_key: Option<String>,
     #[serde(deserialize_with = "redis_deserialize_bool")]
     pub active: Option<bool>,
+    #[serde(default)]
+    pub tracking_components: Option<Vec<TrackingComponent>>, // Parts of a composite tracking key
+    #[serde(default)]
+    pub on_missing_component: Option<MissingComponentBehaviour>,
+    #[serde(default)]
+    pub jwt: Option<JwtTracking>, // Options of jwt_claim tracked keys
+    #[serde(default)]
+    pub tiers: Option<Tiers>, // Limits depending on the plan of the client, override `limit`
+    #[serde(default)]
+    pub allowed_cidrs: Option<Vec<String>>, // Addresses bypassing rate limiting
+    #[serde(default)]
+    pub denied_cidrs: Option<Vec<String>>, // Addresses always rejected
+    #[serde(default)]
+    pub penalty: Option<Penalty>, // Ban of keys rejected too often
+    #[serde(default)]
+    pub route_limit: Option<AggregateLimit>, // Shared by every key of the route
+    #[serde(default)]
+    pub global_limit: Option<bool>, // Counted against the global limit set with RL_GLOBAL_LIMIT when true
+    #[serde(default)]
+    pub priority: Option<i32>, // Precedence among the rules matching a request, the highest wins. 0 by default
+    #[serde(default)]
+    pub schedules: Option<Vec<Schedule>>, // Time ranges changing the limits of the rule, the first matching applies
+    #[serde(default)]
+    pub rejection: Option<RejectionResponse>, // Response returned instead of the default 429
+    #[serde(default)]
+    pub shaping: Option<Shaping>, // Leaky bucket only, requests are delayed instead of rejected
+    #[serde(default)]
+    pub adaptive: Option<Adaptive>, // Limit scaled by the health of the backend
 }
 
 impl Rule {
@@ -29,17 +94,40 @@ impl Rule {
         custom_tracking_key: Option<String>,
         active: Option<bool>,
     ) -> Self {
-        if tracking_type.to_string() == "header"
+        Rule::with_id(
+            Uuid::new_v4().to_string(),
+            route,
+            algorithm,
+            limit,
+            expiration,
+            tracking_type,
+            custom_tracking_key,
+            active,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_id(
+        id: String,
+        route: String,
+        algorithm: RateLimiterAlgorithms,
+        limit: i32,
+        expiration: i32,
+        tracking_type: LimiterTrackingType,
+        custom_tracking_key: Option<String>,
+        active: Option<bool>,
+    ) -> Self {
+        if tracking_type.requires_custom_key()
             && (custom_tracking_key.is_none() || custom_tracking_key.clone().unwrap().is_empty())
         {
             panic!(
-                "Custom tracking key is required when tracking type is header. Route: {}",
-                route
+                "Custom tracking key is required when tracking type is {}. Route: {}",
+                tracking_type, route
             );
         }
 
         Rule {
-            id: Uuid::new_v4().to_string(),
+            id,
             route,
             algorithm,
             limit,
@@ -47,7 +135,192 @@ impl Rule {
             tracking_type,
             custom_tracking_key,
             active: active.or(Some(true)),
+            tracking_components: None,
+            on_missing_component: None,
+            jwt: None,
+            tiers: None,
+            allowed_cidrs: None,
+            denied_cidrs: None,
+            penalty: None,
+            route_limit: None,
+            global_limit: None,
+            priority: None,
+            schedules: None,
+            rejection: None,
+            shaping: None,
+            adaptive: None,
+        }
+    }
+
+    pub fn ip_filter(&self) -> anyhow::Result<IpFilter> {
+        IpFilter::new(
+            self.allowed_cidrs.as_deref().unwrap_or_default(),
+            self.denied_cidrs.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// Checks the parts of the rule that can't be enforced while deserializing it.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let components = self.tracking_components.as_deref().unwrap_or_default();
+        match self.tracking_type {
+            LimiterTrackingType::Composite if components.is_empty() => anyhow::bail!(
+                "Tracking components are required when tracking type is composite. Route: {}",
+                self.route
+            ),
+            LimiterTrackingType::Composite => {}
+            _ if !components.is_empty() => anyhow::bail!(
+                "Tracking components are only allowed when tracking type is composite. Route: {}",
+                self.route
+            ),
+            _ => {}
+        }
+
+        for component in components {
+            let custom_key = component.custom_tracking_key.as_deref().unwrap_or_default();
+            match &component.tracking_type {
+                LimiterTrackingType::Composite => anyhow::bail!(
+                    "Tracking components can't be composite themselves. Route: {}",
+                    self.route
+                ),
+                tracking_type if tracking_type.requires_custom_key() && custom_key.is_empty() => {
+                    anyhow::bail!(
+                        "Custom tracking key is required for {} tracking components. Route: {}",
+                        tracking_type,
+                        self.route
+                    )
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(fallback) = self.jwt.as_ref().and_then(|jwt| jwt.fallback.as_ref()) {
+            match &fallback.tracking_type {
+                LimiterTrackingType::JwtClaim | LimiterTrackingType::Composite => anyhow::bail!(
+                    "JWT fallback can't be tracked by {}. Route: {}",
+                    fallback.tracking_type,
+                    self.route
+                ),
+                tracking_type
+                    if tracking_type.requires_custom_key()
+                        && fallback
+                            .custom_tracking_key
+                            .as_deref()
+                            .unwrap_or_default()
+                            .is_empty() =>
+                {
+                    anyhow::bail!(
+                        "Custom tracking key is required for the {} JWT fallback. Route: {}",
+                        tracking_type,
+                        self.route
+                    )
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(tiers) = &self.tiers {
+            tiers.validate(&self.route)?;
+        }
+
+        if let Some(route_limit) = &self.route_limit
+            && (route_limit.limit <= 0 || route_limit.expiration.is_some_and(|e| e <= 0))
+        {
+            anyhow::bail!(
+                "The route limit and expiration must be positive. Route: {}",
+                self.route
+            );
+        }
+
+        for schedule in self.schedules.iter().flatten() {
+            schedule.validate(&self.route)?;
+        }
+
+        if let Some(rejection) = &self.rejection {
+            rejection.validate(&self.route)?;
         }
+
+        if let Some(penalty) = &self.penalty {
+            penalty.validate(&self.route)?;
+        }
+
+        if let Some(adaptive) = &self.adaptive {
+            adaptive.validate(&self.route)?;
+        }
+
+        if let Some(shaping) = &self.shaping {
+            if !matches!(self.algorithm, RateLimiterAlgorithms::LeakyBucket) {
+                anyhow::bail!(
+                    "Shaping is only supported by the leaky bucket. Route: {}",
+                    self.route
+                );
+            }
+            shaping.validate(&self.route)?;
+        }
+
+        self.ip_filter()
+            .with_context(|| format!("Invalid IP filter. Route: {}", self.route))?;
+
+        let pattern = RoutePattern::parse(&self.route)?;
+
+        // Path parameters must be declared by the route, ex: {id} or {*path}, or be a named group of its regex
+        let path_params = std::iter::once((&self.tracking_type, &self.custom_tracking_key)).chain(
+            components
+                .iter()
+                .map(|component| (&component.tracking_type, &component.custom_tracking_key)),
+        );
+        for (tracking_type, custom_key) in path_params {
+            let name = custom_key.as_deref().unwrap_or_default();
+            if matches!(tracking_type, LimiterTrackingType::PathParam) && !pattern.has_param(name) {
+                anyhow::bail!(
+                    "Path parameter {} is not part of the route {}",
+                    name,
+                    self.route
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// JSON document of the rule as it is stored under `rules` in redis.
+    pub fn to_redis_json(&self) -> Value {
+        let mut document = json!(
+            {
+                "id": self.id,
+                "route": self.route,
+                "algorithm": self.algorithm.to_string(),
+                "tracking_type": self.tracking_type.to_string(),
+                "limit": self.limit,
+                "expiration": self.expiration,
+                "custom_tracking_key": self.custom_tracking_key.clone().unwrap_or_default(),
+                "active": self.active.unwrap_or(true).to_string()
+            }
+        );
+
+        // Only stored when set, so that rules not using them are left untouched.
+        let optional_fields = [
+            ("tracking_components", json!(self.tracking_components)),
+            ("on_missing_component", json!(self.on_missing_component)),
+            ("jwt", json!(self.jwt)),
+            ("tiers", json!(self.tiers)),
+            ("allowed_cidrs", json!(self.allowed_cidrs)),
+            ("denied_cidrs", json!(self.denied_cidrs)),
+            ("penalty", json!(self.penalty)),
+            ("route_limit", json!(self.route_limit)),
+            ("global_limit", json!(self.global_limit)),
+            ("priority", json!(self.priority)),
+            ("schedules", json!(self.schedules)),
+            ("rejection", json!(self.rejection)),
+            ("shaping", json!(self.shaping)),
+            ("adaptive", json!(self.adaptive)),
+        ];
+        for (field, value) in optional_fields {
+            if !value.is_null() {
+                document[field] = value;
+            }
+        }
+
+        document
     }
 }
 
@@ -55,6 +328,49 @@ impl Rule {
 pub struct MinimalRule {
     pub id: String,
     pub route: String,
+    #[serde(default)]
+    pub priority: Option<i32>,
+}
+
+impl From<&Rule> for MinimalRule {
+    fn from(rule: &Rule) -> Self {
+        MinimalRule {
+            id: rule.id.clone(),
+            route: rule.route.clone(),
+            priority: rule.priority,
+        }
+    }
+}
+
+/// A rule along with the parts of it parsed once, when the rules are loaded.
+#[derive(Debug)]
+pub struct LoadedRule {
+    pub rule: Rule,
+    pub ip_filter: IpFilter,
+}
+
+/// Rules of the rate limiter, indexed by id.
+pub type LoadedRules = HashMap<String, Arc<LoadedRule>>;
+
+impl LoadedRule {
+    pub fn new(rule: Rule) -> anyhow::Result<Self> {
+        let ip_filter = rule.ip_filter()?;
+        Ok(LoadedRule { rule, ip_filter })
+    }
+}
+
+/// Prepares the rules read from redis, the invalid ones are left out.
+pub fn load_rules(rules: HashMap<String, Rule>) -> LoadedRules {
+    rules
+        .into_iter()
+        .filter_map(|(id, rule)| match LoadedRule::new(rule) {
+            Ok(loaded) => Some((id, Arc::new(loaded))),
+            Err(err) => {
+                tracing::warn!("Ignoring rule {id}: {err}");
+                None
+            }
+        })
+        .collect()
 }
 
 fn redis_deserialize_bool<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
@@ -91,18 +407,15 @@ pub fn get_rules_route_and_id(
     let rules: HashMap<String, Vec<String>> =
         serde_json::from_str(&response).expect("Failed to parse rules into valid JSON.");
 
-    let length = rules
-        .get(&"$..route".to_string())
-        .expect("Route keys not found")
-        .len();
+    let length = rules.get("$..route").expect("Route keys not found").len();
     tracing::debug!("length of rules keys: {}", length);
 
     let mut route_to_id: HashMap<String, String> = HashMap::new();
     for i in 0..length {
-        let route = rules.get(&"$..route".to_string()).unwrap().get(i).unwrap();
+        let route = rules.get("$..route").unwrap().get(i).unwrap();
 
         let id = rules
-            .get(&"$..id".to_string())
+            .get("$..id")
             .expect("Failed to get id key")
             .get(i)
             .unwrap_or_else(|| panic!("Failed to get id key at index {}", i));
@@ -113,6 +426,23 @@ pub fn get_rules_route_and_id(
     Ok(route_to_id)
 }
 
+/// Retrieves the raw JSON documents of every rule currently stored in redis, indexed by id.
+pub fn get_rules_documents(
+    connection: &mut Connection,
+) -> Result<HashMap<String, Value>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let maybe_response: Option<String> = redis::cmd("JSON.GET")
+        .arg("rules")
+        .arg("$")
+        .query(connection)?;
+
+    let Some(response) = maybe_response else {
+        return Ok(HashMap::default());
+    };
+
+    let documents: Vec<HashMap<String, Value>> = serde_json::from_str(&response)?;
+    Ok(documents.into_iter().next().unwrap_or_default())
+}
+
 impl From<Rule> for Vec<KeyValue> {
     fn from(value: Rule) -> Self {
         vec![
@@ -0,0 +1,234 @@
+use anyhow::Context;
+use chrono::{DateTime, Datelike, NaiveTime, TimeDelta, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Time range during which the limits of a rule change, ex: business hours or nights.
+/// `start` and `end` are local `HH:MM` times. A range ending before it starts spans midnight
+/// and belongs to the day it starts on. Times and timezones are parsed when the rules are loaded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>, // Every day when empty
+    #[serde(with = "hours_minutes")]
+    pub start: NaiveTime,
+    #[serde(with = "hours_minutes")]
+    pub end: NaiveTime,
+    #[serde(default, with = "timezone", skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<Tz>, // IANA name, ex: Europe/Paris. UTC by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i32>, // Relative to the rule limit, see `Schedule::apply`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<i32>,
+    #[serde(default)]
+    pub closed: bool, // Every request is rejected during the range
+}
+
+fn parse_time(time: &str) -> anyhow::Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .with_context(|| format!("Invalid time {time}, expected HH:MM"))
+}
+
+/// `HH:MM` times.
+mod hours_minutes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&time.format("%H:%M"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let time = String::deserialize(deserializer)?;
+        parse_time(&time).map_err(serde::de::Error::custom)
+    }
+}
+
+/// IANA timezone names.
+mod timezone {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        timezone: &Option<Tz>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match timezone {
+            Some(timezone) => serializer.serialize_some(timezone.name()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Tz>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|timezone| {
+                timezone
+                    .parse()
+                    .map_err(|err| serde::de::Error::custom(format!("Invalid timezone: {err}")))
+            })
+            .transpose()
+    }
+}
+
+impl Schedule {
+    pub fn validate(&self, route: &str) -> anyhow::Result<()> {
+        if self.start == self.end {
+            anyhow::bail!("A schedule can't start and end at the same time. Route: {route}");
+        }
+        if self.limit.is_some_and(|limit| limit <= 0)
+            || self.expiration.is_some_and(|expiration| expiration <= 0)
+        {
+            anyhow::bail!("Schedule limit and expiration must be positive. Route: {route}");
+        }
+        Ok(())
+    }
+
+    /// Limit and expiration during the range, from the ones resolved for the key.
+    /// The schedule limit is relative to the rule one: the resolved limit, ex: the one of the key
+    /// tier, is scaled by `limit / rule_limit` so that tiers keep their proportions.
+    /// The schedule expiration replaces the resolved one.
+    pub fn apply(&self, limit: u64, expiration: u64, rule_limit: i32) -> (u64, u64) {
+        let limit = match self.limit {
+            Some(schedule_limit) => {
+                (limit * schedule_limit as u64 / rule_limit.max(1) as u64).max(1)
+            }
+            None => limit,
+        };
+        let expiration = self
+            .expiration
+            .map_or(expiration, |expiration| expiration as u64);
+        (limit, expiration)
+    }
+
+    /// Seconds until the end of the range if `now` falls into it.
+    pub fn remaining(&self, now: DateTime<Utc>) -> Option<u64> {
+        let (start, end) = (self.start, self.end);
+        let local = now.with_timezone(&self.timezone.unwrap_or(Tz::UTC));
+        let time = local.time();
+
+        let (day, until_end) = if start < end {
+            if time < start || time >= end {
+                return None;
+            }
+            (local.weekday(), end - time)
+        } else if time >= start {
+            (local.weekday(), end - time + TimeDelta::days(1))
+        } else if time < end {
+            // Still in the range started the day before.
+            (local.weekday().pred(), end - time)
+        } else {
+            return None;
+        };
+
+        if !self.days.is_empty() && !self.days.contains(&day) {
+            return None;
+        }
+        Some(until_end.num_seconds() as u64)
+    }
+}
+
+/// First schedule `now` falls into, and the seconds until it ends.
+pub fn active_schedule(schedules: &[Schedule], now: DateTime<Utc>) -> Option<(&Schedule, u64)> {
+    schedules.iter().find_map(|schedule| {
+        schedule
+            .remaining(now)
+            .map(|remaining| (schedule, remaining))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(days: &[Weekday], start: &str, end: &str, timezone: &str) -> Schedule {
+        Schedule {
+            days: days.to_vec(),
+            start: parse_time(start).unwrap(),
+            end: parse_time(end).unwrap(),
+            timezone: Some(timezone.parse().unwrap()),
+            limit: None,
+            expiration: None,
+            closed: false,
+        }
+    }
+
+    fn at(datetime: &str) -> DateTime<Utc> {
+        datetime.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_follow_days_timezone_and_midnight() {
+        let business_hours = schedule(
+            &[
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+            "09:00",
+            "18:00",
+            "Europe/Paris",
+        );
+        // Friday 2026-03-13, Paris is UTC+1.
+        assert_eq!(
+            business_hours.remaining(at("2026-03-13T08:00:00Z")),
+            Some(9 * 3600)
+        );
+        assert_eq!(business_hours.remaining(at("2026-03-13T07:59:59Z")), None);
+        assert_eq!(business_hours.remaining(at("2026-03-13T17:00:00Z")), None);
+        assert_eq!(business_hours.remaining(at("2026-03-14T10:00:00Z")), None);
+
+        let friday_nights = schedule(&[Weekday::Fri], "22:00", "06:00", "UTC");
+        assert_eq!(
+            friday_nights.remaining(at("2026-03-13T23:00:00Z")),
+            Some(7 * 3600)
+        );
+        assert_eq!(
+            friday_nights.remaining(at("2026-03-14T05:00:00Z")),
+            Some(3600)
+        );
+        assert_eq!(friday_nights.remaining(at("2026-03-13T05:00:00Z")), None);
+        assert_eq!(friday_nights.remaining(at("2026-03-14T12:00:00Z")), None);
+    }
+
+    #[test]
+    fn schedule_limits_scale_the_tier_limits() {
+        let mut business_hours = schedule(&[], "09:00", "18:00", "UTC");
+        business_hours.limit = Some(10);
+
+        // The rule allows 100 requests, the schedule a tenth of it.
+        assert_eq!(business_hours.apply(100, 60, 100), (10, 60));
+        assert_eq!(business_hours.apply(5000, 60, 100), (500, 60));
+        assert_eq!(business_hours.apply(5, 60, 100), (1, 60));
+
+        business_hours.limit = None;
+        business_hours.expiration = Some(3600);
+        assert_eq!(business_hours.apply(5000, 60, 100), (5000, 3600));
+    }
+
+    #[test]
+    fn times_and_timezones_are_parsed_when_loaded() {
+        let parsed: Schedule = serde_json::from_str(
+            r#"{"days": ["mon"], "start": "22:30", "end": "06:00", "timezone": "Europe/Paris"}"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.start, NaiveTime::from_hms_opt(22, 30, 0).unwrap());
+        assert_eq!(parsed.timezone, Some(Tz::Europe__Paris));
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap(),
+            serde_json::json!({
+                "days": ["Mon"], "start": "22:30", "end": "06:00", "timezone": "Europe/Paris", "closed": false
+            })
+        );
+
+        for invalid in [
+            r#"{"start": "25:00", "end": "06:00"}"#,
+            r#"{"start": "9h", "end": "06:00"}"#,
+            r#"{"start": "22:00", "end": "06:00", "timezone": "Mars/Olympus"}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<Schedule>(invalid).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+}
@@ -1,16 +1,52 @@
 use crate::{
+    admin::AdminListener,
     handler::limiter_handler,
+    ip_filter::{IpFilter, TrustedProxies},
+    jwt::JwtVerifier,
+    overrides::{Overrides, get_overrides},
+    rate_limiter::{GlobalLimit, load_scripts},
+    rules::{LoadedRules, MinimalRule, UnmatchedRoutes, load_rules},
     server_state::States,
-    utils::{get_rules_from_redis, instantiate_matcher_with_rules},
+    tiers::TierCache,
+    utils::{RouteMatcher, RouteMatching, get_rules_by_id, instantiate_matcher_with_rules},
 };
 use hyper::{server::conn::http1, service::service_fn};
 use hyper_util::rt::TokioIo;
 use opentelemetry::global;
 use parking_lot::RwLock;
+use redis::aio::ConnectionManager;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 
+/// Reads the rules and their overrides from redis, and swaps them with the ones in use.
+async fn reload_rules(
+    connection: &mut ConnectionManager,
+    route_matcher: &RwLock<RouteMatcher>,
+    rules: &RwLock<LoadedRules>,
+    overrides: &RwLock<Overrides>,
+) -> usize {
+    let new_rules = get_rules_by_id(connection)
+        .await
+        .inspect_err(|err| tracing::error!("Failed to read the rules: {err:?}"))
+        .unwrap_or_default();
+    let new_overrides = get_overrides(connection, new_rules.keys())
+        .await
+        .unwrap_or_default();
+    let new_router = instantiate_matcher_with_rules(
+        new_rules
+            .iter()
+            .map(|(id, rule)| (id.clone(), MinimalRule::from(rule)))
+            .collect(),
+    );
+    let length = new_rules.len();
+
+    *rules.write() = load_rules(new_rules);
+    *overrides.write() = new_overrides;
+    *route_matcher.write() = new_router;
+    length
+}
+
 pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let redis_host = std::env::var("RL_REDIS_HOST").unwrap_or("localhost".to_string());
     let redis_port = std::env::var("RL_REDIS_PORT").unwrap_or("6379".to_string());
@@ -31,6 +67,15 @@ pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
         .with_description("Total number of requests rejected")
         .with_unit("requests")
         .build();
+    let rl_adaptive_multiplier = meter
+        .f64_gauge("rl_adaptive_multiplier")
+        .with_description("Multiplier applied to the limit of adaptive rules")
+        .build();
+    let rl_banned_requests = meter
+        .u64_counter("rl_banned_requests")
+        .with_description("Total number of requests rejected because of a penalty ban")
+        .with_unit("requests")
+        .build();
 
     tracing::info!("connecting to redis...");
     let client = redis::Client::open(format!(
@@ -52,35 +97,65 @@ pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
         "Subscribed to rl_update channel. Updates will trigger a rebuild of the matcher."
     );
 
-    let rules_config = get_rules_from_redis(&mut redis_connection)
-        .await
-        .unwrap_or_default();
-    let route_matcher = Arc::new(RwLock::new(instantiate_matcher_with_rules(rules_config))); // Initial instance of the matcher.
+    // Initial instance of the matcher, rules and overrides.
+    let route_matcher = Arc::new(RwLock::new(RouteMatcher::default()));
+    let rules = Arc::new(RwLock::new(LoadedRules::new()));
+    let overrides = Arc::new(RwLock::new(Overrides::new()));
+    reload_rules(&mut redis_connection, &route_matcher, &rules, &overrides).await;
+    load_scripts(&mut redis_connection).await?;
 
     {
         let route_matcher = route_matcher.clone();
+        let rules = rules.clone();
+        let overrides = overrides.clone();
         tokio::spawn(async move {
             while let Some(msg) = rx.recv().await {
                 tracing::info!("Event received: {msg:?}");
-                let new_rules = get_rules_from_redis(&mut con_for_task)
-                    .await
-                    .unwrap_or_default();
-                let length = new_rules.len();
-                let new_router = instantiate_matcher_with_rules(new_rules);
-                *route_matcher.write() = new_router;
+                let length =
+                    reload_rules(&mut con_for_task, &route_matcher, &rules, &overrides).await;
                 tracing::info!("Matcher has been rebuilt with {length} routes.");
             }
         });
     }
 
+    let jwt_verifier = JwtVerifier::from_env()?;
+    let ip_filter = IpFilter::from_env()?;
+    let trusted_proxies = TrustedProxies::from_env()?;
+    let unmatched_routes = UnmatchedRoutes::from_env()?;
+    let route_matching = RouteMatching::from_env()?;
+    let tier_cache = TierCache::from_env()?;
+    if let Some(global_limit) = GlobalLimit::from_env()? {
+        global_limit.init();
+    }
+    let admin_listener = AdminListener::from_env()?;
+
     let states = Arc::new(States {
         route_matcher: route_matcher.clone(),
+        rules,
+        overrides,
+        unmatched_routes,
+        route_matching,
         pool: redis_connection,
+        jwt_verifier,
+        ip_filter,
+        trusted_proxies,
+        tier_cache,
         rl_total_requests,
         rl_allowed_requests,
         rl_rejected_requests,
+        rl_banned_requests,
+        rl_adaptive_multiplier,
     });
 
+    {
+        let states = states.clone();
+        tokio::spawn(async move {
+            if let Err(err) = admin_listener.serve(states).await {
+                tracing::error!("Admin server stopped: {err}");
+            }
+        });
+    }
+
     tracing::info!("Starting server on port 3000");
 
     let addr: SocketAddr = ([0, 0, 0, 0], 3000).into();
@@ -97,7 +172,7 @@ pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
                     io,
                     service_fn(move |req| {
                         let states = states.clone();
-                        limiter_handler(states, req)
+                        async move { limiter_handler(states, req).await }
                     }),
                 )
                 .await;
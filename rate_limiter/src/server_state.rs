@@ -1,13 +1,84 @@
+use anyhow::anyhow;
+
 use std::sync::Arc;
 
-use opentelemetry::metrics::Counter;
+use opentelemetry::metrics::{Counter, Gauge};
 use parking_lot::RwLock;
 use redis::aio::ConnectionManager;
 
+use crate::{
+    errors::LimiterError,
+    ip_filter::{IpFilter, TrustedProxies},
+    jwt::JwtVerifier,
+    overrides::{KeyOverride, Overrides},
+    rules::{LoadedRule, LoadedRules, UnmatchedRoutes},
+    tiers::TierCache,
+    utils::{RouteMatcher, RouteMatching},
+};
+
 pub struct States {
-    pub route_matcher: Arc<RwLock<matchit::Router<String>>>,
+    pub route_matcher: Arc<RwLock<RouteMatcher>>,
+    pub rules: Arc<RwLock<LoadedRules>>,
+    pub overrides: Arc<RwLock<Overrides>>,
+    pub unmatched_routes: UnmatchedRoutes,
+    pub route_matching: RouteMatching,
     pub pool: ConnectionManager,
+    pub jwt_verifier: JwtVerifier,
+    pub ip_filter: IpFilter, // Global allow and deny lists
+    pub trusted_proxies: TrustedProxies,
+    pub tier_cache: TierCache,
     pub rl_total_requests: Counter<u64>,
     pub rl_allowed_requests: Counter<u64>,
     pub rl_rejected_requests: Counter<u64>,
+    pub rl_banned_requests: Counter<u64>,
+    pub rl_adaptive_multiplier: Gauge<f64>,
+}
+
+impl States {
+    /// The loaded rule with the given id.
+    pub fn rule(&self, rule_id: &str) -> Result<Arc<LoadedRule>, LimiterError> {
+        self.rules
+            .read()
+            .get(rule_id)
+            .cloned()
+            .ok_or_else(|| LimiterError::Unknown(anyhow!("No rule found for id {rule_id}")))
+    }
+
+    /// The override of a tracked key of the rule, if any.
+    pub fn key_override(&self, rule_id: &str, tracked_key: &str) -> Option<KeyOverride> {
+        self.overrides
+            .read()
+            .get(rule_id)
+            .and_then(|overrides| overrides.get(tracked_key))
+            .cloned()
+    }
+
+    /// Id of the most specific rule matching the path, or of the default rule when it applies.
+    pub fn most_specific_rule(&self, path: &str) -> Option<String> {
+        let route_matcher = self.route_matcher.read();
+        match route_matcher.most_specific(path) {
+            Some((rule_id, _)) => Some(rule_id),
+            None if self.unmatched_routes == UnmatchedRoutes::Default => {
+                route_matcher.default_rule.clone()
+            }
+            None => None,
+        }
+    }
+
+    /// Id of the rule targeted by an API call, given either directly or by a route.
+    pub fn resolve_rule_id(
+        &self,
+        rule_id: Option<&str>,
+        route: Option<&str>,
+    ) -> Result<String, LimiterError> {
+        match (rule_id, route) {
+            (Some(rule_id), _) => Ok(rule_id.to_string()),
+            (None, Some(route)) => self
+                .most_specific_rule(route)
+                .ok_or_else(|| LimiterError::NoRouteMatch(route.to_string())),
+            (None, None) => Err(LimiterError::InvalidRequest(
+                "Either a rule_id or a route is required".to_string(),
+            )),
+        }
+    }
 }
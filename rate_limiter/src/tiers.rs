@@ -0,0 +1,271 @@
+use anyhow::{Context, anyhow};
+use hyper::header::AUTHORIZATION;
+use redis::{AsyncCommands, aio::ConnectionManager};
+use serde::{Deserialize, Serialize};
+
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{
+    errors::LimiterError,
+    rules::Rule,
+    utils::{RequestParts, TtlCache, get_jwt_claim},
+};
+
+/// Hash mapping tracked keys to their tier, used by rules resolving tiers from redis.
+/// Ex: `HSET tiers <api key> enterprise`
+pub const TIERS_KEY: &str = "tiers";
+
+const DEFAULT_TIERS_CACHE_TTL: u64 = 30;
+const TIERS_CACHE_CAPACITY: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TierSource {
+    Header, // The tier is the value of the `key` header, only trustworthy behind a proxy setting it
+    JwtClaim, // The tier is the `key` claim of the verified bearer token
+    Redis,  // The tier of the tracked key is stored in the `tiers` hash
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TierLimit {
+    pub limit: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<i32>, // The rule expiration is used when not set
+}
+
+/// Limits of a rule depending on the plan of the client.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tiers {
+    pub source: TierSource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>, // Name of the header or claim holding the tier
+    pub default: String, // Tier applied when none or an unknown one is resolved
+    pub limits: BTreeMap<String, TierLimit>,
+}
+
+impl Tiers {
+    pub fn validate(&self, route: &str) -> anyhow::Result<()> {
+        if !self.limits.contains_key(&self.default) {
+            anyhow::bail!(
+                "Default tier {} has no limit. Route: {}",
+                self.default,
+                route
+            );
+        }
+        if !matches!(self.source, TierSource::Redis)
+            && self.key.as_deref().unwrap_or_default().is_empty()
+        {
+            anyhow::bail!(
+                "A tier key is required for {:?} tiers. Route: {}",
+                self.source,
+                route
+            );
+        }
+        if matches!(self.source, TierSource::Header) {
+            tracing::warn!(
+                "Tiers of route {} are read from the {} header, clients can send any tier unless a trusted proxy sets it.",
+                route,
+                self.key.as_deref().unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    /// The tier applied for the `requested` one, and its limit.
+    pub fn limit_of(&self, requested: Option<String>) -> Option<(String, TierLimit)> {
+        let tier = requested
+            .filter(|tier| self.limits.contains_key(tier))
+            .unwrap_or_else(|| self.default.clone());
+        let limit = self.limits.get(&tier)?.clone();
+        Some((tier, limit))
+    }
+}
+
+/// Tiers read from the `tiers` hash, kept for `RL_TIERS_CACHE_TTL` seconds (30 by default, 0 to
+/// disable the cache) so that tiered rules don't cost a round trip to redis on every request.
+pub struct TierCache(TtlCache<Option<String>>);
+
+impl TierCache {
+    pub fn new(ttl: Duration) -> Self {
+        TierCache(TtlCache::new(ttl, TIERS_CACHE_CAPACITY))
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let ttl = match std::env::var("RL_TIERS_CACHE_TTL") {
+            Ok(ttl) => ttl
+                .parse()
+                .with_context(|| format!("Invalid RL_TIERS_CACHE_TTL {ttl}"))?,
+            Err(_) => DEFAULT_TIERS_CACHE_TTL,
+        };
+        tracing::info!("Tiers read from redis are cached for {ttl}s.");
+        Ok(TierCache::new(Duration::from_secs(ttl)))
+    }
+
+    async fn get(
+        &self,
+        pool: &mut ConnectionManager,
+        tracked_key: &str,
+    ) -> Result<Option<String>, LimiterError> {
+        if let Some(tier) = self.0.get(tracked_key) {
+            return Ok(tier);
+        }
+        let tier: Option<String> = pool.hget(TIERS_KEY, tracked_key).await?;
+        self.0.insert(tracked_key, tier.clone());
+        Ok(tier)
+    }
+}
+
+/// The tier requested by the request itself, for the header and JWT claim sources.
+fn requested_tier(request: &RequestParts<'_>, rule: &Rule, tiers: &Tiers) -> Option<String> {
+    let key = tiers.key.as_deref().unwrap_or_default();
+    match tiers.source {
+        TierSource::Header => request
+            .headers
+            .get(key)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        TierSource::JwtClaim => {
+            let header = rule
+                .jwt
+                .as_ref()
+                .and_then(|jwt| jwt.header.as_deref())
+                .unwrap_or(AUTHORIZATION.as_str());
+            get_jwt_claim(request, header, key)
+                .inspect_err(|reason| tracing::debug!("Tier claim unavailable: {reason}"))
+                .ok()
+        }
+        TierSource::Redis => None,
+    }
+}
+
+/// Resolves the tier of a request and the limit that comes with it.
+/// Returns `None` when the rule has no tiers.
+pub async fn resolve_tier(
+    pool: &mut ConnectionManager,
+    cache: &TierCache,
+    request: &RequestParts<'_>,
+    rule: &Rule,
+    tracked_key: &str,
+) -> Result<Option<(String, TierLimit)>, LimiterError> {
+    let Some(tiers) = &rule.tiers else {
+        return Ok(None);
+    };
+
+    let requested = match tiers.source {
+        TierSource::Redis => cache.get(pool, tracked_key).await?,
+        _ => requested_tier(request, rule, tiers),
+    };
+
+    tiers
+        .limit_of(requested)
+        .map(Some)
+        .ok_or(LimiterError::Unknown(anyhow!(
+            "No limit for the default tier {} on rule {}",
+            tiers.default,
+            rule.id
+        )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{jwt::JwtVerifier, rate_limiter::LimiterTrackingType};
+    use hyper::HeaderMap;
+    use std::collections::HashMap;
+
+    fn tiered_rule(source: TierSource, key: &str) -> Rule {
+        Rule {
+            tiers: Some(Tiers {
+                source,
+                key: Some(key.to_string()),
+                default: "free".to_string(),
+                limits: BTreeMap::from([
+                    (
+                        "free".to_string(),
+                        TierLimit {
+                            limit: 10,
+                            expiration: None,
+                        },
+                    ),
+                    (
+                        "enterprise".to_string(),
+                        TierLimit {
+                            limit: 1000,
+                            expiration: Some(60),
+                        },
+                    ),
+                ]),
+            }),
+            ..Rule::new(
+                "/api/v1/search".to_string(),
+                crate::rate_limiter::RateLimiterAlgorithms::FixedWindow,
+                10,
+                60,
+                LimiterTrackingType::IP,
+                None,
+                None,
+            )
+        }
+    }
+
+    fn tier_of(rule: &Rule, headers: &HeaderMap, verifier: &JwtVerifier) -> (String, i32) {
+        let no_params = HashMap::new();
+        let request = RequestParts {
+            headers,
+            query: None,
+            path_params: &no_params,
+            jwt_verifier: verifier,
+            client_ip: None,
+        };
+        let tiers = rule.tiers.as_ref().unwrap();
+        let (tier, limit) = tiers
+            .limit_of(requested_tier(&request, rule, tiers))
+            .unwrap();
+        (tier, limit.limit)
+    }
+
+    #[test]
+    fn unknown_or_missing_tiers_fall_back_to_the_default() {
+        let rule = tiered_rule(TierSource::Header, "x-plan");
+        let verifier = JwtVerifier::default();
+        let mut headers = HeaderMap::new();
+
+        assert_eq!(
+            tier_of(&rule, &headers, &verifier),
+            ("free".to_string(), 10)
+        );
+        headers.insert("x-plan", "platinum".parse().unwrap());
+        assert_eq!(
+            tier_of(&rule, &headers, &verifier),
+            ("free".to_string(), 10)
+        );
+        headers.insert("x-plan", "enterprise".parse().unwrap());
+        assert_eq!(
+            tier_of(&rule, &headers, &verifier),
+            ("enterprise".to_string(), 1000)
+        );
+    }
+
+    #[test]
+    fn claim_tiers_require_a_verified_token() {
+        let rule = tiered_rule(TierSource::JwtClaim, "plan");
+        let verifier = JwtVerifier::new(&["secret".to_string()], None).unwrap();
+        let token = |secret: &str| {
+            let exp = chrono::Utc::now().timestamp() + 3600;
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &serde_json::json!({ "plan": "enterprise", "exp": exp }),
+                &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+            )
+            .unwrap()
+        };
+        let tier_with = |secret: &str| {
+            let mut headers = HeaderMap::new();
+            let authorization = format!("Bearer {}", token(secret));
+            headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+            tier_of(&rule, &headers, &verifier)
+        };
+
+        assert_eq!(tier_with("secret"), ("enterprise".to_string(), 1000));
+        assert_eq!(tier_with("forged"), ("free".to_string(), 10));
+    }
+}
@@ -1,34 +1,70 @@
 use anyhow::{Context, anyhow};
-use hyper::HeaderMap;
-use matchit::Router;
-use matchit::Router as MatchitRouter;
+use hyper::{
+    HeaderMap,
+    header::{AUTHORIZATION, COOKIE},
+};
+use lazy_static::lazy_static;
 use redis::{
     AsyncCommands, Commands, JsonAsyncCommands, RedisError, Script, aio::ConnectionManager,
 };
-use serde_json::json;
+use regex::Regex;
+
+use serde_json::Value;
 
-use std::collections::HashMap;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
 
 use crate::{
+    diff::RulesDiff,
     errors::{self, LimiterError},
-    rate_limiter::{LimiterTrackingType, RateLimiterAlgorithms},
-    rules::{MinimalRule, Rule},
+    history::RECORD_VERSION_SCRIPT,
+    jwt::JwtVerifier,
+    rate_limiter::{
+        JwtTracking, LimiterTrackingType, MissingComponentBehaviour, RateLimiterAlgorithms,
+        TrackingComponent,
+    },
+    rules::{DEFAULT_ROUTE, MinimalRule, Rule},
 };
 
+/// Combines the components of a tracked key into a single one.
+/// Components of composite keys are escaped so that one can't forge the separator of another.
+pub fn combine_tracked_keys(keys_tracked: &[String]) -> String {
+    match keys_tracked {
+        [key_tracked] => key_tracked.clone(),
+        _ => keys_tracked
+            .iter()
+            .map(|key| key.replace('%', "%25").replace('|', "%7C"))
+            .collect::<Vec<_>>()
+            .join("|"),
+    }
+}
+
 pub fn make_redis_key(
-    key_tracked: &str,
+    keys_tracked: &[String],
     hashed_route: &str,
     limit_algorithm: &RateLimiterAlgorithms,
 ) -> String {
-    // Ex : fixed_window : id of the matched route : key being tracked for rate limitation
+    // Ex : fixed_window : id of the matched route : key(s) being tracked for rate limitation
     format!(
         "{}:{}:{}",
-        limit_algorithm.to_string(),
+        limit_algorithm,
         hashed_route,
-        key_tracked
+        combine_tracked_keys(keys_tracked)
     )
 }
 
+pub fn make_route_redis_key(hashed_route: &str, limit_algorithm: &RateLimiterAlgorithms) -> String {
+    // Ex : route : fixed_window : id of the matched route
+    format!("route:{limit_algorithm}:{hashed_route}")
+}
+
+// Single key of the global limit, counted with a fixed window whatever the algorithm of the rules.
+pub const GLOBAL_REDIS_KEY: &str = "global";
+
 pub fn _populate_redis_kv_rule_algorithm(
     conn: &mut redis::Connection,
     rules: &Vec<Rule>,
@@ -76,135 +112,1057 @@ pub async fn _populate_redis_with_rules(
     Ok(())
 }
 
-const STANDARD_IP_HEADERS: [&str; 3] = ["x-forwarded-for", "x-real-ip", "forwarded"];
+/// Parts of a request a tracked key can be extracted from.
+pub struct RequestParts<'a> {
+    pub headers: &'a HeaderMap,
+    pub query: Option<&'a str>,
+    pub path_params: &'a HashMap<String, String>, // Parameters of the matched route
+    pub jwt_verifier: &'a JwtVerifier,
+    pub client_ip: Option<IpAddr>, // Resolved once behind the trusted proxies
+}
+
+/// Reads `claim` from the bearer token carried by `header`, once the token is verified.
+pub fn get_jwt_claim(request: &RequestParts, header: &str, claim: &str) -> Result<String, String> {
+    let value = request
+        .headers
+        .get(header)
+        .ok_or(format!("no {header} header"))?
+        .to_str()
+        .map_err(|err| err.to_string())?
+        .trim();
+    let token = match value.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+        _ => value,
+    };
+
+    let claims = request.jwt_verifier.verify(token)?;
+    match claims.get(claim) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(value @ (Value::Number(_) | Value::Bool(_))) => Ok(value.to_string()),
+        Some(_) => Err(format!("claim {claim} is not a scalar")),
+        None => Err(format!("no {claim} claim in token")),
+    }
+}
+
+fn get_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
 
-/// Retrieves the value of the tracked key  from the request headers based on the specified tracking type.
+/// Retrieves the value of the tracked key  from the request based on the specified tracking type.
 ///
 /// # Arguments
 ///
-/// * `headers` - The HTTP headers from which the tracked key is to be extracted.
-/// * `tracking_type` - The type of tracking to be used, either by IP address or using a custom header.
-/// * `custom_header_key` - An optional custom header key to be used when `tracking_type` is `LimiterTrackingType::Custom`.
+/// * `request` - The parts of the HTTP request from which the tracked key is to be extracted.
+/// * `tracking_type` - The type of tracking to be used: IP address, header, query parameter, cookie or path parameter.
+/// * `custom_tracking_key` - The name of the header, query parameter, cookie, path parameter or JWT claim to be tracked.
+/// * `jwt` - Where the bearer token is read for a JWT claim, and what is tracked instead when it is missing or invalid.
 ///
 /// # Returns
 ///
 /// * `Ok(String)` - Returns the tracked key as a string if successful.
-/// * `Err(LimiterError::TrackedKeyNotFound)` - Returns an error if the tracked key cannot be found in the headers.
-
+/// * `Err(LimiterError::TrackedKeyNotFound)` - Returns an error if the tracked key cannot be found in the request.
 pub fn get_tracked_key_from_header(
-    headers: &HeaderMap,
+    request: &RequestParts,
     tracking_type: &LimiterTrackingType,
-    custom_header_key: Option<&str>,
+    custom_tracking_key: Option<&str>,
+    jwt: Option<&JwtTracking>,
 ) -> Result<String, errors::LimiterError> {
+    let headers = request.headers;
+    let custom_key = || custom_tracking_key.context("Custom tracking key should not be null");
+    let not_found = |key: &str| errors::LimiterError::TrackedKeyNotFound(key.to_string());
+
     match tracking_type {
-        LimiterTrackingType::IP => {
-            for key in STANDARD_IP_HEADERS {
-                if let Some(ip) = headers.get(key) {
-                    return Ok(ip.to_str().unwrap().to_string());
+        LimiterTrackingType::IP => request
+            .client_ip
+            .map(|ip| ip.to_string())
+            .ok_or(errors::LimiterError::NoIpFound),
+        LimiterTrackingType::Header => {
+            let custom_key = custom_key()?;
+            let key = headers
+                .get(custom_key)
+                .ok_or_else(|| not_found(custom_key))?;
+            key.to_str().map(str::to_string).map_err(|_| {
+                errors::LimiterError::InvalidRequest(format!(
+                    "Header {custom_key} must be visible ASCII"
+                ))
+            })
+        }
+        LimiterTrackingType::Query => {
+            let custom_key = custom_key()?;
+            form_urlencoded::parse(request.query.unwrap_or_default().as_bytes())
+                .find(|(name, _)| name == custom_key)
+                .map(|(_, value)| value.into_owned())
+                .ok_or_else(|| not_found(custom_key))
+        }
+        LimiterTrackingType::Cookie => {
+            let custom_key = custom_key()?;
+            get_cookie(headers, custom_key).ok_or_else(|| not_found(custom_key))
+        }
+        LimiterTrackingType::PathParam => {
+            let custom_key = custom_key()?;
+            request
+                .path_params
+                .get(custom_key)
+                .cloned()
+                .ok_or_else(|| not_found(custom_key))
+        }
+        // Verified claims and fallback keys live in separate namespaces, so that an anonymous
+        // request can't share the bucket of a tenant by sending its name.
+        LimiterTrackingType::JwtClaim => {
+            let claim = custom_key()?;
+            let jwt = jwt.cloned().unwrap_or_default();
+            let header = jwt.header.as_deref().unwrap_or(AUTHORIZATION.as_str());
+            match get_jwt_claim(request, header, claim) {
+                Ok(value) => Ok(format!("jwt:{value}")),
+                Err(reason) => {
+                    let fallback = jwt.fallback.unwrap_or(TrackingComponent {
+                        tracking_type: LimiterTrackingType::IP,
+                        custom_tracking_key: None,
+                    });
+                    tracing::debug!(
+                        "JWT claim {claim} unavailable ({reason}), request tracked by {} instead.",
+                        fallback.tracking_type
+                    );
+                    get_tracked_key_from_header(
+                        request,
+                        &fallback.tracking_type,
+                        fallback.custom_tracking_key.as_deref(),
+                        None,
+                    )
+                    .map(|key| format!("anon:{key}"))
                 }
             }
+        }
+        LimiterTrackingType::Composite => Err(errors::LimiterError::Unknown(anyhow!(
+            "Composite tracking keys must be resolved with get_tracked_keys"
+        ))),
+    }
+}
 
-            Err(errors::LimiterError::NoIpFound)
+/// Retrieves the tracked key(s) of a request for the given rule.
+///
+/// # Returns
+///
+/// * `Ok(Some(keys))` - The tracked key, or each component of a composite tracked key.
+/// * `Ok(None)` - A component is missing and the rule asks for the request to bypass rate limiting.
+/// * `Err(LimiterError)` - The tracked key, or a component of it, cannot be found.
+pub fn get_tracked_keys(
+    request: &RequestParts,
+    rule: &Rule,
+) -> Result<Option<Vec<String>>, errors::LimiterError> {
+    if !matches!(rule.tracking_type, LimiterTrackingType::Composite) {
+        let key = get_tracked_key_from_header(
+            request,
+            &rule.tracking_type,
+            rule.custom_tracking_key.as_deref(),
+            rule.jwt.as_ref(),
+        )?;
+        return Ok(Some(vec![key]));
+    }
+
+    let mut keys = vec![];
+    for component in rule.tracking_components.iter().flatten() {
+        match get_tracked_key_from_header(
+            request,
+            &component.tracking_type,
+            component.custom_tracking_key.as_deref(),
+            rule.jwt.as_ref(),
+        ) {
+            Ok(key) => keys.push(key),
+            Err(err @ errors::LimiterError::InvalidRequest(_)) => return Err(err),
+            Err(err) => match rule.on_missing_component.unwrap_or_default() {
+                MissingComponentBehaviour::Reject => return Err(err),
+                MissingComponentBehaviour::Skip => keys.push(String::new()),
+                MissingComponentBehaviour::Bypass => return Ok(None),
+            },
         }
-        LimiterTrackingType::Header => {
-            let custom_key = custom_header_key.context("Custom header should not be null")?;
-            if let Some(key) = headers.get(custom_key) {
-                Ok(key.to_str().unwrap().to_string())
+    }
+
+    Ok(Some(keys))
+}
+
+/// Every rule stored in redis, indexed by id.
+pub async fn get_rules_by_id(
+    connection: &mut ConnectionManager,
+) -> Result<HashMap<String, Rule>, LimiterError> {
+    let res: String = connection.json_get("rules", "$").await?;
+    let rules: Vec<HashMap<String, Rule>> =
+        serde_json::from_str(&res).map_err(|err| errors::LimiterError::Unknown(anyhow!(err)))?;
+    Ok(rules.into_iter().next().unwrap_or_default())
+}
+
+/// In-process cache of values read from redis, each entry being served for `ttl` after being read.
+/// The expired entries are dropped once the cache holds more than `capacity` entries.
+pub struct TtlCache<V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: parking_lot::Mutex<HashMap<String, (Instant, V)>>,
+}
+
+impl<V: Clone> TtlCache<V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        TtlCache {
+            ttl,
+            capacity,
+            entries: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The value cached for `key`, unless it expired.
+    pub fn get(&self, key: &str) -> Option<V> {
+        self.entries
+            .lock()
+            .get(key)
+            .filter(|(read_at, _)| read_at.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    pub fn insert(&self, key: &str, value: V) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, (read_at, _)| read_at.elapsed() < self.ttl);
+        }
+        if entries.len() < self.capacity {
+            entries.insert(key.to_string(), (Instant::now(), value));
+        }
+    }
+}
+
+/// How the route of a rule is matched against the path of a request.
+pub enum RoutePattern {
+    Literal(String),     // `/api/v1/orders`, matched as is
+    Route(Regex, usize), // `/api/v1/orders/{id}` or `/files/{*path}`, with its number of static characters
+    Regex(Regex),        // `~^/api/v[0-9]+/users$`, named groups are path parameters
+    Prefix(String),      // `/api/**` matches `/api` and everything below it
+}
+
+impl RoutePattern {
+    pub fn parse(route: &str) -> anyhow::Result<Self> {
+        if let Some(regex) = route.strip_prefix('~') {
+            let regex =
+                Regex::new(regex).with_context(|| format!("Invalid regex route {route}"))?;
+            return Ok(RoutePattern::Regex(regex));
+        }
+        if let Some(prefix) = route.strip_suffix("/**") {
+            return Ok(RoutePattern::Prefix(prefix.to_string()));
+        }
+
+        // {name} matches a segment, {*name} the rest of the path, {{ and }} are literal braces.
+        let mut regex = String::from("^");
+        let mut static_part = String::new();
+        let mut has_params = false;
+        let mut rest = route;
+        while let Some(literal) = rest.chars().next() {
+            if let Some(tail) = rest.strip_prefix("{{") {
+                regex.push_str(r"\{");
+                static_part.push('{');
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix("}}") {
+                regex.push_str(r"\}");
+                static_part.push('}');
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix('{') {
+                has_params = true;
+                let (param, tail) = tail
+                    .split_once('}')
+                    .with_context(|| format!("Unclosed parameter in route {route}"))?;
+                match param.strip_prefix('*') {
+                    Some(name) => regex.push_str(&format!("(?P<{name}>.+)")),
+                    None => regex.push_str(&format!("(?P<{param}>[^/]+)")),
+                }
+                rest = tail;
             } else {
-                Err(errors::LimiterError::TrackedKeyNotFound(
-                    custom_key.to_string(),
-                ))
+                regex.push_str(&regex::escape(&literal.to_string()));
+                static_part.push(literal);
+                rest = &rest[literal.len_utf8()..];
+            }
+        }
+        if !has_params {
+            return Ok(RoutePattern::Literal(static_part));
+        }
+        regex.push('$');
+        let regex = Regex::new(&regex).with_context(|| format!("Invalid route {route}"))?;
+        Ok(RoutePattern::Route(regex, static_part.len()))
+    }
+
+    /// Path parameters of the path if it matches.
+    pub fn at(&self, path: &str) -> Option<HashMap<String, String>> {
+        match self {
+            RoutePattern::Literal(literal) => (path == literal).then(HashMap::new),
+            RoutePattern::Route(regex, _) | RoutePattern::Regex(regex) => {
+                regex.captures(path).map(|captures| {
+                    regex
+                        .capture_names()
+                        .flatten()
+                        .filter_map(|name| {
+                            captures
+                                .name(name)
+                                .map(|value| (name.to_string(), value.as_str().to_string()))
+                        })
+                        .collect()
+                })
+            }
+            RoutePattern::Prefix(prefix) => {
+                let matched = path
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+                matched.then(HashMap::new)
             }
         }
     }
+
+    pub fn has_param(&self, name: &str) -> bool {
+        match self {
+            RoutePattern::Route(regex, _) | RoutePattern::Regex(regex) => {
+                regex.capture_names().flatten().any(|param| param == name)
+            }
+            RoutePattern::Literal(_) | RoutePattern::Prefix(_) => false,
+        }
+    }
 }
 
-pub async fn get_rules_from_redis(
-    connection: &mut ConnectionManager,
-) -> Result<HashMap<String, MinimalRule>, RedisError> {
-    // Get all fields and values from redis.
-    let res: String = connection.json_get("rules", "$").await?;
-    let rules: Vec<HashMap<String, MinimalRule>> = serde_json::from_str(&res)?;
-    tracing::info!("length of rules retrieved: {}", rules.len());
-    if rules.is_empty() {
-        return Ok(HashMap::new());
+/// Precedence of a rule among the ones matching a request: its priority first, then routes
+/// are more specific than regexes, themselves more specific than prefixes. Routes with the most
+/// static characters, and the longest prefixes, win.
+fn specificity(route: &str, pattern: &RoutePattern, priority: i32) -> (i32, u8, usize) {
+    match pattern {
+        RoutePattern::Literal(literal) => (priority, 2, literal.len()),
+        RoutePattern::Route(_, static_len) => (priority, 2, *static_len),
+        RoutePattern::Regex(_) => (priority, 1, route.len()),
+        RoutePattern::Prefix(prefix) => (priority, 0, prefix.len()),
     }
+}
 
-    let hash = rules.first().unwrap();
-    Ok(hash.clone())
+/// Whether a request is limited by the most specific rule matching it or by all of them.
+/// Configured with `RL_ROUTE_MATCHING`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RouteMatching {
+    #[default]
+    MostSpecific,
+    All,
 }
 
-pub fn instantiate_matcher_with_rules(rules: HashMap<String, MinimalRule>) -> Router<String> {
-    let mut matcher = MatchitRouter::new();
+impl RouteMatching {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("RL_ROUTE_MATCHING").as_deref() {
+            Err(_) | Ok("most_specific") => Ok(RouteMatching::MostSpecific),
+            Ok("all") => Ok(RouteMatching::All),
+            Ok(value) => {
+                anyhow::bail!("Invalid RL_ROUTE_MATCHING {value}, expected most_specific or all.")
+            }
+        }
+    }
+}
+
+/// Routes of the rules by decreasing precedence, and the rule applied to the requests matching none of them.
+/// Literal routes and prefixes are looked up by path, only the other routes are scanned.
+#[derive(Default)]
+pub struct RouteMatcher {
+    routes: Vec<(String, RoutePattern)>,   // Rule id and route
+    literals: HashMap<String, Vec<usize>>, // Positions in `routes` of the literal routes, by path
+    prefixes: HashMap<String, Vec<usize>>, // Positions in `routes` of the prefixes, by prefix
+    scanned: Vec<usize>, // Positions in `routes` of the routes with parameters and of the regexes
+    pub default_rule: Option<String>, // Id of the rule whose route is `DEFAULT_ROUTE`
+}
+
+impl RouteMatcher {
+    fn new(routes: Vec<(String, RoutePattern)>, default_rule: Option<String>) -> Self {
+        let mut matcher = RouteMatcher {
+            default_rule,
+            ..Default::default()
+        };
+        for (position, (_, pattern)) in routes.iter().enumerate() {
+            match pattern {
+                RoutePattern::Literal(literal) => matcher
+                    .literals
+                    .entry(literal.clone())
+                    .or_default()
+                    .push(position),
+                RoutePattern::Prefix(prefix) => matcher
+                    .prefixes
+                    .entry(prefix.clone())
+                    .or_default()
+                    .push(position),
+                RoutePattern::Route(..) | RoutePattern::Regex(_) => matcher.scanned.push(position),
+            }
+        }
+        matcher.routes = routes;
+        matcher
+    }
+
+    /// Positions of the literal routes and prefixes matching the path, by decreasing precedence.
+    fn indexed(&self, path: &str) -> Vec<usize> {
+        // A prefix matches the path itself and every path below it.
+        let ancestors = path
+            .match_indices('/')
+            .map(|(end, _)| &path[..end])
+            .chain([path]);
+        let mut positions: Vec<usize> = self
+            .literals
+            .get(path)
+            .into_iter()
+            .chain(ancestors.filter_map(|ancestor| self.prefixes.get(ancestor)))
+            .flatten()
+            .copied()
+            .collect();
+        positions.sort_unstable();
+        positions
+    }
+
+    /// Ids and path parameters of the rules matching the path, by decreasing precedence.
+    pub fn at(&self, path: &str) -> Vec<(String, HashMap<String, String>)> {
+        let mut matched: Vec<(usize, HashMap<String, String>)> = self
+            .indexed(path)
+            .into_iter()
+            .map(|position| (position, HashMap::new()))
+            .collect();
+        matched.extend(self.scanned.iter().filter_map(|&position| {
+            let path_params = self.routes[position].1.at(path)?;
+            Some((position, path_params))
+        }));
+        matched.sort_unstable_by_key(|(position, _)| *position);
+        matched
+            .into_iter()
+            .map(|(position, path_params)| (self.routes[position].0.clone(), path_params))
+            .collect()
+    }
+
+    /// Id and path parameters of the rule of highest precedence matching the path.
+    /// Only the scanned routes outranking the best literal route or prefix are tried.
+    pub fn most_specific(&self, path: &str) -> Option<(String, HashMap<String, String>)> {
+        let indexed = self.indexed(path).first().copied();
+        self.scanned
+            .iter()
+            .take_while(|&&position| indexed.is_none_or(|indexed| position < indexed))
+            .find_map(|&position| {
+                let path_params = self.routes[position].1.at(path)?;
+                Some((position, path_params))
+            })
+            .or(indexed.map(|position| (position, HashMap::new())))
+            .map(|(position, path_params)| (self.routes[position].0.clone(), path_params))
+    }
+}
+
+pub fn instantiate_matcher_with_rules(rules: HashMap<String, MinimalRule>) -> RouteMatcher {
+    let mut default_rule = None;
+    let mut routes = vec![];
     for (rule_id, rule) in rules {
-        match matcher.insert(rule.route.clone(), rule_id.clone()) {
-            Ok(_) => {
+        if rule.route == DEFAULT_ROUTE {
+            tracing::debug!("Default rule set with id: {}", rule_id);
+            default_rule = Some(rule_id);
+            continue;
+        }
+        match RoutePattern::parse(&rule.route) {
+            Ok(pattern) => {
                 tracing::debug!(
                     "Successfully inserted route: {} with id: {}",
                     rule.route,
                     rule_id
                 );
+                let specificity =
+                    specificity(&rule.route, &pattern, rule.priority.unwrap_or_default());
+                routes.push((Reverse(specificity), rule_id, pattern));
             }
             Err(e) => {
                 tracing::warn!("Failed to insert route: {e}. Errors are ignored.");
             }
         }
     }
-    matcher
+
+    // Rules of the same precedence are ordered by id so that the winner does not depend on the hashmap order.
+    routes.sort_by(|(a, a_id, _), (b, b_id, _)| a.cmp(b).then(a_id.cmp(b_id)));
+    let routes = routes
+        .into_iter()
+        .map(|(_, rule_id, pattern)| (rule_id, pattern))
+        .collect();
+    RouteMatcher::new(routes, default_rule)
 }
 
-pub async fn get_rules_information_by_redis_json_key(
-    redis_connection: &mut ConnectionManager,
-    key: &str,
-) -> Result<Rule, LimiterError> {
-    let res: String = redis_connection
-        .json_get("rules", format!("$.{}", key).as_str())
-        .await?;
-    tracing::debug!("Rules retrieved: {}", &res);
-    let rules: Vec<Rule> =
-        serde_json::from_str(&res).map_err(|err| errors::LimiterError::Unknown(anyhow!(err)))?;
+lazy_static! {
+    /// Applies a diff to the `rules` document.
+    /// ARGV[1..3] are the version metadata (see `RECORD_VERSION_SCRIPT`), ARGV[4] is the rules
+    /// version the diff was computed against and the remaining arguments are (operation, id, rule)
+    /// triplets built by `make_rules_configuration_args`.
+    /// The script refuses to run if `rules_version` moved away from ARGV[4], so that concurrent
+    /// loads cannot silently overwrite each other.
+    pub static ref RULES_CONFIGURATION_SOURCE: String = format!(
+        r"
+        local current_version = tonumber(redis.call('GET', 'rules_version') or '0')
+        if current_version ~= tonumber(ARGV[4]) then
+            return redis.error_reply('Rules were modified concurrently (expected version ' .. ARGV[4] .. ', found ' .. current_version .. '). Re-run the load to review the new changes.')
+        end
+        redis.call('JSON.SET', 'rules', '$', '{{}}', 'NX')
 
-    Ok(rules
-        .first()
-        .ok_or(errors::LimiterError::Unknown(anyhow!(
-            "No rule found for key: {}",
-            key
-        )))?
-        .clone())
-}
-
-pub fn make_rules_configuration_script(rules: Vec<Rule>) -> Script {
-    // Empty or initialize the rules hash
-    let check_initialization = r"
-        redis.call('JSON.SET', 'rules', '$', '{}')
-
-    ";
-
-    // Build the rules, redis call after redis call
-    let mut script_rules: Vec<String> = vec![];
-    script_rules.push(check_initialization.to_string());
-
-    rules.into_iter().for_each(|rule| {
-        let id = rule.id.clone().to_string();
-        let rule_json = json!(
-            {
-                "id": rule.id,
-                "route": rule.route,
-                "algorithm": rule.algorithm.to_string(),
-                "tracking_type": rule.tracking_type.to_string(),
-                "limit": rule.limit,
-                "expiration": rule.expiration,
-                "custom_tracking_key": rule.custom_tracking_key.unwrap_or("".to_string()),
-                "active": rule.active.unwrap_or(true).to_string()
-            }
-        );
+        for i = 5, #ARGV, 3 do
+            local operation, id, rule = ARGV[i], ARGV[i + 1], ARGV[i + 2]
+            if operation == 'set' then
+                redis.call('JSON.SET', 'rules', '$.' .. id, rule)
+            else
+                redis.call('JSON.DEL', 'rules', '$.' .. id)
+                redis.call('DEL', 'overrides:' .. id)
+            end
+        end
+        {RECORD_VERSION_SCRIPT}
+        redis.call('PUBLISH', 'rl_update', 'update')
+        return version
+        "
+    );
+    pub static ref RULES_CONFIGURATION_SCRIPT: Script = Script::new(&RULES_CONFIGURATION_SOURCE);
+}
 
-        let script = format!(r#"redis.call('JSON.SET', 'rules', '$.{id}' , '{rule_json}')"#);
-        script_rules.push(script);
+/// Flattens `diff` into the (operation, id, rule) arguments expected by `RULES_CONFIGURATION_SCRIPT`.
+/// Rules are passed as arguments rather than formatted into the script so that no route or header
+/// name can break out of it.
+pub fn make_rules_configuration_args(diff: &RulesDiff) -> Vec<String> {
+    let mut args = vec![];
+
+    diff.added
+        .iter()
+        .chain(diff.changed.iter().map(|change| &change.next))
+        .for_each(|rule_json| {
+            let id = rule_json["id"].as_str().unwrap_or_default();
+            args.extend(["set".to_string(), id.to_string(), rule_json.to_string()]);
+        });
+
+    diff.removed.iter().for_each(|rule_json| {
+        let id = rule_json["id"].as_str().unwrap_or_default();
+        args.extend(["del".to_string(), id.to_string(), String::new()]);
     });
 
-    // Finilize the script by publishing the update
-    let publish = "redis.call('PUBLISH', 'rl_update', 'update')".to_string();
-    let script_rules = script_rules.join("\n");
-    Script::new(&format!("{}\n{}", script_rules, publish))
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{algorithm_tests::FakeRedis, diff::diff_rules, ip_filter::TrustedProxies};
+    use hyper::header::HeaderValue;
+
+    const TRICKY_NAMES: [&str; 8] = [
+        "/api/it's",
+        r"/api/back\slash\",
+        r#"/api/"quoted""#,
+        "/api/ünïcödé/日本語",
+        "/api/emoji/🚀",
+        "/api/'); redis.call('FLUSHALL') --",
+        "/api/new\nline",
+        "/api/{id}/ñ]]",
+    ];
+
+    fn header_rule(route: &str, header: &str) -> Rule {
+        Rule::new(
+            route.to_string(),
+            RateLimiterAlgorithms::FixedWindow,
+            10,
+            60,
+            LimiterTrackingType::Header,
+            Some(header.to_string()),
+            None,
+        )
+    }
+
+    #[test]
+    fn configuration_args_round_trip_arbitrary_routes_and_headers() {
+        let rules: Vec<Rule> = TRICKY_NAMES
+            .iter()
+            .map(|name| header_rule(name, &format!("x-{name}")))
+            .collect();
+        let desired: Vec<_> = rules.iter().map(Rule::to_redis_json).collect();
+        let diff = diff_rules(&HashMap::new(), &desired);
+
+        let args = make_rules_configuration_args(&diff);
+
+        assert_eq!(args.len(), rules.len() * 3);
+        for triplet in args.chunks(3) {
+            assert_eq!(triplet[0], "set");
+            let parsed: Rule = serde_json::from_str(&triplet[2]).unwrap();
+            let original = rules.iter().find(|rule| rule.id == triplet[1]).unwrap();
+            assert_eq!(parsed.id, original.id);
+            assert_eq!(parsed.route, original.route);
+            assert_eq!(parsed.custom_tracking_key, original.custom_tracking_key);
+            assert_eq!(parsed.to_redis_json(), original.to_redis_json());
+        }
+    }
+
+    #[test]
+    fn configuration_args_delete_removed_rules() {
+        let rule = header_rule(TRICKY_NAMES[0], "x-api-key");
+        let live = HashMap::from([(rule.id.clone(), rule.to_redis_json())]);
+        let diff = diff_rules(&live, &[]);
+
+        let args = make_rules_configuration_args(&diff);
+
+        assert_eq!(args, vec!["del".to_string(), rule.id, String::new()]);
+    }
+
+    fn apply_configuration(redis: &FakeRedis, diff: &RulesDiff, base_version: u64) -> i64 {
+        let mut args = ["author", "checksum", "10", &base_version.to_string()]
+            .map(String::from)
+            .to_vec();
+        args.extend(make_rules_configuration_args(diff));
+        redis.eval(&RULES_CONFIGURATION_SOURCE, &[], &args)[0]
+    }
+
+    #[test]
+    fn configuration_script_stores_arbitrary_routes_and_headers() {
+        let redis = FakeRedis::new();
+        let rules: Vec<Rule> = TRICKY_NAMES
+            .iter()
+            .map(|name| header_rule(name, &format!("x-{name}")))
+            .collect();
+        let desired: Vec<_> = rules.iter().map(Rule::to_redis_json).collect();
+
+        let version = apply_configuration(&redis, &diff_rules(&HashMap::new(), &desired), 0);
+
+        assert_eq!(version, 1);
+        let stored: HashMap<String, Value> =
+            serde_json::from_value(redis.json("rules").unwrap()).unwrap();
+        assert_eq!(stored.len(), rules.len());
+        for rule in &rules {
+            let parsed: Rule = serde_json::from_str(&stored[&rule.id].to_string()).unwrap();
+            assert_eq!(parsed.route, rule.route);
+            assert_eq!(parsed.custom_tracking_key, rule.custom_tracking_key);
+            assert_eq!(parsed.to_redis_json(), rule.to_redis_json());
+        }
+        let recorded = redis.json("rules_history:1").unwrap();
+        assert_eq!(recorded["rules"], redis.json("rules").unwrap());
+        assert_eq!(recorded["author"], "author");
+        assert_eq!(
+            redis.published(),
+            vec![("rl_update".to_string(), "update".to_string())]
+        );
+    }
+
+    #[test]
+    fn configuration_script_applies_changes_and_deletions() {
+        let redis = FakeRedis::new();
+        let kept = header_rule(TRICKY_NAMES[1], "x-api-key");
+        let removed = header_rule(TRICKY_NAMES[2], "x-api-key");
+        let initial = vec![kept.to_redis_json(), removed.to_redis_json()];
+        apply_configuration(&redis, &diff_rules(&HashMap::new(), &initial), 0);
+
+        let live: HashMap<String, Value> =
+            serde_json::from_value(redis.json("rules").unwrap()).unwrap();
+        let changed = Rule {
+            limit: 20,
+            ..kept.clone()
+        };
+        let version =
+            apply_configuration(&redis, &diff_rules(&live, &[changed.to_redis_json()]), 1);
+
+        assert_eq!(version, 2);
+        let stored: HashMap<String, Value> =
+            serde_json::from_value(redis.json("rules").unwrap()).unwrap();
+        assert_eq!(stored, HashMap::from([(kept.id, changed.to_redis_json())]));
+        assert_eq!(redis.list("rules_history"), vec!["2", "1"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Rules were modified concurrently")]
+    fn configuration_script_refuses_outdated_diffs() {
+        let redis = FakeRedis::new();
+        let rule = header_rule(TRICKY_NAMES[0], "x-api-key");
+        let diff = diff_rules(&HashMap::new(), &[rule.to_redis_json()]);
+        apply_configuration(&redis, &diff, 0);
+
+        apply_configuration(&redis, &diff, 0);
+    }
+
+    fn request_parts<'a>(
+        headers: &'a HeaderMap,
+        query: Option<&'a str>,
+        path_params: &'a HashMap<String, String>,
+        jwt_verifier: &'a JwtVerifier,
+    ) -> RequestParts<'a> {
+        RequestParts {
+            headers,
+            query,
+            path_params,
+            jwt_verifier,
+            client_ip: TrustedProxies::default().client_ip(headers),
+        }
+    }
+
+    fn composite_rule(on_missing_component: MissingComponentBehaviour) -> Rule {
+        Rule {
+            tracking_components: Some(vec![
+                TrackingComponent {
+                    tracking_type: LimiterTrackingType::Header,
+                    custom_tracking_key: Some("x-tenant".to_string()),
+                },
+                TrackingComponent {
+                    tracking_type: LimiterTrackingType::IP,
+                    custom_tracking_key: None,
+                },
+            ]),
+            on_missing_component: Some(on_missing_component),
+            ..Rule::new(
+                "/api/v1/orders".to_string(),
+                RateLimiterAlgorithms::TokenBucket,
+                10,
+                60,
+                LimiterTrackingType::Composite,
+                None,
+                None,
+            )
+        }
+    }
+
+    #[test]
+    fn ip_keys_can_not_be_rotated_by_the_client() {
+        let no_params = HashMap::new();
+        let verifier = JwtVerifier::default();
+        let key_of = |forwarded_for: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+            get_tracked_key_from_header(
+                &request_parts(&headers, None, &no_params, &verifier),
+                &LimiterTrackingType::IP,
+                None,
+                None,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(key_of("203.0.113.7"), "203.0.113.7");
+        assert_eq!(key_of("198.51.100.1, 203.0.113.7"), "203.0.113.7");
+        assert_eq!(key_of("198.51.100.2, 203.0.113.7"), "203.0.113.7");
+    }
+
+    #[test]
+    fn composite_keys_are_combined_in_order_and_escaped() {
+        let no_params = HashMap::new();
+        let verifier = JwtVerifier::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", "acme|corp".parse().unwrap());
+        headers.insert("x-real-ip", "10.0.0.1".parse().unwrap());
+        let rule = composite_rule(MissingComponentBehaviour::Reject);
+
+        let keys = get_tracked_keys(&request_parts(&headers, None, &no_params, &verifier), &rule)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(keys, vec!["acme|corp".to_string(), "10.0.0.1".to_string()]);
+        assert_eq!(
+            make_redis_key(&keys, "rule-id", &rule.algorithm),
+            "tb:rule-id:acme%7Ccorp|10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn non_ascii_header_keys_are_invalid_requests() {
+        let no_params = HashMap::new();
+        let verifier = JwtVerifier::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+        headers.insert("x-real-ip", "10.0.0.1".parse().unwrap());
+        let request = request_parts(&headers, None, &no_params, &verifier);
+
+        let header = get_tracked_key_from_header(
+            &request,
+            &LimiterTrackingType::Header,
+            Some("x-tenant"),
+            None,
+        );
+        assert!(matches!(header, Err(LimiterError::InvalidRequest(_))));
+        // A malformed component is not a missing one, it is never skipped.
+        for behaviour in [
+            MissingComponentBehaviour::Reject,
+            MissingComponentBehaviour::Skip,
+            MissingComponentBehaviour::Bypass,
+        ] {
+            let composite = get_tracked_keys(&request, &composite_rule(behaviour));
+            assert!(matches!(composite, Err(LimiterError::InvalidRequest(_))));
+        }
+    }
+
+    #[test]
+    fn missing_composite_component_follows_rule_behaviour() {
+        let no_params = HashMap::new();
+        let verifier = JwtVerifier::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "10.0.0.1".parse().unwrap());
+
+        let rejected = get_tracked_keys(
+            &request_parts(&headers, None, &no_params, &verifier),
+            &composite_rule(MissingComponentBehaviour::Reject),
+        );
+        let skipped = get_tracked_keys(
+            &request_parts(&headers, None, &no_params, &verifier),
+            &composite_rule(MissingComponentBehaviour::Skip),
+        );
+        let bypassed = get_tracked_keys(
+            &request_parts(&headers, None, &no_params, &verifier),
+            &composite_rule(MissingComponentBehaviour::Bypass),
+        );
+
+        assert!(matches!(rejected, Err(LimiterError::TrackedKeyNotFound(_))));
+        assert_eq!(
+            skipped.unwrap(),
+            Some(vec![String::new(), "10.0.0.1".to_string()])
+        );
+        assert_eq!(bypassed.unwrap(), None);
+    }
+
+    #[test]
+    fn tracked_keys_are_read_from_query_cookie_and_path_params() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, "theme=dark; session=abc%20123".parse().unwrap());
+        let path_params = HashMap::from([("id".to_string(), "42".to_string())]);
+        let verifier = JwtVerifier::default();
+        let request = request_parts(
+            &headers,
+            Some("page=2&api_key=k%C3%A9y+1"),
+            &path_params,
+            &verifier,
+        );
+
+        let query = get_tracked_key_from_header(
+            &request,
+            &LimiterTrackingType::Query,
+            Some("api_key"),
+            None,
+        );
+        let cookie = get_tracked_key_from_header(
+            &request,
+            &LimiterTrackingType::Cookie,
+            Some("session"),
+            None,
+        );
+        let path_param = get_tracked_key_from_header(
+            &request,
+            &LimiterTrackingType::PathParam,
+            Some("id"),
+            None,
+        );
+        let missing =
+            get_tracked_key_from_header(&request, &LimiterTrackingType::Query, Some("token"), None);
+
+        assert_eq!(query.unwrap(), "kéy 1");
+        assert_eq!(cookie.unwrap(), "abc%20123");
+        assert_eq!(path_param.unwrap(), "42");
+        assert!(matches!(missing, Err(LimiterError::TrackedKeyNotFound(_))));
+    }
+
+    fn hs256_token(secret: &str, tenant_id: &str) -> String {
+        let exp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &serde_json::json!({ "sub": "user-1", "tenant_id": tenant_id, "exp": exp }),
+            &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn ttl_cache_serves_values_until_they_expire() {
+        let cache = TtlCache::new(Duration::from_secs(60), 1);
+        cache.insert("acme", Some("enterprise".to_string()));
+        cache.insert("globex", Some("pro".to_string()));
+
+        assert_eq!(cache.get("acme"), Some(Some("enterprise".to_string())));
+        // Full of fresh entries, the cache doesn't grow.
+        assert_eq!(cache.get("globex"), None);
+
+        let disabled = TtlCache::new(Duration::ZERO, 10);
+        disabled.insert("acme", 1);
+        assert_eq!(disabled.get("acme"), None);
+    }
+
+    #[test]
+    fn jwt_claim_is_tracked_once_token_is_verified() {
+        let no_params = HashMap::new();
+        let verifier =
+            JwtVerifier::new(&["old-secret".to_string(), "secret".to_string()], None).unwrap();
+        let jwt = JwtTracking {
+            header: None,
+            fallback: Some(TrackingComponent {
+                tracking_type: LimiterTrackingType::Header,
+                custom_tracking_key: Some("x-api-key".to_string()),
+            }),
+        };
+        let claim_of = |authorization: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-api-key", "anonymous-key".parse().unwrap());
+            headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+            let request = request_parts(&headers, None, &no_params, &verifier);
+            get_tracked_key_from_header(
+                &request,
+                &LimiterTrackingType::JwtClaim,
+                Some("tenant_id"),
+                Some(&jwt),
+            )
+            .unwrap()
+        };
+
+        let valid = claim_of(&format!("Bearer {}", hs256_token("secret", "acme")));
+        let forged = claim_of(&format!("Bearer {}", hs256_token("not-the-secret", "acme")));
+        let garbage = claim_of("Bearer not-a-token");
+
+        assert_eq!(valid, "jwt:acme");
+        assert_eq!(forged, "anon:anonymous-key");
+        assert_eq!(garbage, "anon:anonymous-key");
+    }
+
+    #[test]
+    fn jwt_fallback_keys_never_collide_with_claims() {
+        let no_params = HashMap::new();
+        let verifier = JwtVerifier::new(&["secret".to_string()], None).unwrap();
+        let jwt = JwtTracking {
+            header: None,
+            fallback: Some(TrackingComponent {
+                tracking_type: LimiterTrackingType::Header,
+                custom_tracking_key: Some("x-tenant".to_string()),
+            }),
+        };
+        let key_of = |headers: &HeaderMap| {
+            let request = request_parts(headers, None, &no_params, &verifier);
+            get_tracked_key_from_header(
+                &request,
+                &LimiterTrackingType::JwtClaim,
+                Some("tenant_id"),
+                Some(&jwt),
+            )
+            .unwrap()
+        };
+
+        let mut tenant = HeaderMap::new();
+        let token = format!("Bearer {}", hs256_token("secret", "acme"));
+        tenant.insert(AUTHORIZATION, token.parse().unwrap());
+        let mut impostor = HeaderMap::new();
+        impostor.insert("x-tenant", "acme".parse().unwrap());
+
+        assert_ne!(key_of(&tenant), key_of(&impostor));
+    }
+
+    fn matcher_of(routes: &[(&str, Option<i32>)]) -> RouteMatcher {
+        instantiate_matcher_with_rules(
+            routes
+                .iter()
+                .map(|(route, priority)| {
+                    let rule = MinimalRule {
+                        id: route.to_string(),
+                        route: route.to_string(),
+                        priority: *priority,
+                    };
+                    (route.to_string(), rule)
+                })
+                .collect(),
+        )
+    }
+
+    fn matched_routes(matcher: &RouteMatcher, path: &str) -> Vec<String> {
+        matcher.at(path).into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn overlapping_routes_are_ordered_by_priority_then_specificity() {
+        let matcher = matcher_of(&[
+            ("/api/**", None),
+            ("/api/v1/**", None),
+            ("~^/api/v[0-9]+/users$", None),
+            ("/api/v1/users", None),
+            ("/api/v1/{resource}", None),
+            ("/api/v1/{name}", Some(-1)),
+            ("/{*path}", Some(10)),
+            ("*", None),
+        ]);
+
+        assert_eq!(
+            matched_routes(&matcher, "/api/v1/users"),
+            [
+                "/{*path}",
+                "/api/v1/users",
+                "/api/v1/{resource}",
+                "~^/api/v[0-9]+/users$",
+                "/api/v1/**",
+                "/api/**",
+                "/api/v1/{name}",
+            ]
+        );
+        assert_eq!(matched_routes(&matcher, "/api"), ["/{*path}", "/api/**"]);
+        assert!(matched_routes(&matcher, "/apis").len() == 1);
+        assert!(matched_routes(&matcher, "/").is_empty());
+        assert_eq!(matcher.default_rule.as_deref(), Some("*"));
+    }
+
+    #[test]
+    fn regex_and_route_parameters_are_path_params() {
+        let matcher = matcher_of(&[
+            ("~^/users/(?P<user>[0-9]+)/orders$", None),
+            ("/files/{*path}", None),
+            ("/literal/{{braces}}", None),
+        ]);
+
+        let params = |path: &str| matcher.at(path).pop().map(|(_, params)| params);
+        assert_eq!(params("/users/42/orders").unwrap()["user"], "42");
+        assert_eq!(params("/files/a/b.txt").unwrap()["path"], "a/b.txt");
+        assert!(params("/literal/{braces}").unwrap().is_empty());
+        assert!(params("/users/me/orders").is_none());
+    }
+
+    #[test]
+    fn most_specific_is_the_first_of_every_match() {
+        let matcher = matcher_of(&[
+            ("/api/**", None),
+            ("/api/v1/**", None),
+            ("~^/api/v[0-9]+/users$", None),
+            ("/api/v1/users", None),
+            ("/api/v1/{resource}", None),
+            ("/api/v2/users", Some(-1)),
+            ("/{*path}", Some(10)),
+            ("/**", Some(-5)),
+        ]);
+
+        for path in [
+            "/api/v1/users",
+            "/api/v2/users",
+            "/api/v1",
+            "/api",
+            "/apis",
+            "/",
+            "",
+        ] {
+            assert_eq!(
+                matcher.most_specific(path).map(|(id, _)| id),
+                matched_routes(&matcher, path).first().cloned(),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            matched_routes(&matcher, "/api/v2/users"),
+            [
+                "/{*path}",
+                "~^/api/v[0-9]+/users$",
+                "/api/**",
+                "/api/v2/users",
+                "/**"
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_braces_are_static_characters() {
+        let matcher = matcher_of(&[("/literal/{{a}}/{name}", None), ("/literal/{a}/b", None)]);
+
+        // `/literal/{a}/` outranks `/literal/` and `/b`.
+        assert_eq!(
+            matched_routes(&matcher, "/literal/{a}/b"),
+            ["/literal/{{a}}/{name}", "/literal/{a}/b"]
+        );
+    }
 }